@@ -1,13 +1,41 @@
-use std::{sync::Arc, time::Duration};
+use std::{path::Path, sync::Arc, time::Duration};
 
 use criterion::{Criterion, criterion_group, criterion_main};
 use minipath::{
-    Camera, RenderSettings, Scene,
+    Camera, RenderEvents, RenderSettings, Scene, ShadingMode,
     geometry::{ScreenSize, WorldPoint, WorldVector},
     render,
-    scene::triangle_bvh::TriangleBvh,
+    scene::triangle_bvh::{BuildProgress, SplitMode, TriangleBvh},
 };
 
+/// Git submodule, its benchmarks are skipped when it is not checked out.
+const SPONZA_PATH: &str = "data/Sponza/sponza.obj";
+
+fn bench_render(
+    c: &mut Criterion,
+    name: &str,
+    path: &str,
+    split_mode: SplitMode,
+    camera: &Camera,
+    settings: RenderSettings,
+) {
+    let object =
+        TriangleBvh::with_obj_split_mode(path, split_mode, &BuildProgress::default()).unwrap();
+    let scene = Arc::new(Scene::new(object));
+
+    c.bench_function(name, |b| {
+        b.iter_batched(
+            || (camera.clone(), settings, scene.clone()),
+            |(camera, settings, scene)| {
+                let mut render_progress =
+                    render(scene, camera, settings, RenderEvents::new()).unwrap();
+                render_progress.wait();
+            },
+            criterion::BatchSize::LargeInput,
+        )
+    });
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     let camera = Camera::default()
         .look_at(
@@ -21,22 +49,42 @@ fn criterion_benchmark(c: &mut Criterion) {
         tile_size: 64.try_into().unwrap(),
         sample_count: 10.try_into().unwrap(),
         resolution: ScreenSize::new(2048, 1536),
+        shading_mode: ShadingMode::Shaded,
+        time_limit: None,
     };
-    let scene = Arc::new(Scene {
-        object: TriangleBvh::with_obj("data/teapot.obj").unwrap(),
-    });
 
-    c.bench_function("render_teapot", |b| {
-        b.iter_batched(
-            || (camera, settings, scene.clone()),
-            |(camera, settings, scene)| {
-                let mut render_progress =
-                    render(scene, camera, settings, |_| {}, |_, _| {}).unwrap();
-                render_progress.wait();
-            },
-            criterion::BatchSize::LargeInput,
-        )
-    });
+    bench_render(
+        c,
+        "render_teapot",
+        "data/teapot.obj",
+        SplitMode::Object,
+        &camera,
+        settings,
+    );
+    bench_render(
+        c,
+        "render_teapot_spatial_splits",
+        "data/teapot.obj",
+        SplitMode::Spatial,
+        &camera,
+        settings,
+    );
+
+    if Path::new(SPONZA_PATH).exists() {
+        let camera = Camera::default()
+            .look_at(
+                WorldPoint::new(-1000.0, 200.0, 0.0),
+                WorldPoint::new(1000.0, 400.0, 0.0),
+                WorldVector::new(0.0, 1.0, 0.0),
+            )
+            .focus_distance(1000.0);
+        for (name, split_mode) in [
+            ("render_sponza", SplitMode::Object),
+            ("render_sponza_spatial_splits", SplitMode::Spatial),
+        ] {
+            bench_render(c, name, SPONZA_PATH, split_mode, &camera, settings);
+        }
+    }
 }
 
 criterion_group! {
@@ -0,0 +1,241 @@
+use std::sync::Arc;
+
+use image::GrayImage;
+use nalgebra::Vector2;
+use rand_distr::Distribution as _;
+
+use crate::geometry::FloatType;
+
+/// How many samples of the aperture shape are tried to find one inside the barrel circle.
+const MAX_CATS_EYE_TRIES: u32 = 64;
+
+/// Shape of the lens opening, determines how out of focus highlights look.
+#[derive(Clone, Debug)]
+pub struct Aperture {
+    pub shape: ApertureShape,
+    /// Strength of the cat's eye effect, between 0 (disabled) and 1.
+    /// Off-axis the aperture gets clipped by the lens barrel, so that bokeh
+    /// towards the image corners becomes lemon shaped.
+    pub cats_eye: FloatType,
+    /// Ratio of height to width of out of focus highlights, 1 for spherical lenses,
+    /// 2 for a typical anamorphic lens.
+    /// Only the bokeh is simulated, the field of view stays the same.
+    pub anamorphic_squeeze: FloatType,
+}
+
+#[derive(Clone, Debug)]
+pub enum ApertureShape {
+    Circle,
+    /// Regular polygon inscribed in the lens circle, made by the diaphragm blades.
+    /// With zero rotation the first vertex points to the right.
+    Polygon {
+        blades: u32,
+        rotation: FloatType,
+    },
+    /// Arbitrary shape given by an image stretched over the lens circle.
+    Image(Arc<ApertureImage>),
+}
+
+impl Default for Aperture {
+    fn default() -> Self {
+        Aperture {
+            shape: ApertureShape::Circle,
+            cats_eye: 0.0,
+            anamorphic_squeeze: 1.0,
+        }
+    }
+}
+
+impl Aperture {
+    /// Samples a point on the aperture for a ray going to the given point of the film.
+    /// The film point is relative to the image center, with 1 being the image corner.
+    /// Returns lens coordinates with the lens circle having radius 1.
+    pub fn sample(
+        &self,
+        film_point: Vector2<FloatType>,
+        rng: &mut impl rand::Rng,
+    ) -> [FloatType; 2] {
+        let barrel_center = film_point * self.cats_eye.clamp(0.0, 1.0);
+        let mut sample = self.shape.sample(rng);
+        // Image apertures can have all their light outside of the barrel circle, in that case
+        // the clipping is given up and the aperture is used as is
+        for _ in 1..MAX_CATS_EYE_TRIES {
+            let [u, v] = sample;
+            if (u - barrel_center.x).powi(2) + (v - barrel_center.y).powi(2) <= 1.0 {
+                break;
+            }
+            sample = self.shape.sample(rng);
+        }
+        [sample[0] / self.anamorphic_squeeze, sample[1]]
+    }
+}
+
+impl ApertureShape {
+    /// Samples a uniformly distributed point of the shape, within the unit circle,
+    /// or within the square around it for images.
+    fn sample(&self, rng: &mut impl rand::Rng) -> [FloatType; 2] {
+        match self {
+            ApertureShape::Circle => rand_distr::UnitDisc.sample(rng),
+            ApertureShape::Polygon { blades, rotation } => sample_polygon(*blades, *rotation, rng),
+            ApertureShape::Image(image) => image.sample(rng),
+        }
+    }
+}
+
+/// Picks one of the triangles between the center and two neighboring vertices, all of them have
+/// the same area, then samples a point in it.
+fn sample_polygon(blades: u32, rotation: FloatType, rng: &mut impl rand::Rng) -> [FloatType; 2] {
+    let blades = blades.max(3);
+    let step = std::f32::consts::TAU / blades as FloatType;
+    let angle = rotation + rng.random_range(0..blades) as FloatType * step;
+    let a = Vector2::new(angle.cos(), angle.sin());
+    let b = Vector2::new((angle + step).cos(), (angle + step).sin());
+
+    let (mut s, mut t): (FloatType, FloatType) = (rng.random(), rng.random());
+    if s + t > 1.0 {
+        (s, t) = (1.0 - s, 1.0 - t);
+    }
+    (a * s + b * t).into()
+}
+
+/// Grayscale image of an aperture, brightness of each pixel is the relative amount of light
+/// passing through it.
+#[derive(Debug)]
+pub struct ApertureImage {
+    width: u32,
+    height: u32,
+    /// Running sum of pixel brightnesses, in C order
+    cumulative: Vec<f32>,
+}
+
+impl ApertureImage {
+    /// Returns None if the image is completely black.
+    pub fn new(image: &GrayImage) -> Option<Self> {
+        let cumulative: Vec<f32> = image
+            .pixels()
+            .scan(0.0, |sum, pixel| {
+                *sum += f32::from(pixel.0[0]);
+                Some(*sum)
+            })
+            .collect();
+
+        if cumulative.last().is_none_or(|&total| total == 0.0) {
+            return None;
+        }
+
+        Some(ApertureImage {
+            width: image.width(),
+            height: image.height(),
+            cumulative,
+        })
+    }
+
+    /// Samples a pixel proportionally to its brightness, then a point within it.
+    /// Image is mapped to the square around the unit circle, with the top row at y = 1.
+    fn sample(&self, rng: &mut impl rand::Rng) -> [FloatType; 2] {
+        let total = self.cumulative[self.cumulative.len() - 1];
+        let target = rng.random::<f32>() * total;
+        let index = self
+            .cumulative
+            .partition_point(|&sum| sum <= target)
+            .min(self.cumulative.len() - 1);
+
+        let x = (index as u32 % self.width) as FloatType + rng.random::<FloatType>();
+        let y = (index as u32 / self.width) as FloatType + rng.random::<FloatType>();
+        [
+            x / self.width as FloatType * 2.0 - 1.0,
+            1.0 - y / self.height as FloatType * 2.0,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+    use image::Luma;
+
+    #[test]
+    fn polygon_samples_stay_inside() {
+        let mut rng = rand::rng();
+        let blades = 6;
+        // Distance from the center to the middle of an edge
+        let apothem = (std::f32::consts::PI / blades as f32).cos();
+
+        for _ in 0..1000 {
+            let [u, v] = sample_polygon(blades, 0.0, &mut rng);
+            let angle = v.atan2(u).rem_euclid(std::f32::consts::TAU / blades as f32);
+            let edge_distance = apothem / (angle - std::f32::consts::PI / blades as f32).cos();
+            assert!(u.hypot(v) <= edge_distance + 1e-5);
+        }
+    }
+
+    #[test]
+    fn image_samples_bright_pixels() {
+        // Only the top right pixel of the 2x2 image is lit
+        let image = GrayImage::from_fn(2, 2, |x, y| Luma([if x == 1 && y == 0 { 255 } else { 0 }]));
+        let aperture = ApertureImage::new(&image).unwrap();
+        let mut rng = rand::rng();
+
+        for _ in 0..100 {
+            let [u, v] = aperture.sample(&mut rng);
+            assert!((0.0..=1.0).contains(&u));
+            assert!((0.0..=1.0).contains(&v));
+        }
+    }
+
+    #[test]
+    fn black_image_is_rejected() {
+        assert!(ApertureImage::new(&GrayImage::new(4, 4)).is_none());
+    }
+
+    #[test]
+    fn cats_eye_clips_off_axis() {
+        let aperture = Aperture {
+            cats_eye: 1.0,
+            ..Aperture::default()
+        };
+        let mut rng = rand::rng();
+        let corner = Vector2::new(1.0, 0.0);
+
+        for _ in 0..1000 {
+            let [u, v] = aperture.sample(corner, &mut rng);
+            assert!(u.hypot(v) <= 1.0);
+            assert!((u - 1.0).hypot(v) <= 1.0);
+        }
+    }
+
+    #[test]
+    fn cats_eye_gives_up_on_unreachable_image() {
+        // Only the top right pixel of the 4x4 image is lit, it's always more than one away
+        // from the barrel center in the opposite corner
+        let image = GrayImage::from_fn(4, 4, |x, y| Luma([if x == 3 && y == 0 { 255 } else { 0 }]));
+        let aperture = Aperture {
+            shape: ApertureShape::Image(Arc::new(ApertureImage::new(&image).unwrap())),
+            cats_eye: 1.0,
+            ..Aperture::default()
+        };
+        let mut rng = rand::rng();
+        let opposite_corner = Vector2::new(-1.0, -1.0).normalize();
+
+        for _ in 0..100 {
+            let [u, v] = aperture.sample(opposite_corner, &mut rng);
+            assert!((0.5..=1.0).contains(&u));
+            assert!((0.5..=1.0).contains(&v));
+        }
+    }
+
+    #[test]
+    fn anamorphic_squeeze_narrows() {
+        let aperture = Aperture {
+            anamorphic_squeeze: 2.0,
+            ..Aperture::default()
+        };
+        let mut rng = rand::rng();
+
+        for _ in 0..1000 {
+            let [u, _] = aperture.sample(Vector2::zeros(), &mut rng);
+            assert!(u.abs() <= 0.5);
+        }
+    }
+}
@@ -0,0 +1,667 @@
+mod aperture;
+
+use assert2::assert;
+use nalgebra::{Isometry3, Translation3, Unit, UnitQuaternion, Vector2};
+
+use crate::{
+    geometry::{FloatType, Ray, ScreenPoint, ScreenSize, WorldBox, WorldPoint, WorldVector},
+    scene::{Object, Scene, triangle_bvh::StackCache},
+};
+
+pub use aperture::{Aperture, ApertureImage, ApertureShape};
+
+/// Represents camera looking at the scene
+#[derive(Clone, Debug)]
+pub struct Camera {
+    pub camera_to_world: Isometry3<FloatType>,
+
+    pub focus_distance: FloatType,
+
+    pub sensor_size: SensorSize,
+    pub focal_length: FloatType,
+    pub f_number: FloatType,
+    pub aperture: Aperture,
+
+    pub projection: Projection,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum SensorSize {
+    Width(FloatType),
+    Height(FloatType),
+}
+
+/// How directions in the scene map to points on the image.
+/// Only the perspective projection simulates a lens with depth of field,
+/// all other projections are pinhole cameras.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Projection {
+    /// Thin lens camera.
+    #[default]
+    Perspective,
+    /// Parallel rays along the viewing direction, covering a rectangle of the given
+    /// height (in world units) centered at the camera.
+    Orthographic { height: FloatType },
+    /// Full 360 degree panorama, longitude along the image width and latitude along its height.
+    /// Ignores the sensor size and focal length, the image should have 2:1 aspect ratio.
+    Equirectangular,
+    /// Wide angle lens, angle from the viewing direction grows with the distance
+    /// from the center of the sensor.
+    Fisheye(FisheyeMapping),
+    /// Panorama wrapped around the vertical axis of the camera, angle grows linearly
+    /// with the horizontal position, vertical direction is a plain perspective.
+    Cylindrical,
+}
+
+/// Relation between distance `r` from the image center and angle `theta` from the viewing direction.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FisheyeMapping {
+    /// `r = f * theta`
+    Equidistant,
+    /// `r = 2 * f * sin(theta / 2)`, preserves areas.
+    Equisolid,
+}
+
+#[derive(Clone, Debug)]
+pub struct CameraSampler {
+    center: WorldPoint,
+    projection: Projection,
+
+    forward: Unit<WorldVector>,
+    up: Unit<WorldVector>,
+    right: Unit<WorldVector>,
+
+    resolution: Vector2<FloatType>,
+    /// Position of the image center on the film, relative to the center of pixel 0, 0
+    film_center: Vector2<FloatType>,
+    /// Distance between pixels in meters (in world units for the orthographic projection)
+    pixel_scale: FloatType,
+    /// Distance from the image center to its corner, in the same units as `pixel_scale`
+    half_diagonal: FloatType,
+    focal_length: FloatType,
+
+    /// Lens radius in meters
+    lens_radius: FloatType,
+    lens_weight: FloatType,
+    aperture: Aperture,
+}
+
+/// Default camera is a 35mm camera with 50mm f/9 lens, looks along Z, focuses at infinity.
+impl Default for Camera {
+    fn default() -> Self {
+        Camera {
+            camera_to_world: Default::default(),
+            focus_distance: FloatType::INFINITY,
+            sensor_size: SensorSize::Height(24e-3),
+            focal_length: 50e-3,
+            f_number: 9.0,
+            aperture: Aperture::default(),
+            projection: Projection::Perspective,
+        }
+    }
+}
+
+impl Camera {
+    /// Creates a new camera that replaces its transform with the argument
+    pub fn with_transform(&self, camera_to_world: Isometry3<FloatType>) -> Camera {
+        Camera {
+            camera_to_world,
+            ..self.clone()
+        }
+    }
+
+    pub fn focus_distance(&self, focus_distance: FloatType) -> Camera {
+        assert!(focus_distance >= 0.0);
+        Camera {
+            focus_distance,
+            ..self.clone()
+        }
+    }
+
+    pub fn sensor_width(&self, sensor_width: FloatType) -> Camera {
+        assert!(sensor_width > 0.0);
+        Camera {
+            sensor_size: SensorSize::Width(sensor_width),
+            ..self.clone()
+        }
+    }
+
+    pub fn sensor_height(&self, sensor_height: FloatType) -> Camera {
+        assert!(sensor_height > 0.0);
+        Camera {
+            sensor_size: SensorSize::Height(sensor_height),
+            ..self.clone()
+        }
+    }
+
+    pub fn focal_length(&self, focal_length: FloatType) -> Camera {
+        assert!(focal_length > 0.0);
+        Camera {
+            focal_length,
+            ..self.clone()
+        }
+    }
+
+    pub fn f_number(&self, f_number: FloatType) -> Camera {
+        assert!(f_number > 0.0);
+        Camera {
+            f_number,
+            ..self.clone()
+        }
+    }
+
+    pub fn aperture(&self, aperture: Aperture) -> Camera {
+        assert!((0.0..=1.0).contains(&aperture.cats_eye));
+        assert!(aperture.anamorphic_squeeze > 0.0);
+        Camera {
+            aperture,
+            ..self.clone()
+        }
+    }
+
+    pub fn projection(&self, projection: Projection) -> Camera {
+        if let Projection::Orthographic { height } = projection {
+            assert!(height > 0.0);
+        }
+        Camera {
+            projection,
+            ..self.clone()
+        }
+    }
+
+    /// Creates a new camera that looks from `center` to `look_at` and also focuses at `look_at`
+    pub fn look_at(&self, center: WorldPoint, look_at: WorldPoint, up: WorldVector) -> Camera {
+        let transform = Isometry3::look_at_rh(&center, &look_at, &up);
+
+        Camera {
+            camera_to_world: transform.inverse(),
+            focus_distance: (look_at - center).norm(),
+            ..self.clone()
+        }
+    }
+
+    /// Creates a new camera that looks from `center` looking `forward`.
+    pub fn look_direction(
+        &self,
+        center: WorldPoint,
+        forward: WorldVector,
+        up: WorldVector,
+    ) -> Camera {
+        let transform = Isometry3::look_at_rh(&center, &(center + forward), &up);
+
+        Camera {
+            camera_to_world: transform.inverse(),
+            ..self.clone()
+        }
+    }
+
+    /// Creates a new camera that applies the given transform relative to the previous camera frame
+    pub fn transformed(&self, transform: Isometry3<FloatType>) -> Camera {
+        self.with_transform(transform * self.camera_to_world)
+    }
+
+    /// Creates a new camera moved by an offset given in camera coordinates
+    /// (x goes right, y goes up, z goes forward).
+    pub fn moved(&self, offset: &WorldVector) -> Camera {
+        let (_, forward, up, right) = self.center_forward_up_right();
+        let world_offset =
+            right.as_ref() * offset.x + up.as_ref() * offset.y + forward.as_ref() * offset.z;
+        self.transformed(Translation3::from(world_offset).into())
+    }
+
+    /// Creates a new camera rotated around a pivot point, first tilted by `pitch` around
+    /// its own horizontal axis, then turned by `yaw` around the `up` axis.
+    /// Angles are in radians, positive values turn left and up.
+    pub fn orbit(
+        &self,
+        pivot: &WorldPoint,
+        up: &Unit<WorldVector>,
+        yaw: FloatType,
+        pitch: FloatType,
+    ) -> Camera {
+        let (_, _, _, right) = self.center_forward_up_right();
+        let rotation = UnitQuaternion::from_axis_angle(up, yaw)
+            * UnitQuaternion::from_axis_angle(&right, pitch);
+        let around_pivot =
+            Translation3::from(pivot.coords) * rotation * Translation3::from(-pivot.coords);
+        self.transformed(around_pivot)
+    }
+
+    /// Creates a new camera rotated around its own center, see `orbit`.
+    pub fn look_around(&self, up: &Unit<WorldVector>, yaw: FloatType, pitch: FloatType) -> Camera {
+        let (center, _, _, _) = self.center_forward_up_right();
+        self.orbit(&center, up, yaw, pitch)
+    }
+
+    /// Creates a new camera that looks in the same direction, moved so that the whole box
+    /// fits in the view and focused at the box center.
+    /// The orthographic projection gets its height adjusted instead of being moved far away,
+    /// the panoramic projections are placed as if they were perspective.
+    pub fn frame(&self, bounding_box: &WorldBox, resolution: ScreenSize) -> Camera {
+        let (_, forward, _, _) = self.center_forward_up_right();
+        let target = bounding_box.center();
+        let radius = bounding_box.size().norm() / 2.0;
+
+        let (distance, camera) = match self.projection {
+            Projection::Orthographic { .. } => {
+                let aspect = resolution.x as FloatType / resolution.y as FloatType;
+                let height = 2.0 * radius * aspect.recip().max(1.0);
+                (
+                    2.0 * radius,
+                    self.projection(Projection::Orthographic { height }),
+                )
+            }
+            _ => {
+                let fov = self.field_of_view(resolution);
+                (radius / (fov.min() / 2.0).sin(), self.clone())
+            }
+        };
+
+        let center = target - forward.as_ref() * distance;
+        camera
+            .with_transform(Isometry3::from_parts(
+                Translation3::from(center.coords),
+                self.camera_to_world.rotation,
+            ))
+            .focus_distance(distance)
+    }
+
+    /// Creates a new camera focused on whatever is visible through the center of the given pixel.
+    /// Returns None if the scene is not visible there.
+    pub fn autofocus<O: Object>(
+        &self,
+        scene: &Scene<O>,
+        resolution: ScreenSize,
+        point: &ScreenPoint,
+    ) -> Option<Camera> {
+        let sampler = self.build_sampler(resolution);
+        let hit = scene
+            .object
+            .intersect(&sampler.center_ray(point), &mut StackCache::default())?;
+        Some(self.focus_distance(sampler.depth(&hit.point)))
+    }
+
+    /// Returns horizontal and vertical field of view of the perspective projection, in radians.
+    pub fn field_of_view(&self, resolution: ScreenSize) -> Vector2<FloatType> {
+        let resolution = resolution.cast::<FloatType>();
+        let sensor = match self.sensor_size {
+            SensorSize::Width(w) => Vector2::new(w, w * resolution.y / resolution.x),
+            SensorSize::Height(h) => Vector2::new(h * resolution.x / resolution.y, h),
+        };
+        sensor.map(|size| 2.0 * (size / (2.0 * self.focal_length)).atan())
+    }
+
+    pub fn build_sampler(&self, resolution: ScreenSize) -> CameraSampler {
+        let (center, forward, up, right) = self.center_forward_up_right();
+
+        let resolution = resolution.cast::<FloatType>();
+        let pixel_scale = match (self.projection, self.sensor_size) {
+            (Projection::Orthographic { height }, _) => height / resolution.y,
+            (_, SensorSize::Width(w)) => w / resolution.x,
+            (_, SensorSize::Height(h)) => h / resolution.y,
+        };
+
+        let film_center = (resolution.map(|x| x - 1.0) * pixel_scale) / 2.0;
+
+        CameraSampler {
+            center,
+            projection: self.projection,
+            forward,
+            up,
+            right,
+            resolution,
+            film_center,
+            pixel_scale,
+            half_diagonal: (resolution * pixel_scale).norm() / 2.0,
+            focal_length: self.focal_length,
+            lens_radius: self.focal_length / (2.0 * self.f_number),
+            lens_weight: self.focal_length / self.focus_distance,
+            aperture: self.aperture.clone(),
+        }
+    }
+
+    pub fn center_forward_up_right(
+        &self,
+    ) -> (
+        WorldPoint,
+        Unit<WorldVector>,
+        Unit<WorldVector>,
+        Unit<WorldVector>,
+    ) {
+        let center = self.camera_to_world.transform_point(&WorldPoint::origin());
+        let forward = Unit::new_unchecked(
+            self.camera_to_world
+                .transform_vector(&WorldVector::new(0.0, 0.0, -1.0)),
+        );
+        let up = Unit::new_unchecked(
+            self.camera_to_world
+                .transform_vector(&WorldVector::new(0.0, 1.0, 0.0)),
+        );
+        let right = Unit::new_unchecked(
+            self.camera_to_world
+                .transform_vector(&WorldVector::new(1.0, 0.0, 0.0)),
+        );
+
+        (center, forward, up, right)
+    }
+}
+
+impl CameraSampler {
+    /// Samples a new ray from the camera for the given image pixel.
+    pub fn sample_ray(&self, point: &ScreenPoint, rng: &mut impl rand::Rng) -> Ray {
+        //TODO: Figure out a better reconstruction kernel for the pixel than a square
+        let film_u = point.x as f32 + rng.random_range(-0.5..=0.5);
+        let film_v = point.y as f32 + rng.random_range(-0.5..=0.5);
+
+        let lens_uv = if self.projection == Projection::Perspective {
+            let film_point = self.film_position(film_u, film_v) / self.half_diagonal;
+            self.aperture.sample(film_point, rng)
+        } else {
+            [0.0, 0.0]
+        };
+
+        self.ray(film_u, film_v, lens_uv)
+    }
+
+    /// Returns the ray through the center of the pixel and the center of the lens.
+    pub fn center_ray(&self, point: &ScreenPoint) -> Ray {
+        self.ray(point.x as FloatType, point.y as FloatType, [0.0, 0.0])
+    }
+
+    /// Returns the ray through a point on the film (in pixels) and a point on the lens
+    /// (within unit circle). The lens point is only used by the perspective projection.
+    fn ray(&self, film_u: FloatType, film_v: FloatType, lens_uv: [FloatType; 2]) -> Ray {
+        let film_position = self.film_position(film_u, film_v);
+        let (x, y) = (film_position.x, film_position.y);
+
+        match self.projection {
+            Projection::Perspective => {
+                let lens_vector = self.right.as_ref() * (self.lens_radius * lens_uv[0])
+                    + self.up.as_ref() * (self.lens_radius * lens_uv[1]);
+
+                let direction =
+                    lens_vector * self.lens_weight + self.camera_vector(x, y, self.focal_length);
+                Ray::new(self.center + lens_vector, direction)
+            }
+            Projection::Orthographic { .. } => Ray::new(
+                self.center + self.right.as_ref() * x + self.up.as_ref() * y,
+                self.forward.into_inner(),
+            ),
+            Projection::Equirectangular => {
+                let longitude = (film_u / self.resolution.x - 0.5) * std::f32::consts::TAU;
+                let latitude = (0.5 - film_v / self.resolution.y) * std::f32::consts::PI;
+                let horizontal = latitude.cos();
+                let direction = self.camera_vector(
+                    horizontal * longitude.sin(),
+                    latitude.sin(),
+                    horizontal * longitude.cos(),
+                );
+                Ray::new(self.center, direction)
+            }
+            Projection::Fisheye(mapping) => {
+                let r = x.hypot(y) / self.focal_length;
+                let theta = match mapping {
+                    FisheyeMapping::Equidistant => r,
+                    FisheyeMapping::Equisolid => 2.0 * (r / 2.0).min(1.0).asin(),
+                };
+                // Direction of the point from the center, doesn't matter at the center itself
+                let scale = if r > 0.0 { theta.sin() / r } else { 0.0 };
+                let direction = self.camera_vector(
+                    x / self.focal_length * scale,
+                    y / self.focal_length * scale,
+                    theta.cos(),
+                );
+                Ray::new(self.center, direction)
+            }
+            Projection::Cylindrical => {
+                let angle = x / self.focal_length;
+                let direction = self.camera_vector(angle.sin(), y / self.focal_length, angle.cos());
+                Ray::new(self.center, direction)
+            }
+        }
+    }
+
+    /// Distance of a point from the camera, measured along the viewing direction for
+    /// the planar projections, and from the camera center for the panoramic ones.
+    pub fn depth(&self, point: &WorldPoint) -> FloatType {
+        match self.projection {
+            Projection::Perspective | Projection::Orthographic { .. } => {
+                (point - self.center).dot(&self.forward)
+            }
+            _ => (point - self.center).norm(),
+        }
+    }
+
+    /// Position on the film relative to its center, x goes right, y goes up.
+    fn film_position(&self, film_u: FloatType, film_v: FloatType) -> Vector2<FloatType> {
+        Vector2::new(
+            film_u * self.pixel_scale - self.film_center.x,
+            self.film_center.y - film_v * self.pixel_scale,
+        )
+    }
+
+    /// Converts a vector from camera coordinates (x right, y up, z forward) to world space.
+    fn camera_vector(&self, x: FloatType, y: FloatType, z: FloatType) -> WorldVector {
+        self.right.as_ref() * x + self.up.as_ref() * y + self.forward.as_ref() * z
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::{assert, let_assert};
+    use nalgebra::Point3;
+
+    #[test]
+    fn left_right_up_down() {
+        // X goes right, Y goes away, Z goes up
+        let camera = Camera::default()
+            .look_direction(
+                WorldPoint::new(0.0, 0.0, 0.0),
+                WorldVector::new(0.0, 1.0, 0.0), /* forward */
+                WorldVector::new(0.0, 0.0, 1.0), /* up */
+            )
+            .focus_distance(2.0)
+            .build_sampler(ScreenSize::new(800, 600));
+
+        let mut rng = rand::rng();
+
+        let ray_center = camera.sample_ray(&ScreenPoint::new(400, 300), &mut rng);
+        let ray_left = camera.sample_ray(&ScreenPoint::new(0, 300), &mut rng);
+        let ray_right = camera.sample_ray(&ScreenPoint::new(799, 300), &mut rng);
+        let ray_up = camera.sample_ray(&ScreenPoint::new(400, 0), &mut rng);
+        let ray_down = camera.sample_ray(&ScreenPoint::new(400, 599), &mut rng);
+
+        assert!(ray_center.direction.x.abs() < 1e-3);
+        assert!(ray_center.direction.z.abs() < 1e-3);
+        assert!(ray_left.direction.x < ray_center.direction.x);
+        assert!(ray_right.direction.x > ray_center.direction.x);
+        assert!(ray_up.direction.z > ray_center.direction.z);
+        assert!(ray_down.direction.z < ray_center.direction.z);
+    }
+
+    /// Camera looking along -Z with a 10mm lens on a 36mm sensor, 600x600 pixels.
+    fn wide_sampler(projection: Projection) -> CameraSampler {
+        Camera {
+            focal_length: 10e-3,
+            ..Camera::default()
+        }
+        .sensor_height(36e-3)
+        .projection(projection)
+        .build_sampler(ScreenSize::new(600, 600))
+    }
+
+    /// Angle between the ray through the pixel and the viewing direction.
+    fn off_axis_angle(sampler: &CameraSampler, point: ScreenPoint) -> FloatType {
+        let ray = sampler.sample_ray(&point, &mut rand::rng());
+        ray.direction.dot(&WorldVector::new(0.0, 0.0, -1.0)).acos()
+    }
+
+    #[test]
+    fn orthographic_rays_are_parallel() {
+        let sampler = Camera::default()
+            .projection(Projection::Orthographic { height: 2.0 })
+            .build_sampler(ScreenSize::new(200, 100));
+        let mut rng = rand::rng();
+
+        let ray_center = sampler.sample_ray(&ScreenPoint::new(100, 50), &mut rng);
+        let ray_left = sampler.sample_ray(&ScreenPoint::new(0, 50), &mut rng);
+
+        assert!(ray_center.direction.z == -1.0);
+        assert!(ray_left.direction.z == -1.0);
+        assert!((ray_left.origin.x + 2.0).abs() < 0.02);
+        assert!(ray_center.origin.x.abs() < 0.02);
+    }
+
+    #[test]
+    fn equirectangular_covers_all_directions() {
+        let sampler = Camera::default()
+            .projection(Projection::Equirectangular)
+            .build_sampler(ScreenSize::new(400, 200));
+        let mut rng = rand::rng();
+        let mut direction = |x, y| {
+            sampler
+                .sample_ray(&ScreenPoint::new(x, y), &mut rng)
+                .direction
+                .into_inner()
+        };
+
+        assert!(direction(200, 100).z < -0.99);
+        assert!(direction(0, 100).z > 0.99);
+        assert!(direction(300, 100).x > 0.99);
+        assert!(direction(200, 0).y > 0.99);
+        assert!(direction(200, 199).y < -0.99);
+    }
+
+    #[test]
+    fn fisheye_mappings() {
+        // 200.5 pixels from the center, 1.203 focal lengths
+        let point = ScreenPoint::new(500, 300);
+        let r: FloatType = 200.5 * 6e-5 / 10e-3;
+
+        let equidistant = wide_sampler(Projection::Fisheye(FisheyeMapping::Equidistant));
+        assert!((off_axis_angle(&equidistant, point) - r).abs() < 0.01);
+
+        let equisolid = wide_sampler(Projection::Fisheye(FisheyeMapping::Equisolid));
+        assert!((off_axis_angle(&equisolid, point) - 2.0 * (r / 2.0).asin()).abs() < 0.01);
+    }
+
+    #[test]
+    fn cylindrical_angle_is_linear() {
+        let sampler = wide_sampler(Projection::Cylindrical);
+        let r: FloatType = 200.5 * 6e-5 / 10e-3;
+
+        let ray = sampler.sample_ray(&ScreenPoint::new(500, 300), &mut rand::rng());
+        let horizontal_angle = ray.direction.x.atan2(-ray.direction.z);
+        assert!((horizontal_angle - r).abs() < 0.01);
+
+        let ray_up = sampler.sample_ray(&ScreenPoint::new(300, 0), &mut rand::rng());
+        assert!(ray_up.direction.y > 0.5);
+    }
+
+    #[test]
+    fn field_of_view() {
+        // 36x24mm sensor with 18mm lens has 90 degrees horizontal field of view
+        let camera = Camera {
+            focal_length: 18e-3,
+            ..Camera::default()
+        };
+        let fov = camera.field_of_view(ScreenSize::new(1500, 1000));
+
+        assert!((fov.x - std::f32::consts::FRAC_PI_2).abs() < 1e-5);
+        assert!((fov.y - 2.0 * (12.0f32 / 18.0).atan()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn frame_fits_box() {
+        let resolution = ScreenSize::new(800, 600);
+        let bounding_box = WorldBox::new(
+            WorldPoint::new(-1.0, -1.0, -1.0),
+            WorldPoint::new(1.0, 3.0, 1.0),
+        );
+        let camera = Camera::default().frame(&bounding_box, resolution);
+        let sampler = camera.build_sampler(resolution);
+
+        // Looks at the box center from far enough for its bounding sphere to fit the view
+        let ray = sampler.center_ray(&ScreenPoint::new(400, 300));
+        assert!((ray.origin.xy() - WorldPoint::new(0.0, 1.0, 0.0).xy()).norm() < 1e-2);
+        assert!((camera.focus_distance - sampler.depth(&bounding_box.center())).abs() < 1e-4);
+        for corner in [bounding_box.min, bounding_box.max] {
+            let direction = (corner - ray.origin).normalize();
+            let angle = direction.dot(&ray.direction).acos();
+            assert!(angle < camera.field_of_view(resolution).y / 2.0);
+        }
+    }
+
+    #[test]
+    fn autofocus() {
+        let scene = Scene::new(crate::scene::primitives::Sphere {
+            center: WorldPoint::new(0.0, 0.0, -5.0),
+            radius: 1.0,
+        });
+        let resolution = ScreenSize::new(100, 100);
+        let camera = Camera::default();
+
+        let focused = camera.autofocus(&scene, resolution, &ScreenPoint::new(50, 50));
+        let_assert!(Some(focused) = focused);
+        assert!((focused.focus_distance - 4.0).abs() < 1e-2);
+
+        assert!(
+            camera
+                .autofocus(&scene, resolution, &ScreenPoint::new(0, 0))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn moved_in_camera_frame() {
+        // Looking along -Z, right is +X
+        let camera = Camera::default().moved(&WorldVector::new(1.0, 2.0, 3.0));
+        let (center, _, _, _) = camera.center_forward_up_right();
+        assert!((center - WorldPoint::new(1.0, 2.0, -3.0)).norm() < 1e-6);
+    }
+
+    #[test]
+    fn orbit_keeps_distance_to_pivot() {
+        let pivot = WorldPoint::new(0.0, 0.0, -5.0);
+        let up = WorldVector::y_axis();
+        let camera = Camera::default().orbit(&pivot, &up, std::f32::consts::FRAC_PI_2, 0.3);
+        let (center, forward, _, _) = camera.center_forward_up_right();
+
+        assert!(((center - pivot).norm() - 5.0).abs() < 1e-5);
+        assert!(((pivot - center).normalize() - forward.into_inner()).norm() < 1e-5);
+        // Turned left by 90 degrees, the camera is now on the right of the pivot
+        assert!(center.x > 4.0);
+    }
+
+    #[test]
+    fn look_around_keeps_center() {
+        let up = WorldVector::y_axis();
+        let camera = Camera::default().look_around(&up, 0.5, 0.0);
+        let (center, forward, _, _) = camera.center_forward_up_right();
+
+        assert!(center.coords.norm() < 1e-6);
+        assert!(forward.x < 0.0);
+    }
+
+    #[test]
+    fn relative_translation() {
+        let camera = Camera::default()
+            .look_direction(
+                WorldPoint::new(0.0, 0.0, 0.0),
+                WorldVector::new(0.0, 1.0, 0.0), /* forward */
+                WorldVector::new(0.0, 0.0, 1.0), /* up */
+            )
+            .focus_distance(2.0);
+        dbg!(camera.center_forward_up_right());
+
+        let camera_translated = camera.transformed(Translation3::new(1.0, 2.0, 3.0).into());
+
+        let (center, _, _, _) = camera_translated.center_forward_up_right();
+        assert!(
+            (center - Point3::new(1.0, 2.0, 3.0)).norm() < 1e-6,
+            "{:?}",
+            center
+        );
+    }
+}
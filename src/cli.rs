@@ -1,15 +1,167 @@
-use std::sync::Arc;
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        mpsc::{Receiver, RecvTimeoutError},
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
+use anyhow::{Context as _, bail};
 use minipath::{
-    Camera, RenderSettings, Scene,
+    Aov, Camera, Checkpoint, DenoiseSettings, Film, RenderEvent, RenderEvents, RenderSettings,
+    Scene, ShadingMode, denoise,
+    distributed::{self, Address},
     geometry::{ScreenSize, WorldPoint, WorldVector},
-    render,
-    scene::triangle_bvh::TriangleBvh,
+    render, resume,
+    scene::triangle_bvh::{BuildProgress, SplitMode, TriangleBvh},
 };
 
 use indicatif::ProgressBar;
 
+const USAGE: &str = "\
+Usage: minipath-cli [--serve ADDRESS | --worker ADDRESS] [--output FILE] [--time-limit SECONDS]
+                    [--checkpoint FILE [--checkpoint-interval SECONDS] [--resume]]
+                    [--shading MODE] [--aovs LIST] [--denoise] [--bvh-cache FILE]
+                    [--spatial-splits] [--print-bvh] [--export-bvh FILE] [--validate-bvh]
+
+  --serve ADDRESS                Hand out tiles to worker processes instead of rendering locally
+  --worker ADDRESS               Render tiles for a coordinator running with --serve
+  --output FILE                  Save the rendered image
+  --time-limit SECONDS           Stop rendering after this time, keeping the samples done so far
+  --checkpoint FILE              Periodically save render state to a file (local rendering only)
+  --checkpoint-interval SECONDS  Time between checkpoint saves, default 60
+  --resume                       Continue the render from the checkpoint file, if it exists
+  --shading MODE                 Render a debug view instead of the shaded image
+  --aovs LIST                    Also save auxiliary outputs next to the --output file,
+                                 as float EXR images named OUTPUT_STEM.AOV.exr
+  --denoise                      Filter noise out of the saved image, guided by the AOVs
+  --bvh-cache FILE               Load the built BVH from this file, or build it and save it there
+                                 if the file is missing or was built from a different mesh
+  --spatial-splits               Build the BVH with spatial splits, slower to build but
+                                 faster to trace with long thin triangles
+  --print-bvh                    Print the BVH nodes and exit without rendering
+  --export-bvh FILE              Save boxes of the BVH nodes as OBJ wireframe, grouped by depth,
+                                 or as JSON if FILE ends with .json, and exit without rendering
+  --validate-bvh                 Check consistency of the BVH and exit without rendering
+
+ADDRESS is either host:port for TCP, or unix:PATH for a Unix socket.
+MODE is one of shaded (default), geometric-normal, shading-normal, uv, material-id, distance,
+triangle-index, inner-node-heatmap, triangle-packet-heatmap.
+LIST is a comma separated list of albedo, normal, depth, position, material-id, sample-count,
+or all.";
+
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+enum Mode {
+    Local,
+    Serve(Address),
+    Worker(Address),
+}
+
+struct Args {
+    mode: Mode,
+    output: Option<PathBuf>,
+    time_limit: Option<Duration>,
+    checkpoint: Option<PathBuf>,
+    checkpoint_interval: Duration,
+    resume: bool,
+    shading_mode: ShadingMode,
+    aovs: Vec<Aov>,
+    denoise: bool,
+    bvh_cache: Option<PathBuf>,
+    split_mode: SplitMode,
+    print_bvh: bool,
+    export_bvh: Option<PathBuf>,
+    validate_bvh: bool,
+}
+
+impl Args {
+    fn parse() -> anyhow::Result<Args> {
+        let mut args = Args {
+            mode: Mode::Local,
+            output: None,
+            time_limit: None,
+            checkpoint: None,
+            checkpoint_interval: Duration::from_secs(60),
+            resume: false,
+            shading_mode: ShadingMode::Shaded,
+            aovs: Vec::new(),
+            denoise: false,
+            bvh_cache: None,
+            split_mode: SplitMode::Object,
+            print_bvh: false,
+            export_bvh: None,
+            validate_bvh: false,
+        };
+
+        let mut it = std::env::args().skip(1);
+        while let Some(arg) = it.next() {
+            let mut value = || it.next().with_context(|| format!("{arg} needs a value"));
+            match arg.as_str() {
+                "--serve" => args.mode = Mode::Serve(value()?.parse()?),
+                "--worker" => args.mode = Mode::Worker(value()?.parse()?),
+                "--output" => args.output = Some(value()?.into()),
+                "--time-limit" => args.time_limit = Some(parse_seconds(&arg, &value()?)?),
+                "--checkpoint" => args.checkpoint = Some(value()?.into()),
+                "--checkpoint-interval" => {
+                    args.checkpoint_interval = parse_seconds(&arg, &value()?)?
+                }
+                "--resume" => args.resume = true,
+                "--shading" => args.shading_mode = value()?.parse()?,
+                "--aovs" => {
+                    args.aovs = match value()?.as_str() {
+                        "all" => Aov::ALL.to_vec(),
+                        list => list.split(',').map(str::parse).collect::<Result<_, _>>()?,
+                    }
+                }
+                "--denoise" => args.denoise = true,
+                "--bvh-cache" => args.bvh_cache = Some(value()?.into()),
+                "--spatial-splits" => args.split_mode = SplitMode::Spatial,
+                "--print-bvh" => args.print_bvh = true,
+                "--export-bvh" => args.export_bvh = Some(value()?.into()),
+                "--validate-bvh" => args.validate_bvh = true,
+                "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                _ => bail!("Unknown argument {arg}\n\n{USAGE}"),
+            }
+        }
+
+        if args.resume && args.checkpoint.is_none() {
+            bail!("--resume needs --checkpoint");
+        }
+        if !args.aovs.is_empty() && args.output.is_none() {
+            bail!("--aovs needs --output");
+        }
+
+        Ok(args)
+    }
+}
+
 fn main() -> anyhow::Result<()> {
+    let args = Args::parse()?;
+
+    if args.print_bvh || args.export_bvh.is_some() || args.validate_bvh {
+        let scene = load_scene(args.bvh_cache.as_deref(), args.split_mode)?;
+        if args.print_bvh {
+            scene.object.print_tree();
+        }
+        if let Some(path) = &args.export_bvh {
+            scene
+                .object
+                .export_boxes(path)
+                .with_context(|| format!("Exporting BVH to {}", path.display()))?;
+        }
+        if args.validate_bvh {
+            scene.object.validate()?;
+            println!("BVH is valid");
+        }
+        return Ok(());
+    }
+
     let camera = Camera::default()
         .look_at(
             WorldPoint::new(0.0, 2.0, 10.0),
@@ -23,25 +175,157 @@ fn main() -> anyhow::Result<()> {
         tile_size: 64.try_into().unwrap(),
         sample_count: 100.try_into().unwrap(),
         resolution: ScreenSize::new(2048, 1536),
+        shading_mode: args.shading_mode,
+        time_limit: args.time_limit,
     };
-    let scene = Arc::new(Scene {
-        object: TriangleBvh::with_obj("data/teapot.obj").unwrap(),
-    });
-    scene.object.print_statistics();
 
     let bar = ProgressBar::no_length();
-    let mut render_progress = render(scene, camera, settings, |_| {}, {
-        let bar = bar.clone();
-        move |_, progress| {
-            bar.update(|ps| {
-                ps.set_len(progress.total as u64);
-                ps.set_pos(progress.finished as u64)
-            })
+
+    let events = RenderEvents::new();
+    let receiver = events.subscribe();
+
+    let film = match args.mode {
+        Mode::Local => {
+            let scene = Arc::new(load_scene(args.bvh_cache.as_deref(), args.split_mode)?);
+            let mut render_progress = match &args.checkpoint {
+                Some(path) if args.resume && path.exists() => {
+                    let checkpoint = Checkpoint::load(path)
+                        .with_context(|| format!("Loading checkpoint {}", path.display()))?;
+                    resume(scene, camera, settings, checkpoint, events)?
+                }
+                _ => render(scene, camera, settings, events)?,
+            };
+            bar.set_length(render_progress.progress().total as u64);
+
+            let mut last_save = Instant::now();
+            report_events(&bar, &receiver, || {
+                if let Some(path) = &args.checkpoint
+                    && last_save.elapsed() >= args.checkpoint_interval
+                {
+                    render_progress.checkpoint().save(path)?;
+                    last_save = Instant::now();
+                }
+                Ok(!render_progress.is_finished())
+            })?;
+
+            render_progress.wait();
+            let checkpoint = render_progress.checkpoint();
+            if let Some(path) = &args.checkpoint {
+                checkpoint.save(path)?;
+            }
+            checkpoint.film().clone()
+        }
+        Mode::Serve(address) => {
+            let listener = address.bind()?;
+            println!("Waiting for workers on {}", listener.local_address()?);
+            thread::scope(|scope| -> anyhow::Result<Film> {
+                let coordinator = scope.spawn(|| distributed::serve(&listener, settings, &events));
+                report_events(&bar, &receiver, || Ok(!coordinator.is_finished()))?;
+                Ok(coordinator.join().expect("Coordinator panicked!")?)
+            })?
+        }
+        Mode::Worker(address) => {
+            let scene = load_scene(args.bvh_cache.as_deref(), args.split_mode)?;
+            let tile_count = distributed::work(&address, &scene, &camera, num_cpus::get())?;
+            println!("Rendered {tile_count} tiles");
+            return Ok(());
         }
-    })?;
-    bar.set_length(render_progress.progress().total as u64);
+    };
+    bar.finish();
 
-    render_progress.wait();
+    if let Some(output) = args.output {
+        let image = if args.denoise {
+            denoise(&film, &DenoiseSettings::default()).develop()
+        } else {
+            film.develop()
+        };
+        image.save(&output)?;
+        for aov in args.aovs {
+            let path = aov_path(&output, aov);
+            film.develop_aov(aov)
+                .save(&path)
+                .with_context(|| format!("Saving {}", path.display()))?;
+        }
+    }
 
     Ok(())
 }
+
+/// Parses a duration in seconds given as the value of a command line argument.
+fn parse_seconds(arg: &str, value: &str) -> anyhow::Result<Duration> {
+    let seconds: f64 = value
+        .parse()
+        .with_context(|| format!("{arg} needs a number of seconds, got {value:?}"))?;
+    Duration::try_from_secs_f64(seconds)
+        .with_context(|| format!("{arg} needs a non-negative finite duration, got {value:?}"))
+}
+
+/// Returns path of the file for an AOV, next to the main output file.
+fn aov_path(output: &Path, aov: Aov) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    output.with_file_name(format!("{stem}.{aov}.exr"))
+}
+
+/// Shows render events on the progress bar until the render finishes.
+/// `poll` is called after every event and at least once per `EVENT_POLL_INTERVAL`,
+/// the loop also ends when it returns false.
+fn report_events(
+    bar: &ProgressBar,
+    receiver: &Receiver<RenderEvent>,
+    mut poll: impl FnMut() -> anyhow::Result<bool>,
+) -> anyhow::Result<()> {
+    loop {
+        match receiver.recv_timeout(EVENT_POLL_INTERVAL) {
+            Ok(event) => {
+                if report_event(bar, event) {
+                    return Ok(());
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        // Busy renders send events more often than the timeout, so polling only on timeouts
+        // could starve it
+        if !poll()? {
+            // Final events may still be queued behind the ones already reported
+            for event in receiver.try_iter() {
+                if report_event(bar, event) {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+    }
+}
+
+/// Shows a single render event, returns true once the render has finished.
+fn report_event(bar: &ProgressBar, event: RenderEvent) -> bool {
+    match event {
+        RenderEvent::TileFinished { progress, .. } => bar.update(|ps| {
+            ps.set_len(progress.total as u64);
+            ps.set_pos(progress.finished as u64)
+        }),
+        RenderEvent::Error { message } => bar.println(message),
+        RenderEvent::RenderFinished { stats } => {
+            bar.println(stats.to_string());
+            return true;
+        }
+        _ => {}
+    }
+    false
+}
+
+fn load_scene(
+    bvh_cache: Option<&Path>,
+    split_mode: SplitMode,
+) -> anyhow::Result<Scene<TriangleBvh>> {
+    const PATH: &str = "data/teapot.obj";
+    let progress = BuildProgress::default();
+    let object = match bvh_cache {
+        Some(cache) => TriangleBvh::with_obj_cached(PATH, cache, split_mode, &progress)?,
+        None => TriangleBvh::with_obj_split_mode(PATH, split_mode, &progress)?,
+    };
+    let scene = Scene::new(object);
+    scene.object.print_statistics();
+    Ok(scene)
+}
@@ -75,6 +75,10 @@ pub struct HitRecord {
     pub point: WorldPoint,
     /// Normalized normal vector
     pub normal: Unit<WorldVector>,
+    /// Normalized normal of the geometry itself, without any interpolation
+    pub geometric_normal: Unit<WorldVector>,
     pub material: usize,
+    /// Index of the hit triangle within the object, 0 for objects that aren't made of triangles
+    pub triangle_index: usize,
     pub texture_coords: TexturePoint,
 }
@@ -1,35 +1,98 @@
 use std::{
+    num::NonZeroU32,
     ops::Deref,
-    sync::{Arc, Mutex},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        mpsc::{Receiver, TryRecvError},
+    },
+    time::{Duration, Instant},
 };
 
 use assert2::assert;
 use eframe::{App, CreationContext, Frame, egui};
-use egui::{CentralPanel, Color32, ColorImage, Image, TextureOptions};
-use image::{GenericImageView, Rgba};
+use egui::{
+    Button, CentralPanel, Color32, ColorImage, ComboBox, DragValue, PointerButton, ProgressBar,
+    Rect, Sense, SidePanel, Stroke, TextureOptions, TopBottomPanel, Vec2, pos2,
+};
+use image::{GenericImageView, Rgba, RgbaImage};
 use minipath::{
-    Camera, RenderProgress, RenderSettings, Scene,
+    Camera, Checkpoint, DenoiseSettings, DisplaySettings, Film, Material, RenderEvent,
+    RenderEvents, RenderProgress, RenderSettings, RenderStats, Scene, SensorSize, ShadingMode,
+    ToneMap, denoise,
     geometry::{ScreenBlock, ScreenPoint, ScreenSize, WorldPoint, WorldVector},
-    render,
-    scene::{Object, triangle_bvh::TriangleBvh},
+    render, resume,
+    scene::{
+        Object,
+        triangle_bvh::{BuildProgress, TriangleBvh},
+    },
 };
-use nalgebra::{Translation3, Vector2};
+use nalgebra::Vector2;
+
+/// How often the window is redrawn while a render is running.
+const REPAINT_INTERVAL: Duration = Duration::from_millis(33);
+/// Camera rotation per point of mouse drag, in radians.
+const ROTATION_SPEED: f32 = 0.005;
+/// Fly speed, in scene sizes per second.
+const FLY_SPEED: f32 = 0.25;
+/// Dolly distance per point of scrolling, relative to the distance to the pivot.
+const DOLLY_SPEED: f32 = 0.002;
 
 pub struct MinipathGui<O: Object> {
     render_progress: RenderProgress<O>,
+    render_events: Receiver<RenderEvent>,
+    /// Stats of the last render that finished
+    last_stats: Option<RenderStats>,
 
-    pending_tiles: Arc<Mutex<Vec<(ScreenBlock, bool)>>>,
     texture: egui::TextureHandle,
+    /// Show denoised images of finished renders
+    denoise: bool,
+    /// The texture shows a denoised image
+    texture_denoised: bool,
+    /// Denoised film of the finished render, so that it's only denoised once
+    denoised: Option<Film>,
+    /// Conversion of the rendered radiance for display
+    display: DisplaySettings,
+    /// Size of an image pixel in physical screen pixels, None fits the image to the window
+    zoom: Option<f32>,
+    /// Zoom that fits the image to the window, as of the last frame
+    fit_zoom: f32,
+    /// Offset of the image center from the window center, in points
+    pan: Vec2,
+    /// Pixel under the mouse cursor, as of the last frame
+    hovered_pixel: Option<ScreenPoint>,
+    /// Image that the render is compared against, if any
+    reference: Option<Reference>,
+    /// Where the reference image gets loaded from
+    reference_path: String,
+    /// Error of the last reference loading
+    reference_error: Option<String>,
 
     scene: Arc<Scene<O>>,
     camera: Camera,
+    navigation: Navigation,
+    /// Point that the camera orbits around
+    pivot: WorldPoint,
     full_render_settings: RenderSettings,
     preview_render_settings: RenderSettings,
 
     draw_state: DrawState,
+    /// State of the paused render, if any
+    paused: Option<Checkpoint>,
+    /// Where the save button saves the image
+    save_path: String,
+    /// Result of the last save
+    save_status: Option<String>,
+
+    /// Path of the scene to open, as typed in by the user
+    scene_path: String,
+    /// Scene being loaded in the background, if any
+    loading: Option<SceneLoading<O>>,
+    /// Error of the last scene loading
+    load_error: Option<String>,
 }
 
-impl<O: Object + Send + Sync + 'static> MinipathGui<O> {
+impl<O: LoadableObject> MinipathGui<O> {
     pub fn new(
         scene: Arc<Scene<O>>,
         camera: Camera,
@@ -39,13 +102,11 @@ impl<O: Object + Send + Sync + 'static> MinipathGui<O> {
     ) -> anyhow::Result<Self> {
         assert!(preview_render_settings.resolution == full_render_settings.resolution);
 
-        let pending_tiles = Arc::new(Mutex::new(Vec::new()));
-        let render_progress = Self::start_render(
-            Arc::clone(&pending_tiles),
+        let (render_progress, render_events) = Self::start_render(
             Arc::clone(&scene),
             camera.clone(),
             preview_render_settings.clone(),
-            cc.egui_ctx.clone(),
+            None,
         )?;
         let screen_block =
             ScreenBlock::with_size(ScreenPoint::origin(), &full_render_settings.resolution);
@@ -61,141 +122,784 @@ impl<O: Object + Send + Sync + 'static> MinipathGui<O> {
 
         Ok(MinipathGui {
             render_progress,
-            pending_tiles,
+            render_events,
+            last_stats: None,
             texture,
+            denoise: false,
+            texture_denoised: false,
+            denoised: None,
+            display: DisplaySettings::default(),
+            zoom: None,
+            fit_zoom: 1.0,
+            pan: Vec2::ZERO,
+            hovered_pixel: None,
+            reference: None,
+            reference_path: "minipath.png".to_owned(),
+            reference_error: None,
+            pivot: scene.object.get_bounding_box().center(),
             scene,
             camera,
+            navigation: Navigation::Orbit,
             full_render_settings,
             preview_render_settings,
             draw_state: DrawState::FullRender,
+            paused: None,
+            save_path: "minipath.png".to_owned(),
+            save_status: None,
+            scene_path: String::new(),
+            loading: None,
+            load_error: None,
         })
     }
 
+    /// Starts a new render, or continues from a checkpoint if there is one.
     fn start_render(
-        pending_tiles: Arc<Mutex<Vec<(ScreenBlock, bool)>>>,
         scene: Arc<Scene<O>>,
         camera: Camera,
         render_settings: RenderSettings,
-        ctx: egui::Context,
-    ) -> anyhow::Result<RenderProgress<O>> {
-        pending_tiles.lock().unwrap().clear();
-
-        let started_tile_callback = {
-            let pending_tiles = Arc::clone(&pending_tiles);
-            let ctx = ctx.clone();
-            move |tile| {
-                pending_tiles.lock().unwrap().push((tile, true));
-                ctx.request_repaint();
-            }
+        checkpoint: Option<Checkpoint>,
+    ) -> anyhow::Result<(RenderProgress<O>, Receiver<RenderEvent>)> {
+        let events = RenderEvents::new();
+        let receiver = events.subscribe();
+        let render_progress = match checkpoint {
+            Some(checkpoint) => resume(scene, camera.clone(), render_settings, checkpoint, events)?,
+            None => render(scene, camera.clone(), render_settings, events)?,
         };
-
-        let finished_tile_callback = move |tile, _| {
-            pending_tiles.lock().unwrap().push((tile, false));
-            ctx.request_repaint();
-        };
-
-        render(
-            scene,
-            camera.clone(),
-            render_settings,
-            started_tile_callback,
-            finished_tile_callback,
-        )
+        Ok((render_progress, receiver))
     }
 
     fn cancel_previous_render(&mut self) {
         self.reload_texture();
         self.render_progress.abort();
+        self.paused = None;
+    }
+
+    /// Image of the current render as displayed, denoised if enabled and the render is finished.
+    fn current_image(&mut self) -> RgbaImage {
+        if self.denoise && self.render_progress.is_finished() {
+            let render_progress = &self.render_progress;
+            self.denoised
+                .get_or_insert_with(|| {
+                    denoise(
+                        render_progress.checkpoint().film(),
+                        &DenoiseSettings::default(),
+                    )
+                })
+                .develop_with(&self.display)
+        } else if self.display == DisplaySettings::default() {
+            self.render_progress.image().lock().unwrap().clone()
+        } else {
+            // Settings may already be changed for the next render, the image has the actual size
+            let (width, height) = self.render_progress.image().lock().unwrap().dimensions();
+            let block =
+                ScreenBlock::with_size(ScreenPoint::origin(), &ScreenSize::new(width, height));
+            self.render_progress
+                .crop_film(&block)
+                .develop_with(&self.display)
+        }
     }
 
+    /// Image of a tile of the current render as displayed.
+    fn tile_image(&self, tile: &ScreenBlock, in_progress: bool) -> ColorImage {
+        if self.display == DisplaySettings::default() {
+            let img = self.render_progress.image().lock().unwrap();
+            let tile_img = img.view(tile.min.x, tile.min.y, tile.width(), tile.height());
+            egui_image(tile, tile_img.deref(), in_progress)
+        } else {
+            let tile_img = self
+                .render_progress
+                .crop_film(tile)
+                .develop_with(&self.display);
+            egui_image(tile, &tile_img, in_progress)
+        }
+    }
+
+    /// Shows the whole image of the current render, denoised if enabled and the render is finished.
     fn reload_texture(&mut self) {
         let screen_block =
             ScreenBlock::with_size(ScreenPoint::origin(), &self.full_render_settings.resolution);
-        self.texture.set(
-            egui_image(
-                &screen_block,
-                self.render_progress.image().lock().unwrap().deref(),
-                false,
-            ),
-            TextureOptions::LINEAR,
-        );
+        self.texture_denoised = self.denoise && self.render_progress.is_finished();
+        let image = egui_image(&screen_block, &self.current_image(), false);
+        self.texture.set(image, TextureOptions::LINEAR);
     }
 
-    fn start_preview_render(&mut self, ctx: egui::Context) {
+    fn start_preview_render(&mut self) {
         self.cancel_previous_render();
-        self.draw_state = DrawState::Preview;
-        self.render_progress = Self::start_render(
-            Arc::clone(&self.pending_tiles),
-            Arc::clone(&self.scene),
-            self.camera.clone(),
-            self.preview_render_settings.clone(),
-            ctx,
-        )
-        .unwrap();
+        self.start(DrawState::Preview, None);
     }
 
-    fn start_full_render(&mut self, ctx: egui::Context) {
+    fn start_full_render(&mut self) {
         self.cancel_previous_render();
-        self.draw_state = DrawState::FullRender;
-        self.render_progress = Self::start_render(
-            Arc::clone(&self.pending_tiles),
+        self.start(DrawState::FullRender, None);
+    }
+
+    fn start(&mut self, draw_state: DrawState, checkpoint: Option<Checkpoint>) {
+        self.draw_state = draw_state;
+        self.denoised = None;
+        let settings = match draw_state {
+            DrawState::Preview => self.preview_render_settings,
+            DrawState::FullRender => self.full_render_settings,
+        };
+        (self.render_progress, self.render_events) = Self::start_render(
             Arc::clone(&self.scene),
             self.camera.clone(),
-            self.full_render_settings.clone(),
-            ctx,
+            settings,
+            checkpoint,
         )
         .unwrap();
+
+        // Tiles of the new render can't be drawn into a texture of different size
+        let resolution = settings.resolution;
+        if self.texture.size() != [resolution.x as usize, resolution.y as usize] {
+            self.reload_texture();
+        }
     }
-}
 
-impl<O: Object + Send + Sync + 'static> App for MinipathGui<O> {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut Frame) {
-        {
-            let mut pending_tiles = self.pending_tiles.lock().unwrap();
-            if !pending_tiles.is_empty() {
-                let img = self.render_progress.image().lock().unwrap();
-
-                for (tile, in_progress) in pending_tiles.drain(..) {
-                    let tile_img = img.view(tile.min.x, tile.min.y, tile.width(), tile.height());
-                    let color_image = egui_image(&tile, tile_img.deref(), in_progress);
-
-                    self.texture.set_partial(
-                        [tile.min.x as usize, tile.min.y as usize],
-                        color_image,
-                        TextureOptions::LINEAR,
-                    );
-                }
+    /// Stops the workers, keeping the render state so that it can be resumed later.
+    fn pause(&mut self) {
+        self.render_progress.abort();
+        self.render_progress.wait();
+        self.paused = Some(self.render_progress.checkpoint());
+    }
+
+    fn unpause(&mut self) {
+        if let Some(checkpoint) = self.paused.take() {
+            self.start(self.draw_state, Some(checkpoint));
+        }
+    }
+
+    fn save_image(&mut self) {
+        self.save_status = Some(match self.current_image().save(&self.save_path) {
+            Ok(()) => format!("Saved {}", self.save_path),
+            Err(e) => format!("Saving failed: {e}"),
+        });
+    }
+
+    fn load_reference(&mut self, ctx: &egui::Context) {
+        self.reference_error = None;
+        match image::open(&self.reference_path) {
+            Ok(image) => self.set_reference(ctx, image.to_rgba8()),
+            Err(e) => {
+                self.reference_error = Some(format!("Loading {} failed: {e}", self.reference_path))
             }
         }
+    }
+
+    fn set_reference(&mut self, ctx: &egui::Context, image: RgbaImage) {
+        let resolution = self.full_render_settings.resolution;
+        if image.dimensions() != (resolution.x, resolution.y) {
+            self.reference_error = Some(format!(
+                "Reference is {}×{}, render is {}×{}",
+                image.width(),
+                image.height(),
+                resolution.x,
+                resolution.y
+            ));
+            return;
+        }
+
+        let screen_block = ScreenBlock::with_size(ScreenPoint::origin(), &resolution);
+        let texture = ctx.load_texture(
+            "reference",
+            egui_image(&screen_block, &image, false),
+            TextureOptions::LINEAR,
+        );
+        self.reference = Some(Reference {
+            image,
+            texture,
+            split: 0.5,
+        });
+    }
+
+    /// Starts loading a scene on a background thread.
+    /// The current scene keeps rendering until the new one is ready.
+    fn open_scene(&mut self, path: PathBuf) {
+        let progress = Arc::new(BuildProgress::default());
+        let (sender, result) = std::sync::mpsc::channel();
+        std::thread::spawn({
+            let path = path.clone();
+            let progress = Arc::clone(&progress);
+            move || {
+                // The receiver is gone if the loading was superseded by another one
+                let _ = sender.send(O::load(&path, &progress));
+            }
+        });
+
+        self.scene_path = path.display().to_string();
+        self.load_error = None;
+        self.loading = Some(SceneLoading {
+            path,
+            progress,
+            started: Instant::now(),
+            result,
+        });
+    }
+
+    /// Swaps in the loaded scene once the background loading is done.
+    fn poll_loading(&mut self) {
+        let Some(loading) = &self.loading else {
+            return;
+        };
+        let result = match loading.result.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => Err(anyhow::anyhow!("Loader thread panicked")),
+        };
+        let path = loading.path.display().to_string();
+        self.loading = None;
 
-        if self.render_progress.is_finished() && self.draw_state == DrawState::Preview {
-            self.start_full_render(ctx.clone());
+        match result {
+            Ok(object) => {
+                let bounding_box = object.get_bounding_box();
+                // Edited materials carry over to the new scene
+                self.scene = Arc::new(Scene {
+                    object: Arc::new(object),
+                    materials: self.scene.materials.clone(),
+                });
+                self.pivot = bounding_box.center();
+                self.camera = self
+                    .camera
+                    .frame(&bounding_box, self.full_render_settings.resolution);
+                self.start_preview_render();
+            }
+            Err(e) => self.load_error = Some(format!("Loading {path} failed: {e}")),
         }
+    }
+
+    /// Side panel with render settings, camera parameters, materials and view options.
+    /// Any change that affects the image restarts the render.
+    fn controls(&mut self, ui: &mut egui::Ui) {
+        let mut restart = false;
 
-        CentralPanel::default().show(ctx, |ui| {
-            ui.centered_and_justified(|ui| {
-                ui.add(Image::from_texture(&self.texture).shrink_to_fit())
+        ui.collapsing("Scene", |ui| {
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut self.scene_path)
+                    .on_hover_text("Path of an OBJ file, or drop one on the window");
+                let can_open = self.loading.is_none() && !self.scene_path.is_empty();
+                if ui.add_enabled(can_open, Button::new("Open")).clicked() {
+                    self.open_scene(PathBuf::from(&self.scene_path));
+                }
+                if ui
+                    .add_enabled(self.loading.is_none(), Button::new("Browse…"))
+                    .clicked()
+                    && let Some(path) = rfd::FileDialog::new()
+                        .add_filter("Wavefront OBJ", &["obj"])
+                        .pick_file()
+                {
+                    self.open_scene(path);
+                }
+            });
+            if let Some(loading) = &self.loading {
+                ui.add(
+                    ProgressBar::new(loading.progress.fraction())
+                        .text(format!("Building BVH, {:.1?}", loading.started.elapsed())),
+                );
+            }
+            if let Some(error) = &self.load_error {
+                ui.label(error);
+            }
+        });
+
+        ui.collapsing("Render settings", |ui| {
+            let mut full_samples = self.full_render_settings.sample_count.get();
+            let mut preview_samples = self.preview_render_settings.sample_count.get();
+            let mut tile_size = self.full_render_settings.tile_size.get();
+            let mut resolution = self.full_render_settings.resolution;
+
+            let changed = ui
+                .add(
+                    DragValue::new(&mut full_samples)
+                        .range(1..=65536)
+                        .prefix("Samples: "),
+                )
+                .changed()
+                | ui.add(
+                    DragValue::new(&mut preview_samples)
+                        .range(1..=65536)
+                        .prefix("Preview samples: "),
+                )
+                .changed()
+                | ui.add(
+                    DragValue::new(&mut tile_size)
+                        .range(1..=1024)
+                        .prefix("Tile size: "),
+                )
+                .changed()
+                | ui.horizontal(|ui| {
+                    ui.label("Resolution:");
+                    let width_changed = ui
+                        .add(DragValue::new(&mut resolution.x).range(16..=16384))
+                        .changed();
+                    ui.label("×");
+                    width_changed
+                        | ui.add(DragValue::new(&mut resolution.y).range(16..=16384))
+                            .changed()
+                })
+                .inner;
+
+            if changed {
+                let nonzero = |value: u32| NonZeroU32::new(value.max(1)).unwrap();
+                for settings in [
+                    &mut self.full_render_settings,
+                    &mut self.preview_render_settings,
+                ] {
+                    settings.tile_size = nonzero(tile_size);
+                    settings.resolution = resolution;
+                }
+                self.full_render_settings.sample_count = nonzero(full_samples);
+                self.preview_render_settings.sample_count = nonzero(preview_samples);
+                restart = true;
+            }
+        });
+
+        ui.collapsing("Camera", |ui| {
+            let mut f_number = self.camera.f_number;
+            let mut focus_distance = self.camera.focus_distance;
+            let mut focal_length = self.camera.focal_length * 1e3;
+            let mut sensor_mm = match self.camera.sensor_size {
+                SensorSize::Width(size) | SensorSize::Height(size) => size * 1e3,
+            };
+
+            let changed = ui
+                .add(
+                    DragValue::new(&mut f_number)
+                        .range(0.5..=64.0)
+                        .speed(0.1)
+                        .prefix("f/"),
+                )
+                .changed()
+                | ui.add(
+                    DragValue::new(&mut focus_distance)
+                        .range(0.01..=f32::INFINITY)
+                        .speed(0.05)
+                        .prefix("Focus: "),
+                )
+                .changed()
+                | ui.add(
+                    DragValue::new(&mut focal_length)
+                        .range(1.0..=2000.0)
+                        .prefix("Focal length: ")
+                        .suffix(" mm"),
+                )
+                .changed()
+                | ui.add(
+                    DragValue::new(&mut sensor_mm)
+                        .range(1.0..=100.0)
+                        .prefix("Sensor: ")
+                        .suffix(" mm"),
+                )
+                .changed();
+
+            if changed {
+                let camera = self
+                    .camera
+                    .f_number(f_number)
+                    .focus_distance(focus_distance)
+                    .focal_length(focal_length * 1e-3);
+                self.camera = match self.camera.sensor_size {
+                    SensorSize::Width(_) => camera.sensor_width(sensor_mm * 1e-3),
+                    SensorSize::Height(_) => camera.sensor_height(sensor_mm * 1e-3),
+                };
+                restart = true;
+            }
+        });
+
+        ui.collapsing("Materials", |ui| {
+            let mut materials = self.scene.materials.clone();
+            for (id, material) in materials.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    let mut albedo = [material.albedo.r, material.albedo.g, material.albedo.b];
+                    if ui.color_edit_button_rgb(&mut albedo).changed() {
+                        material.albedo = rgb::RGB::from(albedo);
+                    }
+                    ui.label(format!("Material {id}"));
+                });
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Add").clicked() {
+                    materials.push(Material::default());
+                }
+                if ui
+                    .add_enabled(!materials.is_empty(), Button::new("Remove last"))
+                    .clicked()
+                {
+                    materials.pop();
+                }
             })
+            .response
+            .on_hover_text("Material IDs without a material are white");
+
+            if materials != self.scene.materials {
+                self.scene = Arc::new(Scene {
+                    object: Arc::clone(&self.scene.object),
+                    materials,
+                });
+                restart = true;
+            }
         });
 
+        ui.collapsing("View", |ui| {
+            let mut shading_mode = self.full_render_settings.shading_mode;
+            ComboBox::from_label("Shading")
+                .selected_text(shading_mode.name())
+                .show_ui(ui, |ui| {
+                    for mode in ShadingMode::ALL {
+                        ui.selectable_value(&mut shading_mode, mode, mode.name());
+                    }
+                });
+
+            if shading_mode != self.full_render_settings.shading_mode {
+                self.full_render_settings.shading_mode = shading_mode;
+                self.preview_render_settings.shading_mode = shading_mode;
+                restart = true;
+            }
+
+            ComboBox::from_label("Navigation")
+                .selected_text(self.navigation.name())
+                .show_ui(ui, |ui| {
+                    for navigation in [Navigation::Orbit, Navigation::Fly] {
+                        ui.selectable_value(&mut self.navigation, navigation, navigation.name());
+                    }
+                });
+
+            if ui.checkbox(&mut self.denoise, "Denoise").changed() {
+                self.reload_texture();
+            }
+
+            let display = self.display;
+            ui.add(
+                DragValue::new(&mut self.display.exposure)
+                    .range(-16.0..=16.0)
+                    .speed(0.05)
+                    .prefix("Exposure: ")
+                    .suffix(" EV"),
+            );
+            ComboBox::from_label("Tone map")
+                .selected_text(self.display.tone_map.name())
+                .show_ui(ui, |ui| {
+                    for tone_map in ToneMap::ALL {
+                        ui.selectable_value(&mut self.display.tone_map, tone_map, tone_map.name());
+                    }
+                });
+            if self.display != display {
+                self.reload_texture();
+            }
+
+            ui.horizontal(|ui| {
+                ui.label("Zoom:");
+                if ui.selectable_label(self.zoom.is_none(), "Fit").clicked() {
+                    self.zoom = None;
+                    self.pan = Vec2::ZERO;
+                }
+                if ui.selectable_label(self.zoom == Some(1.0), "1:1").clicked() {
+                    self.zoom = Some(1.0);
+                }
+                let mut percent = self.zoom.unwrap_or(self.fit_zoom) * 100.0;
+                if ui
+                    .add(
+                        DragValue::new(&mut percent)
+                            .range(1.0..=6400.0)
+                            .max_decimals(0)
+                            .suffix(" %"),
+                    )
+                    .changed()
+                {
+                    self.zoom = Some(percent / 100.0);
+                }
+            });
+        });
+
+        ui.collapsing("Compare", |ui| {
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut self.reference_path);
+                if ui.button("Load").clicked() {
+                    self.load_reference(ui.ctx());
+                }
+            });
+            if ui.button("Use current image").clicked() {
+                self.reference_error = None;
+                let image = self.current_image();
+                self.set_reference(ui.ctx(), image);
+            }
+            if let Some(error) = &self.reference_error {
+                ui.label(error);
+            }
+            if let Some(reference) = &mut self.reference {
+                ui.add(
+                    egui::Slider::new(&mut reference.split, 0.0..=1.0).text("Render | reference"),
+                );
+                if ui.button("Clear").clicked() {
+                    self.reference = None;
+                }
+            }
+        });
+
+        ui.collapsing("Inspector", |ui| self.inspector(ui));
+
+        ui.separator();
+
+        let finished = self.render_progress.is_finished();
+        if self.paused.is_some() {
+            if ui.button("Resume").clicked() {
+                self.unpause();
+            }
+        } else if ui.add_enabled(!finished, Button::new("Pause")).clicked() {
+            self.pause();
+        }
+
+        ui.horizontal(|ui| {
+            ui.text_edit_singleline(&mut self.save_path);
+            if ui.button("Save image").clicked() {
+                self.save_image();
+            }
+        });
+        if let Some(status) = &self.save_status {
+            ui.label(status);
+        }
+
+        if restart {
+            self.start_preview_render();
+        }
+    }
+
+    /// Shows values of the pixel under the mouse cursor.
+    fn inspector(&self, ui: &mut egui::Ui) {
+        let Some(p) = self.hovered_pixel else {
+            ui.label("Hover over the image");
+            return;
+        };
+        let film = self
+            .render_progress
+            .crop_film(&ScreenBlock::with_size(p, &ScreenSize::new(1, 1)));
+        let color = film.pixel(&p);
+
+        ui.label(format!("Pixel: {}, {}", p.x, p.y));
+        ui.label(format!(
+            "Radiance: {:.4}, {:.4}, {:.4}",
+            color.r, color.g, color.b
+        ));
+        ui.label(format!("Alpha: {:.3}", color.a));
+        ui.label(format!("Samples: {}", film.sample_count(&p)));
+        if let Some(reference) = &self.reference
+            && let Some(pixel) = reference.image.get_pixel_checked(p.x, p.y)
+        {
+            let [r, g, b, _] = pixel.0.map(|v| v as f32 / 255.0);
+            ui.label(format!("Reference: {r:.4}, {g:.4}, {b:.4}"));
+        }
+    }
+
+    /// Draws the image with the current zoom and pan, and the reference next to it if there is one.
+    /// Returns the response of the whole area and the rectangle covered by the image.
+    fn image_view(&mut self, ui: &mut egui::Ui) -> (egui::Response, Rect) {
+        let (response, painter) = ui.allocate_painter(ui.available_size(), Sense::click_and_drag());
+
+        let image_size = self.texture.size_vec2();
+        let pixels_per_point = ui.ctx().pixels_per_point();
+        self.fit_zoom = (response.rect.size() / image_size).min_elem() * pixels_per_point;
+        let scale = self.zoom.unwrap_or(self.fit_zoom) / pixels_per_point;
+
+        if response.dragged_by(PointerButton::Middle)
+            || response.dragged_by(PointerButton::Secondary)
+        {
+            self.pan += response.drag_delta();
+        }
+        let image_rect =
+            Rect::from_center_size(response.rect.center() + self.pan, image_size * scale);
+
+        let uv = |from: f32, to: f32| Rect::from_min_max(pos2(from, 0.0), pos2(to, 1.0));
+        match &self.reference {
+            None => {
+                painter.image(self.texture.id(), image_rect, uv(0.0, 1.0), Color32::WHITE);
+            }
+            Some(reference) => {
+                let split_x = egui::lerp(image_rect.left()..=image_rect.right(), reference.split);
+                let (left, right) = image_rect.split_left_right_at_x(split_x);
+                painter.image(
+                    self.texture.id(),
+                    left,
+                    uv(0.0, reference.split),
+                    Color32::WHITE,
+                );
+                painter.image(
+                    reference.texture.id(),
+                    right,
+                    uv(reference.split, 1.0),
+                    Color32::WHITE,
+                );
+                painter.vline(
+                    split_x,
+                    image_rect.y_range(),
+                    Stroke::new(1.0, Color32::WHITE),
+                );
+            }
+        }
+
+        (response, image_rect)
+    }
+
+    /// Converts a screen position to the image pixel under it, if there is one.
+    fn pixel_at(&self, image_rect: &Rect, position: egui::Pos2) -> Option<ScreenPoint> {
+        if !image_rect.contains(position) {
+            return None;
+        }
+        let resolution = self.full_render_settings.resolution;
+        let relative = (position - image_rect.min) / image_rect.size();
+        Some(ScreenPoint::new(
+            ((relative.x * resolution.x as f32) as u32).min(resolution.x - 1),
+            ((relative.y * resolution.y as f32) as u32).min(resolution.y - 1),
+        ))
+    }
+
+    /// Returns the moved camera if the user did anything to move it.
+    /// Left mouse drag rotates the camera, keys move it, scroll wheel moves it forward and back
+    /// and ctrl + scroll wheel zooms.
+    fn navigate(&mut self, ctx: &egui::Context, image_response: &egui::Response) -> Option<Camera> {
+        let up = WorldVector::y_axis();
+        let (center, _, _, _) = self.camera.center_forward_up_right();
+        let mut camera = self.camera.clone();
+        let mut moved = false;
+
+        if image_response.dragged_by(PointerButton::Primary) {
+            let delta = image_response.drag_delta() * ROTATION_SPEED;
+            camera = match self.navigation {
+                Navigation::Orbit => camera.orbit(&self.pivot, &up, -delta.x, -delta.y),
+                Navigation::Fly => camera.look_around(&up, -delta.x, -delta.y),
+            };
+            moved = true;
+        }
+
+        let scene_size = self.scene.object.get_bounding_box().size().norm();
+        // Keys typed into text fields don't move the camera
+        let keyboard = !ctx.wants_keyboard_input();
         ctx.input(|i| {
-            let translation_speed = 2.0 * i.stable_dt;
+            let key = |a, b| (keyboard && (i.key_down(a) || i.key_down(b))) as i32 as f32;
+            let offset = WorldVector::new(
+                key(egui::Key::D, egui::Key::ArrowRight) - key(egui::Key::A, egui::Key::ArrowLeft),
+                key(egui::Key::E, egui::Key::PageUp) - key(egui::Key::Q, egui::Key::PageDown),
+                key(egui::Key::W, egui::Key::ArrowUp) - key(egui::Key::S, egui::Key::ArrowDown),
+            ) * (FLY_SPEED * scene_size * i.stable_dt);
+            if offset != WorldVector::zeros() {
+                let (before, _, _, _) = camera.center_forward_up_right();
+                camera = camera.moved(&offset);
+                let (after, _, _, _) = camera.center_forward_up_right();
+                // Keyboard movement pans the pivot together with the camera
+                if self.navigation == Navigation::Orbit {
+                    self.pivot += after - before;
+                }
+                moved = true;
+            }
 
-            let x_speed = (i.key_pressed(egui::Key::ArrowRight) as i32)
-                - (i.key_pressed(egui::Key::ArrowLeft) as i32);
-            let z_speed = (i.key_pressed(egui::Key::ArrowDown) as i32)
-                - (i.key_pressed(egui::Key::ArrowUp) as i32);
+            if image_response.hovered() {
+                let scroll = i.smooth_scroll_delta.y;
+                if scroll != 0.0 {
+                    let distance = (self.pivot - center).norm();
+                    camera =
+                        camera.moved(&WorldVector::new(0.0, 0.0, scroll * DOLLY_SPEED * distance));
+                    moved = true;
+                }
 
-            if x_speed != 0 || z_speed != 0 {
-                let x_speed = x_speed as f32 * translation_speed;
-                let z_speed = z_speed as f32 * translation_speed;
-                let translation = Translation3::new(x_speed, 0.0, z_speed);
+                let zoom = i.zoom_delta();
+                if zoom != 1.0 {
+                    camera = camera.focal_length(camera.focal_length * zoom);
+                    moved = true;
+                }
+            }
+        });
+
+        moved.then_some(camera)
+    }
+}
+
+impl<O: LoadableObject> App for MinipathGui<O> {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut Frame) {
+        self.poll_loading();
+        if self.loading.is_some() {
+            ctx.request_repaint_after(REPAINT_INTERVAL);
+        }
+        let dropped = ctx.input(|i| i.raw.dropped_files.iter().find_map(|f| f.path.clone()));
+        if let Some(path) = dropped {
+            self.open_scene(path);
+        }
+
+        // Checked before receiving events, so that events of the last tiles are not missed
+        let render_finished = self.render_progress.is_finished();
+        let mut changed_tiles = Vec::new();
+        for event in self.render_events.try_iter() {
+            match event {
+                RenderEvent::TileStarted { block } => changed_tiles.push((block, true)),
+                RenderEvent::TileFinished { block, .. } => changed_tiles.push((block, false)),
+                RenderEvent::RenderFinished { stats } => self.last_stats = Some(stats),
+                _ => {}
+            }
+        }
+        if !changed_tiles.is_empty() {
+            self.texture_denoised = false;
 
-                self.camera = self.camera.transformed(translation.into());
+            for (tile, in_progress) in changed_tiles {
+                let color_image = self.tile_image(&tile, in_progress);
+                self.texture.set_partial(
+                    [tile.min.x as usize, tile.min.y as usize],
+                    color_image,
+                    TextureOptions::LINEAR,
+                );
+            }
+        }
+
+        if render_finished {
+            if self.paused.is_some() {
+                // Waiting for the user to resume
+            } else if self.draw_state == DrawState::Preview {
+                self.start_full_render();
+            } else if self.denoise && !self.texture_denoised {
+                self.reload_texture();
+            }
+        } else {
+            ctx.request_repaint_after(REPAINT_INTERVAL);
+        }
+
+        SidePanel::left("controls").show(ctx, |ui| self.controls(ui));
 
-                self.start_preview_render(ctx.clone());
+        TopBottomPanel::bottom("stats").show(ctx, |ui| match &self.last_stats {
+            Some(stats) => {
+                ui.label(format!(
+                    "{} samples in {:.2?}, {:.2} Mrays/s, {:.1} inner nodes and {:.1} triangle packets per ray",
+                    stats.samples,
+                    stats.elapsed,
+                    stats.mrays_per_second(),
+                    stats.traversal.inner_nodes_per_ray(),
+                    stats.traversal.triangle_packets_per_ray(),
+                ))
+                .on_hover_text(stats.to_string());
+            }
+            None => {
+                ui.label("Rendering...");
             }
         });
+
+        let (image_response, image_rect) = CentralPanel::default()
+            .show(ctx, |ui| self.image_view(ui))
+            .inner;
+        let image_response = image_response.on_hover_text(self.navigation.help());
+
+        self.hovered_pixel = image_response
+            .hover_pos()
+            .and_then(|position| self.pixel_at(&image_rect, position));
+
+        if image_response.clicked()
+            && let Some(position) = image_response.interact_pointer_pos()
+            && let Some(point) = self.pixel_at(&image_rect, position)
+        {
+            let resolution = self.full_render_settings.resolution;
+            if let Some(camera) = self.camera.autofocus(&self.scene, resolution, &point) {
+                self.camera = camera;
+                self.start_preview_render();
+            }
+        }
+
+        if let Some(camera) = self.navigate(ctx, &image_response) {
+            self.camera = camera;
+            self.start_preview_render();
+        }
     }
 }
 
@@ -217,14 +921,16 @@ fn main() -> anyhow::Result<()> {
                 tile_size: 64.try_into().unwrap(),
                 sample_count: 2.try_into().unwrap(),
                 resolution: ScreenSize::new(2048, 1536),
+                shading_mode: ShadingMode::Shaded,
+                time_limit: None,
             };
             let preview_settings = RenderSettings {
                 sample_count: 1.try_into().unwrap(),
                 ..full_settings
             };
-            let scene = Arc::new(Scene {
-                object: TriangleBvh::with_obj("data/teapot.obj").unwrap(),
-            });
+            let scene = Arc::new(Scene::new(
+                TriangleBvh::with_obj("data/teapot.obj").unwrap(),
+            ));
             scene.object.print_statistics();
 
             Ok(Box::new(MinipathGui::new(
@@ -241,6 +947,34 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Object that the GUI knows how to load from a file.
+pub trait LoadableObject: Object + Send + Sync + Sized + 'static {
+    fn load(path: &Path, progress: &BuildProgress) -> anyhow::Result<Self>;
+}
+
+impl LoadableObject for TriangleBvh {
+    fn load(path: &Path, progress: &BuildProgress) -> anyhow::Result<Self> {
+        Ok(TriangleBvh::with_obj_progress(path, progress)?)
+    }
+}
+
+/// Image that the render is compared against.
+struct Reference {
+    image: RgbaImage,
+    texture: egui::TextureHandle,
+    /// Position of the boundary between the render on the left and the reference on the right,
+    /// relative to the image width
+    split: f32,
+}
+
+/// Scene being loaded on a background thread.
+struct SceneLoading<O> {
+    path: PathBuf,
+    progress: Arc<BuildProgress>,
+    started: Instant,
+    result: Receiver<anyhow::Result<O>>,
+}
+
 fn egui_image(
     tile: &ScreenBlock,
     img: &impl GenericImageView<Pixel = Rgba<u8>>,
@@ -251,7 +985,8 @@ fn egui_image(
     let mut pixels = Vec::with_capacity((width * height) as usize);
     pixels.extend(img.pixels().map(|(x, y, px)| {
         let bw = 4u32;
-        let border = (x < bw) | (y < bw) | (x >= (width - bw)) | (y > (height - bw));
+        // Written without subtraction, edge tiles can be narrower than the border
+        let border = (x < bw) | (y < bw) | (x + bw >= width) | (y + bw > height);
         if in_progress && border {
             Color32::from_rgba_unmultiplied(200, 100, 100, 255)
         } else {
@@ -281,6 +1016,35 @@ fn background_grid(p: ScreenPoint) -> Color32 {
     }
 }
 
+/// What dragging the mouse over the image does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Navigation {
+    /// Rotate around the pivot point.
+    Orbit,
+    /// Rotate around the camera itself.
+    Fly,
+}
+
+impl Navigation {
+    fn name(&self) -> &'static str {
+        match self {
+            Navigation::Orbit => "Orbit",
+            Navigation::Fly => "Fly",
+        }
+    }
+
+    fn help(&self) -> &'static str {
+        match self {
+            Navigation::Orbit => {
+                "Drag to orbit, WASD/QE to move, scroll to dolly, ctrl + scroll to zoom, click to focus, right drag to pan the view"
+            }
+            Navigation::Fly => {
+                "Drag to look around, WASD/QE to fly, scroll to dolly, ctrl + scroll to zoom, click to focus, right drag to pan the view"
+            }
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum DrawState {
     Preview,
@@ -5,6 +5,13 @@ pub mod scene;
 mod screen_block;
 mod util;
 
-pub use crate::renderer::{RenderProgress, RenderSettings, render};
-pub use camera::Camera;
-pub use scene::{Scene, primitives};
+pub use crate::renderer::{
+    Aov, Checkpoint, CheckpointError, DenoiseSettings, DisplaySettings, Film, RenderEvent,
+    RenderEvents, RenderProgress, RenderProgressSnapshot, RenderSettings, RenderStats, ShadingMode,
+    ToneMap, UnknownAov, UnknownShadingMode, denoise, distributed, render, resume,
+};
+pub use camera::{
+    Aperture, ApertureImage, ApertureShape, Camera, FisheyeMapping, Projection, SensorSize,
+};
+pub use scene::{Material, Scene, primitives};
+pub use util::Stats;
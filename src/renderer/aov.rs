@@ -0,0 +1,213 @@
+use std::{fmt::Display, str::FromStr};
+
+use thiserror::Error;
+
+use crate::{
+    geometry::{HitRecord, WorldVector},
+    scene::Material,
+};
+
+/// Auxiliary output buffers rendered next to the image, for compositing and denoising.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Aov {
+    /// Surface color of the first hit.
+    Albedo,
+    /// World space shading normal of the first hit.
+    Normal,
+    /// Distance of the first hit from the camera, along the viewing direction.
+    Depth,
+    /// World space position of the first hit.
+    Position,
+    MaterialId,
+    SampleCount,
+}
+
+#[derive(Debug, Error)]
+#[error("Unknown AOV {0}")]
+pub struct UnknownAov(String);
+
+impl Aov {
+    pub const ALL: [Aov; 6] = [
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Position,
+        Aov::MaterialId,
+        Aov::SampleCount,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::MaterialId => "material-id",
+            Aov::SampleCount => "sample-count",
+        }
+    }
+}
+
+impl Display for Aov {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Aov {
+    type Err = UnknownAov;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Aov::ALL
+            .into_iter()
+            .find(|aov| aov.name() == s)
+            .ok_or_else(|| UnknownAov(s.to_owned()))
+    }
+}
+
+/// Auxiliary values of a single sample that hit something.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AovSample {
+    pub albedo: rgb::RGB<f32>,
+    pub normal: WorldVector,
+    pub depth: f32,
+    pub position: WorldVector,
+    pub material_id: u32,
+}
+
+impl AovSample {
+    pub fn new(hit: &HitRecord, material: &Material, depth: f32) -> Self {
+        AovSample {
+            albedo: material.albedo,
+            normal: hit.normal.into_inner(),
+            depth,
+            position: hit.point.coords,
+            material_id: hit.material as u32,
+        }
+    }
+}
+
+/// Sums of auxiliary values of the samples in a pixel that hit something.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AovSums {
+    pub albedo: rgb::RGB<f32>,
+    pub normal: WorldVector,
+    pub depth: f32,
+    pub position: WorldVector,
+    /// Material of the first sample that hit something. IDs can't be averaged.
+    pub material_id: u32,
+    /// Number of samples that hit something.
+    pub hit_count: u32,
+}
+
+impl Default for AovSums {
+    fn default() -> Self {
+        AovSums {
+            albedo: rgb::RGB::new(0.0, 0.0, 0.0),
+            normal: WorldVector::zeros(),
+            depth: 0.0,
+            position: WorldVector::zeros(),
+            material_id: 0,
+            hit_count: 0,
+        }
+    }
+}
+
+impl AovSums {
+    pub fn add(&mut self, sample: &AovSample) {
+        if self.hit_count == 0 {
+            self.material_id = sample.material_id;
+        }
+        self.albedo += sample.albedo;
+        self.normal += sample.normal;
+        self.depth += sample.depth;
+        self.position += sample.position;
+        self.hit_count += 1;
+    }
+
+    pub fn merge(&mut self, other: &AovSums) {
+        if self.hit_count == 0 {
+            self.material_id = other.material_id;
+        }
+        self.albedo += other.albedo;
+        self.normal += other.normal;
+        self.depth += other.depth;
+        self.position += other.position;
+        self.hit_count += other.hit_count;
+    }
+
+    /// Returns the averaged value of an AOV as three channels, scalar AOVs are repeated.
+    /// Pixels where no sample hit anything have zero everywhere, except for infinite depth
+    /// and material ID of -1.
+    pub fn value(&self, aov: Aov, sample_count: u32) -> [f32; 3] {
+        if aov == Aov::SampleCount {
+            return [sample_count as f32; 3];
+        }
+
+        if self.hit_count == 0 {
+            return match aov {
+                Aov::Depth => [f32::INFINITY; 3],
+                Aov::MaterialId => [-1.0; 3],
+                _ => [0.0; 3],
+            };
+        }
+
+        let n = self.hit_count as f32;
+        match aov {
+            Aov::Albedo => (self.albedo / n).into(),
+            Aov::Normal => (self.normal / n).into(),
+            Aov::Depth => [self.depth / n; 3],
+            Aov::Position => (self.position / n).into(),
+            Aov::MaterialId => [self.material_id as f32; 3],
+            Aov::SampleCount => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    fn sample(depth: f32, material_id: u32) -> AovSample {
+        AovSample {
+            albedo: rgb::RGB::new(1.0, 0.5, 0.0),
+            normal: WorldVector::new(0.0, 1.0, 0.0),
+            depth,
+            position: WorldVector::new(1.0, 2.0, depth),
+            material_id,
+        }
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for aov in Aov::ALL {
+            assert!(aov.name().parse::<Aov>().unwrap() == aov);
+        }
+        assert!("nonsense".parse::<Aov>().is_err());
+    }
+
+    #[test]
+    fn values_are_averaged() {
+        let mut a = AovSums::default();
+        a.add(&sample(1.0, 3));
+        let mut b = AovSums::default();
+        b.add(&sample(3.0, 5));
+        a.merge(&b);
+
+        assert!(a.hit_count == 2);
+        assert!(a.value(Aov::Albedo, 4) == [1.0, 0.5, 0.0]);
+        assert!(a.value(Aov::Depth, 4) == [2.0; 3]);
+        assert!(a.value(Aov::Position, 4) == [1.0, 2.0, 2.0]);
+        assert!(a.value(Aov::MaterialId, 4) == [3.0; 3]);
+        assert!(a.value(Aov::SampleCount, 4) == [4.0; 3]);
+    }
+
+    #[test]
+    fn miss_values() {
+        let sums = AovSums::default();
+        assert!(sums.value(Aov::Normal, 1) == [0.0; 3]);
+        assert!(sums.value(Aov::Depth, 1) == [f32::INFINITY; 3]);
+        assert!(sums.value(Aov::MaterialId, 1) == [-1.0; 3]);
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+};
+
+use thiserror::Error;
+
+use crate::{
+    geometry::{ScreenBlock, ScreenPoint},
+    renderer::{
+        RenderSettings,
+        encoding::{
+            invalid_data, put_block, put_film, put_settings, put_u32, read_block, read_film,
+            read_settings, read_u32,
+        },
+        film::Film,
+    },
+};
+
+const MAGIC: [u8; 4] = *b"MPCK";
+const VERSION: u32 = 3;
+
+#[derive(Debug, Error)]
+pub enum CheckpointError {
+    #[error("Failed to access checkpoint: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Not a checkpoint file")]
+    NotACheckpoint,
+
+    #[error("Unsupported checkpoint version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("Checkpoint doesn't match render settings: {0}")]
+    IncompatibleSettings(&'static str),
+}
+
+/// Snapshot of the state of a render, can be saved to a file and resumed later.
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    settings: RenderSettings,
+    film: Film,
+    finished_tiles: Vec<ScreenBlock>,
+}
+
+impl Checkpoint {
+    /// Creates a checkpoint of a render that hasn't started yet.
+    pub fn new(settings: RenderSettings) -> Self {
+        Checkpoint {
+            settings,
+            film: Film::new(ScreenBlock::with_size(
+                ScreenPoint::origin(),
+                &settings.resolution,
+            )),
+            finished_tiles: Vec::new(),
+        }
+    }
+
+    pub fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    /// Accumulated samples of the whole image.
+    pub fn film(&self) -> &Film {
+        &self.film
+    }
+
+    /// Tiles that had all their samples rendered.
+    pub fn finished_tiles(&self) -> &[ScreenBlock] {
+        &self.finished_tiles
+    }
+
+    /// Replaces samples of a rendered tile in the checkpoint.
+    /// If `finished` is true, the tile is also marked as finished.
+    pub fn update_tile(&mut self, tile_film: &Film, finished: bool) {
+        self.film.paste(tile_film);
+        if finished {
+            self.finished_tiles.push(tile_film.block().clone());
+        }
+    }
+
+    /// Returns true if the tile doesn't need any more samples.
+    pub fn is_tile_finished(&self, tile: &ScreenBlock) -> bool {
+        self.finished_tiles
+            .iter()
+            .any(|finished| finished.min == tile.min && finished.max == tile.max)
+    }
+
+    /// Checks that the render can be continued from this checkpoint with the given settings,
+    /// returns the checkpoint updated to the new settings.
+    /// Sample count may differ, all tiles are then rendered up to the new sample count.
+    pub fn resume_with(mut self, settings: RenderSettings) -> Result<Self, CheckpointError> {
+        if settings.resolution != self.settings.resolution {
+            return Err(CheckpointError::IncompatibleSettings("Resolution differs"));
+        }
+        if settings.tile_size != self.settings.tile_size {
+            return Err(CheckpointError::IncompatibleSettings("Tile size differs"));
+        }
+        if settings.shading_mode != self.settings.shading_mode {
+            return Err(CheckpointError::IncompatibleSettings(
+                "Shading mode differs",
+            ));
+        }
+
+        if settings.sample_count > self.settings.sample_count {
+            self.finished_tiles.clear();
+        }
+        self.settings = settings;
+
+        Ok(self)
+    }
+
+    /// Saves the checkpoint to a file.
+    /// The file is replaced atomically, so that a crash while saving keeps the previous checkpoint.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), CheckpointError> {
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        fs::write(&tmp_path, self.encode())?;
+        fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CheckpointError> {
+        let data = fs::read(path)?;
+        Self::decode(&mut data.as_slice())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&MAGIC);
+        put_u32(&mut buffer, VERSION);
+        put_settings(&mut buffer, &self.settings);
+        put_u32(&mut buffer, self.finished_tiles.len() as u32);
+        for tile in &self.finished_tiles {
+            put_block(&mut buffer, tile);
+        }
+        put_film(&mut buffer, &self.film);
+        buffer
+    }
+
+    fn decode(r: &mut impl Read) -> Result<Self, CheckpointError> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(CheckpointError::NotACheckpoint);
+        }
+
+        let version = read_u32(r)?;
+        if version != VERSION {
+            return Err(CheckpointError::UnsupportedVersion(version));
+        }
+
+        let settings = read_settings(r)?;
+        let finished_tile_count = read_u32(r)?;
+        let finished_tiles = (0..finished_tile_count)
+            .map(|_| read_block(r))
+            .collect::<Result<_, _>>()?;
+        let film = read_film(r)?;
+
+        let expected_block = ScreenBlock::with_size(ScreenPoint::origin(), &settings.resolution);
+        if film.block().min != expected_block.min || film.block().max != expected_block.max {
+            return Err(invalid_data("Film doesn't cover the whole image").into());
+        }
+
+        Ok(Checkpoint {
+            settings,
+            film,
+            finished_tiles,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{geometry::ScreenSize, renderer::ShadingMode, util::Rgba};
+    use assert2::{assert, let_assert};
+
+    fn test_settings() -> RenderSettings {
+        RenderSettings {
+            tile_size: 4.try_into().unwrap(),
+            sample_count: 3.try_into().unwrap(),
+            resolution: ScreenSize::new(10, 8),
+            shading_mode: ShadingMode::Shaded,
+            time_limit: None,
+        }
+    }
+
+    fn test_checkpoint() -> Checkpoint {
+        let mut checkpoint = Checkpoint::new(test_settings());
+        let mut tile = Film::new(ScreenBlock::new([4, 0].into(), [8, 4].into()));
+        for p in tile.block().internal_points() {
+            for _ in 0..3 {
+                tile.add_sample(&p, Rgba::new(0.5, 0.25, 1.0, 1.0));
+            }
+        }
+        checkpoint.update_tile(&tile, true);
+        checkpoint
+    }
+
+    #[test]
+    fn round_trip() {
+        let checkpoint = test_checkpoint();
+        let decoded = Checkpoint::decode(&mut checkpoint.encode().as_slice()).unwrap();
+
+        assert!(decoded.settings().sample_count == checkpoint.settings().sample_count);
+        assert!(decoded.settings().resolution == checkpoint.settings().resolution);
+        assert!(decoded.finished_tiles().len() == 1);
+        assert!(decoded.film().sums() == checkpoint.film().sums());
+        assert!(decoded.film().sample_counts() == checkpoint.film().sample_counts());
+    }
+
+    #[test]
+    fn not_a_checkpoint() {
+        let data = b"PNG whatever";
+        let_assert!(Err(CheckpointError::NotACheckpoint) = Checkpoint::decode(&mut &data[..]));
+    }
+
+    #[test]
+    fn finished_tiles() {
+        let checkpoint = test_checkpoint();
+
+        assert!(checkpoint.is_tile_finished(&ScreenBlock::new([4, 0].into(), [8, 4].into())));
+        assert!(!checkpoint.is_tile_finished(&ScreenBlock::new([0, 0].into(), [4, 4].into())));
+    }
+
+    #[test]
+    fn resume_with_more_samples() {
+        let settings = RenderSettings {
+            sample_count: 10.try_into().unwrap(),
+            ..test_settings()
+        };
+        let checkpoint = test_checkpoint().resume_with(settings).unwrap();
+
+        assert!(checkpoint.finished_tiles().is_empty());
+        assert!(checkpoint.film().sample_count(&ScreenPoint::new(5, 1)) == 3);
+    }
+
+    #[test]
+    fn resume_with_different_shading_mode() {
+        let settings = RenderSettings {
+            shading_mode: ShadingMode::InnerNodeHeatmap,
+            ..test_settings()
+        };
+        let_assert!(
+            Err(CheckpointError::IncompatibleSettings(_)) = test_checkpoint().resume_with(settings)
+        );
+    }
+
+    #[test]
+    fn resume_with_different_resolution() {
+        let settings = RenderSettings {
+            resolution: ScreenSize::new(20, 20),
+            ..test_settings()
+        };
+        let_assert!(
+            Err(CheckpointError::IncompatibleSettings(_)) = test_checkpoint().resume_with(settings)
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use crate::{
+    geometry::WorldVector,
+    renderer::{aov::AovSums, film::Film},
+    util::Rgba,
+};
+
+/// Weights of the 5 tap B3 spline kernel used by the à-trous filter.
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Parameters of the edge avoiding à-trous wavelet denoiser.
+/// Sigmas control how much a difference in each of the guides stops the blurring,
+/// smaller values preserve more edges.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DenoiseSettings {
+    /// Number of filter passes, the filter radius doubles with every pass.
+    pub iterations: u32,
+    /// Halved with every pass, so that the later wide passes only smooth out the remaining noise.
+    pub color_sigma: f32,
+    pub normal_sigma: f32,
+    /// Relative to the depth of the center pixel.
+    pub depth_sigma: f32,
+    pub albedo_sigma: f32,
+}
+
+impl Default for DenoiseSettings {
+    fn default() -> Self {
+        DenoiseSettings {
+            iterations: 5,
+            color_sigma: 0.6,
+            normal_sigma: 0.3,
+            depth_sigma: 0.05,
+            albedo_sigma: 0.1,
+        }
+    }
+}
+
+/// First hit properties of a pixel that steer the filter.
+struct Guide {
+    /// At least one sample of the pixel hit something.
+    hit: bool,
+    normal: WorldVector,
+    depth: f32,
+    albedo: WorldVector,
+}
+
+impl Guide {
+    fn new(aov: &AovSums) -> Self {
+        let n = aov.hit_count.max(1) as f32;
+        Guide {
+            hit: aov.hit_count > 0,
+            normal: aov.normal / n,
+            depth: aov.depth / n,
+            albedo: WorldVector::new(aov.albedo.r, aov.albedo.g, aov.albedo.b) / n,
+        }
+    }
+}
+
+/// Removes noise from the film using the edge avoiding à-trous wavelet filter
+/// (Dammertz et al., 2010), guided by the normal, depth and albedo AOVs.
+/// Returns a film with the same sample counts and AOVs, but with filtered colors.
+/// Pixels without samples are left empty and don't contribute to their neighbors.
+pub fn denoise(film: &Film, settings: &DenoiseSettings) -> Film {
+    let width = film.block().width() as usize;
+    let height = film.block().height() as usize;
+    let counts = film.sample_counts();
+    let guides: Vec<_> = film.aovs().iter().map(Guide::new).collect();
+
+    let mut color: Vec<Rgba> = film
+        .sums()
+        .iter()
+        .zip(counts)
+        .map(|(&sum, &count)| match count {
+            0 => Rgba::default(),
+            count => sum / count as f32,
+        })
+        .collect();
+    let mut filtered = color.clone();
+
+    for iteration in 0..settings.iterations {
+        let step = 1usize << iteration;
+        let color_sigma = settings.color_sigma * 0.5f32.powi(iteration as i32);
+
+        for y in 0..height {
+            for x in 0..width {
+                let index = x + y * width;
+                if counts[index] == 0 {
+                    continue;
+                }
+
+                let mut sum = Rgba::default();
+                let mut weight_sum = 0.0;
+                for (ky, &y_weight) in KERNEL.iter().enumerate() {
+                    let Some(qy) = (y + ky * step).checked_sub(2 * step) else {
+                        continue;
+                    };
+                    if qy >= height {
+                        continue;
+                    }
+                    for (kx, &x_weight) in KERNEL.iter().enumerate() {
+                        let Some(qx) = (x + kx * step).checked_sub(2 * step) else {
+                            continue;
+                        };
+                        if qx >= width {
+                            continue;
+                        }
+                        let q = qx + qy * width;
+                        if counts[q] == 0 {
+                            continue;
+                        }
+
+                        let weight = x_weight
+                            * y_weight
+                            * edge_weight(
+                                settings,
+                                color_sigma,
+                                (&color[index], &guides[index]),
+                                (&color[q], &guides[q]),
+                            );
+                        sum += color[q] * weight;
+                        weight_sum += weight;
+                    }
+                }
+
+                // The center pixel always has a positive weight
+                filtered[index] = sum / weight_sum;
+            }
+        }
+
+        std::mem::swap(&mut color, &mut filtered);
+    }
+
+    let sums = color
+        .iter()
+        .zip(counts)
+        .map(|(&c, &count)| c * count as f32)
+        .collect();
+    Film::from_raw(
+        film.block().clone(),
+        sums,
+        counts.to_vec(),
+        film.aovs().to_vec(),
+    )
+    .unwrap_or_else(|| unreachable!("Buffer sizes always match the block"))
+}
+
+/// How much a neighboring pixel q is allowed to contribute to pixel p.
+/// Pixels where only one of them hits the scene never mix.
+fn edge_weight(
+    settings: &DenoiseSettings,
+    color_sigma: f32,
+    (p_color, p): (&Rgba, &Guide),
+    (q_color, q): (&Rgba, &Guide),
+) -> f32 {
+    let color_distance = (p_color.r - q_color.r).powi(2)
+        + (p_color.g - q_color.g).powi(2)
+        + (p_color.b - q_color.b).powi(2);
+    let mut exponent = color_distance / color_sigma.powi(2);
+
+    match (p.hit, q.hit) {
+        (true, true) => {
+            let depth_distance = (p.depth - q.depth) / p.depth.abs().max(f32::EPSILON);
+            exponent += (p.normal - q.normal).norm_squared() / settings.normal_sigma.powi(2)
+                + depth_distance.powi(2) / settings.depth_sigma.powi(2)
+                + (p.albedo - q.albedo).norm_squared() / settings.albedo_sigma.powi(2);
+        }
+        (false, false) => {}
+        _ => return 0.0,
+    }
+
+    (-exponent).exp()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        geometry::{ScreenBlock, ScreenPoint},
+        renderer::aov::AovSample,
+    };
+    use assert2::assert;
+    use rand::{Rng, SeedableRng, rngs::SmallRng};
+
+    const SIZE: u32 = 32;
+
+    /// Reference image of two walls meeting at the middle of the image,
+    /// the left one dark, the right one bright.
+    fn reference(p: &ScreenPoint) -> (Rgba, AovSample) {
+        let left = p.x < SIZE / 2;
+        let (value, normal) = if left {
+            (0.2, WorldVector::new(1.0, 0.0, 0.0))
+        } else {
+            (0.8, WorldVector::new(0.0, 0.0, 1.0))
+        };
+        let aov = AovSample {
+            albedo: rgb::RGB::new(1.0, 1.0, 1.0),
+            normal,
+            depth: 5.0,
+            position: WorldVector::new(p.x as f32, p.y as f32, 5.0),
+            material_id: left as u32,
+        };
+        (Rgba::new(value, value, value, 1.0), aov)
+    }
+
+    /// Film of the reference image with noise added to every sample.
+    fn noisy_film(samples: u32) -> Film {
+        let mut rng = SmallRng::seed_from_u64(1234);
+        let mut film = Film::new(ScreenBlock::new([0, 0].into(), [SIZE, SIZE].into()));
+        for p in film.block().clone().internal_points() {
+            let (color, aov) = reference(&p);
+            for _ in 0..samples {
+                let noise = rng.random_range(-0.2..0.2);
+                film.add_sample(&p, color + Rgba::new(noise, noise, noise, 0.0));
+                film.add_aov_sample(&p, &aov);
+            }
+        }
+        film
+    }
+
+    fn mean_squared_error(film: &Film) -> f32 {
+        let points: Vec<_> = film.block().internal_points().collect();
+        points
+            .iter()
+            .map(|p| {
+                let difference = film.pixel(p) - reference(p).0;
+                difference.r.powi(2) + difference.g.powi(2) + difference.b.powi(2)
+            })
+            .sum::<f32>()
+            / points.len() as f32
+    }
+
+    #[test]
+    fn reduces_noise() {
+        let film = noisy_film(1);
+        let denoised = denoise(&film, &DenoiseSettings::default());
+
+        assert!(mean_squared_error(&denoised) < mean_squared_error(&film) / 10.0);
+        assert!(denoised.sample_counts() == film.sample_counts());
+        assert!(denoised.aovs() == film.aovs());
+    }
+
+    #[test]
+    fn preserves_edges() {
+        let denoised = denoise(&noisy_film(1), &DenoiseSettings::default());
+
+        for y in 0..SIZE {
+            let left = denoised.pixel(&ScreenPoint::new(SIZE / 2 - 1, y));
+            let right = denoised.pixel(&ScreenPoint::new(SIZE / 2, y));
+            assert!((left.r - 0.2).abs() < 0.1);
+            assert!((right.r - 0.8).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn empty_pixels_stay_empty() {
+        let mut film = noisy_film(1);
+        let mut empty = Film::new(ScreenBlock::new([4, 4].into(), [8, 8].into()));
+        film.paste(&empty);
+        let denoised = denoise(&film, &DenoiseSettings::default());
+
+        denoised.crop_into(&empty.block().clone(), &mut empty);
+        assert!(empty.sample_counts().iter().all(|&count| count == 0));
+        assert!(empty.sums().iter().all(|&sum| sum == Rgba::default()));
+    }
+}
@@ -0,0 +1,730 @@
+//! Rendering distributed over multiple processes.
+//!
+//! Coordinator listens on a TCP or Unix socket and hands out tiles to connected workers,
+//! which render them and send back floating point films of the tiles.
+//! Tiles of workers that disconnect before finishing are returned to the queue and picked up
+//! by another worker.
+//!
+//! Only the render settings are transferred, workers must be set up with the same scene
+//! and camera as the coordinator.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    str::FromStr,
+    sync::{Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+};
+
+use thiserror::Error;
+
+use crate::{
+    camera::Camera,
+    geometry::{ScreenBlock, ScreenPoint},
+    renderer::{
+        RenderSettings,
+        encoding::{
+            put_block, put_film, put_render_stats, put_settings, put_u32, read_block, read_film,
+            read_render_stats, read_settings, read_u8, read_u32,
+        },
+        events::{RenderEvent, RenderEvents},
+        film::Film,
+        machinery::RenderProgressSnapshot,
+        stats::RenderStats,
+        worker::{StopCondition, Worker},
+    },
+    scene::{Object, Scene},
+};
+
+const PROTOCOL_VERSION: u32 = 5;
+
+/// Upper limit on size of a single message, to avoid allocating nonsense when reading garbage.
+const MAX_MESSAGE_LENGTH: usize = 1 << 30;
+
+/// How long the coordinator sleeps between checks for new connections.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("Connection error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Unsupported address: {0}")]
+    UnsupportedAddress(String),
+
+    #[error("Protocol version mismatch (expected {expected}, got {actual})")]
+    VersionMismatch { expected: u32, actual: u32 },
+
+    #[error("Unknown message tag {0}")]
+    UnknownMessage(u8),
+
+    #[error("Invalid message: {0}")]
+    InvalidMessage(&'static str),
+
+    #[error("Unexpected message")]
+    UnexpectedMessage,
+}
+
+/// Address of the coordinator.
+/// Parsed from `unix:<path>` for a Unix socket, or `<host>:<port>` for TCP.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = ProtocolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => Ok(Address::Unix(PathBuf::from(path))),
+            #[cfg(not(unix))]
+            Some(_) => Err(ProtocolError::UnsupportedAddress(s.to_owned())),
+            None => Ok(Address::Tcp(s.to_owned())),
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Tcp(address) => write!(f, "{address}"),
+            #[cfg(unix)]
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Address {
+    pub fn bind(&self) -> io::Result<Listener> {
+        match self {
+            Address::Tcp(address) => TcpListener::bind(address).map(Listener::Tcp),
+            #[cfg(unix)]
+            Address::Unix(path) => UnixListener::bind(path).map(Listener::Unix),
+        }
+    }
+
+    pub fn connect(&self) -> io::Result<Connection> {
+        match self {
+            Address::Tcp(address) => TcpStream::connect(address).map(Connection::Tcp),
+            #[cfg(unix)]
+            Address::Unix(path) => UnixStream::connect(path).map(Connection::Unix),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Returns the address the listener is bound to.
+    /// Useful to find out which port was assigned when binding TCP port 0.
+    pub fn local_address(&self) -> io::Result<Address> {
+        match self {
+            Listener::Tcp(listener) => Ok(Address::Tcp(listener.local_addr()?.to_string())),
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let address = listener.local_addr()?;
+                let path = address
+                    .as_pathname()
+                    .ok_or_else(|| io::Error::other("Unnamed unix socket"))?;
+                Ok(Address::Unix(path.to_owned()))
+            }
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(s, _)| Connection::Tcp(s)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().map(|(s, _)| Connection::Unix(s)),
+        }
+    }
+}
+
+/// Connection between a coordinator and a single worker thread.
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Connection {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Renders an image by handing out tiles to workers connecting to the listener.
+/// Blocks until all tiles are finished and returns the accumulated film.
+/// Failures of individual workers are reported as error events.
+///
+/// If the settings have a time limit, no new tiles are handed out once it expires,
+/// tiles that are already being rendered are still waited for.
+/// Pixels of tiles that were never rendered stay empty.
+pub fn serve(
+    listener: &Listener,
+    settings: RenderSettings,
+    events: &RenderEvents,
+) -> Result<Film, ProtocolError> {
+    let coordinator = Coordinator::new(settings, events);
+
+    listener.set_nonblocking(true)?;
+    thread::scope(|scope| {
+        loop {
+            // Workers that connect while the last tiles are being finished still get served,
+            // they just receive no work.
+            let finished = coordinator.is_finished();
+            match listener.accept() {
+                Ok(connection) => {
+                    connection.set_nonblocking(false)?;
+                    let coordinator = &coordinator;
+                    scope.spawn(move || {
+                        if let Err(e) = coordinator.handle_worker(connection) {
+                            events.send(RenderEvent::Error {
+                                message: format!("Worker failed: {e}"),
+                            });
+                        }
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if finished {
+                        break;
+                    }
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                }
+                Err(e) => return Err(ProtocolError::from(e)),
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(coordinator.finish())
+}
+
+/// Connects `thread_count` worker threads to a coordinator and renders tiles
+/// until the coordinator runs out of work.
+/// Returns total number of rendered tiles.
+pub fn work<O: Object + Send + Sync>(
+    address: &Address,
+    scene: &Scene<O>,
+    camera: &Camera,
+    thread_count: usize,
+) -> Result<usize, ProtocolError> {
+    thread::scope(|scope| {
+        let threads: Vec<_> = (0..thread_count)
+            .map(|worker_id| {
+                thread::Builder::new()
+                    .name(format!("worker{worker_id}"))
+                    .spawn_scoped(scope, move || -> Result<usize, ProtocolError> {
+                        let mut connection = address.connect()?;
+                        work_connection(worker_id, &mut connection, scene, camera)
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+
+        threads.into_iter().try_fold(0, |total, thread| {
+            let tiles = thread.join().expect("Worker thread panicked!")?;
+            Ok(total + tiles)
+        })
+    })
+}
+
+/// Renders tiles received over a single connection, returns number of rendered tiles.
+fn work_connection<O: Object + Send + Sync>(
+    worker_id: usize,
+    connection: &mut (impl Read + Write),
+    scene: &Scene<O>,
+    camera: &Camera,
+) -> Result<usize, ProtocolError> {
+    let Message::Settings(settings) = Message::read_from(connection)? else {
+        return Err(ProtocolError::UnexpectedMessage);
+    };
+
+    let mut worker = Worker::<O>::new(worker_id, camera.build_sampler(settings.resolution));
+    let mut film = Film::new(ScreenBlock::new(
+        ScreenPoint::origin(),
+        ScreenPoint::origin(),
+    ));
+    let mut tile_count = 0;
+
+    loop {
+        match Message::read_from(connection)? {
+            Message::Tile(tile) => {
+                film.reset(tile);
+                worker.render_tile(scene, &settings, &mut film, &StopCondition::default());
+                Message::TileFinished(film.clone(), worker.take_stats()).write_to(connection)?;
+                tile_count += 1;
+            }
+            Message::Finished => return Ok(tile_count),
+            _ => return Err(ProtocolError::UnexpectedMessage),
+        }
+    }
+}
+
+struct Coordinator<'a> {
+    settings: RenderSettings,
+    events: &'a RenderEvents,
+    start_time: Instant,
+    deadline: Option<Instant>,
+    tile_count: usize,
+    queue: Mutex<TileQueue>,
+    queue_changed: Condvar,
+    film: Mutex<Film>,
+    /// Merged stats reported by workers with their tiles.
+    stats: Mutex<RenderStats>,
+}
+
+struct TileQueue {
+    pending: VecDeque<ScreenBlock>,
+    in_flight: usize,
+    finished: usize,
+    total: usize,
+}
+
+impl<'a> Coordinator<'a> {
+    fn new(settings: RenderSettings, events: &'a RenderEvents) -> Self {
+        let screen_block = ScreenBlock::with_size(ScreenPoint::origin(), &settings.resolution);
+        let pending: VecDeque<_> = screen_block.tile_ordering(settings.tile_size).into();
+        let total = pending.len();
+
+        let start_time = Instant::now();
+
+        Coordinator {
+            settings,
+            events,
+            start_time,
+            deadline: settings.time_limit.map(|limit| start_time + limit),
+            tile_count: total,
+            queue: Mutex::new(TileQueue {
+                pending,
+                in_flight: 0,
+                finished: 0,
+                total,
+            }),
+            queue_changed: Condvar::new(),
+            film: Mutex::new(Film::new(screen_block)),
+            stats: Mutex::new(RenderStats::default()),
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        let mut queue = self.queue.lock().expect("Poisoned lock!");
+        self.drop_pending_after_deadline(&mut queue);
+        queue.finished == queue.total
+    }
+
+    /// Removes all pending tiles if the time limit has expired.
+    fn drop_pending_after_deadline(&self, queue: &mut TileQueue) {
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            queue.total -= queue.pending.len();
+            queue.pending.clear();
+        }
+    }
+
+    /// Takes a new tile to render.
+    /// Blocks while there are no pending tiles, but some are still being rendered,
+    /// because they might have to be handed out again.
+    /// Returns None once all tiles are finished or the time limit expires.
+    fn take_tile(&self) -> Option<ScreenBlock> {
+        let mut queue = self.queue.lock().expect("Poisoned lock!");
+        loop {
+            self.drop_pending_after_deadline(&mut queue);
+            if let Some(tile) = queue.pending.pop_front() {
+                queue.in_flight += 1;
+                return Some(tile);
+            }
+            if queue.in_flight == 0 {
+                return None;
+            }
+            queue = self.queue_changed.wait(queue).expect("Poisoned lock!");
+        }
+    }
+
+    /// Puts back a tile whose worker failed.
+    fn return_tile(&self, tile: ScreenBlock) {
+        let mut queue = self.queue.lock().expect("Poisoned lock!");
+        queue.pending.push_front(tile);
+        queue.in_flight -= 1;
+        self.queue_changed.notify_all();
+    }
+
+    fn finish_tile(&self, film: &Film, stats: &RenderStats, elapsed: Duration) {
+        self.film.lock().expect("Poisoned lock!").accumulate(film);
+        {
+            let mut merged_stats = self.stats.lock().expect("Poisoned lock!");
+            *merged_stats = merged_stats.merge(stats);
+        }
+
+        let progress = {
+            let mut queue = self.queue.lock().expect("Poisoned lock!");
+            queue.in_flight -= 1;
+            queue.finished += 1;
+            self.queue_changed.notify_all();
+            RenderProgressSnapshot {
+                finished: queue.finished,
+                total: queue.total,
+            }
+        };
+
+        self.events.send(RenderEvent::TileFinished {
+            block: film.block().clone(),
+            samples: film.total_sample_count(),
+            elapsed,
+            progress,
+        });
+    }
+
+    /// Sends the final events and returns the accumulated film.
+    fn finish(self) -> Film {
+        let finished_tiles = self.queue.into_inner().expect("Poisoned lock!").finished;
+        let film = self.film.into_inner().expect("Poisoned lock!");
+        let stats = RenderStats {
+            finished_tiles,
+            elapsed: self.start_time.elapsed(),
+            stopped: finished_tiles < self.tile_count,
+            ..self.stats.into_inner().expect("Poisoned lock!")
+        };
+
+        if !stats.stopped {
+            self.events.send(RenderEvent::PassFinished);
+        }
+        self.events.send(RenderEvent::RenderFinished { stats });
+
+        film
+    }
+
+    fn handle_worker(&self, mut connection: Connection) -> Result<(), ProtocolError> {
+        Message::Settings(self.settings).write_to(&mut connection)?;
+
+        while let Some(tile) = self.take_tile() {
+            self.events.send(RenderEvent::TileStarted {
+                block: tile.clone(),
+            });
+            let tile_start_time = Instant::now();
+
+            match Self::render_remotely(&mut connection, &tile) {
+                Ok((film, stats)) => self.finish_tile(&film, &stats, tile_start_time.elapsed()),
+                Err(e) => {
+                    self.return_tile(tile);
+                    return Err(e);
+                }
+            }
+        }
+
+        Message::Finished.write_to(&mut connection)
+    }
+
+    fn render_remotely(
+        connection: &mut Connection,
+        tile: &ScreenBlock,
+    ) -> Result<(Film, RenderStats), ProtocolError> {
+        Message::Tile(tile.clone()).write_to(connection)?;
+        match Message::read_from(connection)? {
+            Message::TileFinished(film, stats)
+                if film.block().min == tile.min && film.block().max == tile.max =>
+            {
+                Ok((film, stats))
+            }
+            _ => Err(ProtocolError::UnexpectedMessage),
+        }
+    }
+}
+
+/// Messages exchanged between the coordinator and workers.
+/// Each message is sent as a little endian u32 length, followed by a tag byte and the payload.
+#[derive(Clone, Debug)]
+enum Message {
+    /// Coordinator -> worker, first message after connecting
+    Settings(RenderSettings),
+    /// Coordinator -> worker, request to render a tile
+    Tile(ScreenBlock),
+    /// Worker -> coordinator, response to `Tile`, with stats of rendering the tile
+    TileFinished(Film, RenderStats),
+    /// Coordinator -> worker, there is no more work
+    Finished,
+}
+
+impl Message {
+    const TAG_SETTINGS: u8 = 1;
+    const TAG_TILE: u8 = 2;
+    const TAG_TILE_FINISHED: u8 = 3;
+    const TAG_FINISHED: u8 = 4;
+
+    fn write_to(&self, w: &mut impl Write) -> Result<(), ProtocolError> {
+        let mut payload = Vec::new();
+        self.encode(&mut payload);
+
+        w.write_all(&(payload.len() as u32).to_le_bytes())?;
+        w.write_all(&payload)?;
+        w.flush()?;
+
+        Ok(())
+    }
+
+    fn read_from(r: &mut impl Read) -> Result<Message, ProtocolError> {
+        let length = read_u32(r)? as usize;
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(ProtocolError::InvalidMessage("Message too long"));
+        }
+
+        let mut buffer = vec![0; length];
+        r.read_exact(&mut buffer)?;
+
+        let mut payload = buffer.as_slice();
+        let message = Self::decode(&mut payload)?;
+        if !payload.is_empty() {
+            return Err(ProtocolError::InvalidMessage("Trailing data"));
+        }
+
+        Ok(message)
+    }
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Message::Settings(settings) => {
+                buffer.push(Self::TAG_SETTINGS);
+                put_u32(buffer, PROTOCOL_VERSION);
+                put_settings(buffer, settings);
+            }
+            Message::Tile(tile) => {
+                buffer.push(Self::TAG_TILE);
+                put_block(buffer, tile);
+            }
+            Message::TileFinished(film, stats) => {
+                buffer.push(Self::TAG_TILE_FINISHED);
+                put_film(buffer, film);
+                put_render_stats(buffer, stats);
+            }
+            Message::Finished => buffer.push(Self::TAG_FINISHED),
+        }
+    }
+
+    fn decode(payload: &mut &[u8]) -> Result<Message, ProtocolError> {
+        match read_u8(payload)? {
+            Self::TAG_SETTINGS => {
+                let version = read_u32(payload)?;
+                if version != PROTOCOL_VERSION {
+                    return Err(ProtocolError::VersionMismatch {
+                        expected: PROTOCOL_VERSION,
+                        actual: version,
+                    });
+                }
+
+                Ok(Message::Settings(read_settings(payload)?))
+            }
+            Self::TAG_TILE => Ok(Message::Tile(read_block(payload)?)),
+            Self::TAG_TILE_FINISHED => Ok(Message::TileFinished(
+                read_film(payload)?,
+                read_render_stats(payload)?,
+            )),
+            Self::TAG_FINISHED => Ok(Message::Finished),
+            tag => Err(ProtocolError::UnknownMessage(tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        geometry::{ScreenSize, WorldPoint},
+        renderer::ShadingMode,
+        scene::primitives::Sphere,
+        util::Rgba,
+    };
+    use assert2::{assert, let_assert};
+
+    fn test_settings() -> RenderSettings {
+        RenderSettings {
+            tile_size: 8.try_into().unwrap(),
+            sample_count: 2.try_into().unwrap(),
+            resolution: ScreenSize::new(30, 20),
+            shading_mode: ShadingMode::Shaded,
+            time_limit: None,
+        }
+    }
+
+    fn test_scene() -> Scene<Sphere> {
+        Scene::new(Sphere {
+            center: WorldPoint::new(0.0, 0.0, -5.0),
+            radius: 1.0,
+        })
+    }
+
+    fn round_trip(message: &Message) -> Message {
+        let mut buffer = Vec::new();
+        message.write_to(&mut buffer).unwrap();
+        let mut reader = buffer.as_slice();
+        let decoded = Message::read_from(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        decoded
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let settings = test_settings();
+        let_assert!(Message::Settings(decoded) = round_trip(&Message::Settings(settings)));
+        assert!(decoded.tile_size == settings.tile_size);
+        assert!(decoded.sample_count == settings.sample_count);
+        assert!(decoded.resolution == settings.resolution);
+    }
+
+    #[test]
+    fn tile_finished_round_trip() {
+        let mut film = Film::new(ScreenBlock::new([3, 4].into(), [7, 6].into()));
+        film.add_sample(&ScreenPoint::new(4, 5), Rgba::new(0.25, 0.5, 1.0, 1.0));
+
+        let stats = RenderStats {
+            samples: 1,
+            primary_rays: 1,
+            ..RenderStats::default()
+        };
+
+        let_assert!(
+            Message::TileFinished(decoded, decoded_stats) =
+                round_trip(&Message::TileFinished(film.clone(), stats))
+        );
+        assert!(decoded_stats.samples == 1);
+        assert!(decoded.block().min == film.block().min);
+        assert!(decoded.block().max == film.block().max);
+        assert!(decoded.sums() == film.sums());
+        assert!(decoded.sample_counts() == film.sample_counts());
+    }
+
+    #[test]
+    fn unknown_tag() {
+        let buffer = [1u8, 0, 0, 0, 200];
+        let result = Message::read_from(&mut buffer.as_slice());
+        assert!(let Err(ProtocolError::UnknownMessage(200)) = result);
+    }
+
+    #[test]
+    fn address_parsing() {
+        assert!(
+            "localhost:1234".parse::<Address>().unwrap() == Address::Tcp("localhost:1234".into())
+        );
+        #[cfg(unix)]
+        assert!(
+            "unix:/tmp/minipath.sock".parse::<Address>().unwrap()
+                == Address::Unix("/tmp/minipath.sock".into())
+        );
+    }
+
+    /// Renders the whole image with several workers, one of which disconnects in the middle
+    /// of a tile, and checks that all pixels got rendered exactly once.
+    #[test]
+    fn distributed_render_with_failing_worker() {
+        let settings = test_settings();
+        let scene = &test_scene();
+        let camera = &Camera::default();
+
+        let listener = Address::Tcp("127.0.0.1:0".into()).bind().unwrap();
+        let address = listener.local_address().unwrap();
+
+        let events = RenderEvents::new();
+        let receiver = events.subscribe();
+
+        let film = thread::scope(|scope| {
+            let coordinator = scope.spawn(|| serve(&listener, settings, &events));
+
+            // Worker that takes a tile and then dies without rendering it
+            let mut failing_connection = address.connect().unwrap();
+            let_assert!(Ok(Message::Settings(_)) = Message::read_from(&mut failing_connection));
+            let_assert!(Ok(Message::Tile(_)) = Message::read_from(&mut failing_connection));
+
+            // Connect all of the other workers before the work can be finished
+            let connections: Vec<_> = (0..3).map(|_| address.connect().unwrap()).collect();
+            drop(failing_connection);
+
+            let workers: Vec<_> = connections
+                .into_iter()
+                .enumerate()
+                .map(|(worker_id, mut connection)| {
+                    scope.spawn(move || {
+                        work_connection(worker_id, &mut connection, scene, camera).unwrap()
+                    })
+                })
+                .collect();
+            let tile_count: usize = workers.into_iter().map(|w| w.join().unwrap()).sum();
+
+            let total = ScreenBlock::with_size(ScreenPoint::origin(), &settings.resolution)
+                .tile_ordering(settings.tile_size)
+                .len();
+            assert!(tile_count == total);
+
+            coordinator.join().unwrap().unwrap()
+        });
+
+        for p in film.block().internal_points() {
+            assert!(film.sample_count(&p) == settings.sample_count.get());
+        }
+
+        let events: Vec<_> = receiver.try_iter().collect();
+        assert!(
+            events
+                .iter()
+                .any(|e| matches!(e, RenderEvent::Error { .. }))
+        );
+        let_assert!(Some(RenderEvent::RenderFinished { stats }) = events.last());
+        assert!(!stats.stopped);
+        assert!(stats.samples == film.total_sample_count());
+        assert!(stats.primary_rays == stats.samples);
+    }
+}
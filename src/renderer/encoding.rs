@@ -0,0 +1,319 @@
+//! Little endian binary encoding of render data,
+//! shared by checkpoint files, the distributed rendering protocol and BVH cache files.
+
+use std::{
+    io::{self, Read},
+    num::NonZeroU32,
+};
+
+use crate::{
+    geometry::{ScreenBlock, ScreenPoint, ScreenSize, WorldVector},
+    renderer::{RenderSettings, ShadingMode, aov::AovSums, film::Film, stats::RenderStats},
+    scene::triangle_bvh::TraversalStats,
+    util::{Rgba, Stats},
+};
+
+/// Upper limit on pixel count of a decoded film, to avoid allocating nonsense when reading garbage.
+const MAX_FILM_AREA: u64 = 1 << 28;
+
+pub fn put_u16(buffer: &mut Vec<u8>, v: u16) {
+    buffer.extend_from_slice(&v.to_le_bytes());
+}
+
+pub fn put_u32(buffer: &mut Vec<u8>, v: u32) {
+    buffer.extend_from_slice(&v.to_le_bytes());
+}
+
+pub fn put_u64(buffer: &mut Vec<u8>, v: u64) {
+    buffer.extend_from_slice(&v.to_le_bytes());
+}
+
+pub fn put_f32(buffer: &mut Vec<u8>, v: f32) {
+    buffer.extend_from_slice(&v.to_le_bytes());
+}
+
+pub fn put_block(buffer: &mut Vec<u8>, block: &ScreenBlock) {
+    for v in [block.min.x, block.min.y, block.max.x, block.max.y] {
+        put_u32(buffer, v);
+    }
+}
+
+pub fn put_settings(buffer: &mut Vec<u8>, settings: &RenderSettings) {
+    put_u32(buffer, settings.tile_size.get());
+    put_u32(buffer, settings.sample_count.get());
+    put_u32(buffer, settings.resolution.x);
+    put_u32(buffer, settings.resolution.y);
+    put_shading_mode(buffer, settings.shading_mode);
+}
+
+pub fn put_shading_mode(buffer: &mut Vec<u8>, mode: ShadingMode) {
+    let index = ShadingMode::ALL
+        .iter()
+        .position(|m| *m == mode)
+        .expect("All shading modes are listed");
+    put_u32(buffer, index as u32);
+}
+
+pub fn put_film(buffer: &mut Vec<u8>, film: &Film) {
+    put_block(buffer, film.block());
+    for ((sum, count), aov) in film
+        .sums()
+        .iter()
+        .zip(film.sample_counts())
+        .zip(film.aovs())
+    {
+        for component in [sum.r, sum.g, sum.b, sum.a] {
+            put_f32(buffer, component);
+        }
+        put_u32(buffer, *count);
+        put_aov_sums(buffer, aov);
+    }
+}
+
+pub fn put_aov_sums(buffer: &mut Vec<u8>, aov: &AovSums) {
+    for component in [aov.albedo.r, aov.albedo.g, aov.albedo.b] {
+        put_f32(buffer, component);
+    }
+    put_vector(buffer, &aov.normal);
+    put_f32(buffer, aov.depth);
+    put_vector(buffer, &aov.position);
+    put_u32(buffer, aov.material_id);
+    put_u32(buffer, aov.hit_count);
+}
+
+pub fn put_vector(buffer: &mut Vec<u8>, v: &WorldVector) {
+    for component in v.iter() {
+        put_f32(buffer, *component);
+    }
+}
+
+pub fn put_stats(buffer: &mut Vec<u8>, stats: &Stats) {
+    put_u64(buffer, stats.count as u64);
+    put_u64(buffer, stats.min as u64);
+    put_u64(buffer, stats.max as u64);
+    put_f32(buffer, stats.avg);
+}
+
+/// Writes render stats, except for the elapsed time and stopped flag, which only make sense
+/// for the whole render.
+pub fn put_render_stats(buffer: &mut Vec<u8>, stats: &RenderStats) {
+    put_u64(buffer, stats.finished_tiles as u64);
+    put_u64(buffer, stats.samples);
+    put_u64(buffer, stats.primary_rays);
+    put_u64(buffer, stats.traversal.rays);
+    put_u64(buffer, stats.traversal.inner_nodes);
+    put_u64(buffer, stats.traversal.triangle_packets);
+    put_stats(buffer, &stats.tile_times);
+}
+
+pub fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+pub fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    r.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+pub fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn read_usize(r: &mut impl Read) -> io::Result<usize> {
+    usize::try_from(read_u64(r)?).map_err(|_| invalid_data("Value out of range"))
+}
+
+pub fn read_f32(r: &mut impl Read) -> io::Result<f32> {
+    read_u32(r).map(f32::from_bits)
+}
+
+pub fn read_nonzero_u32(r: &mut impl Read) -> io::Result<NonZeroU32> {
+    NonZeroU32::new(read_u32(r)?).ok_or_else(|| invalid_data("Unexpected zero"))
+}
+
+pub fn read_block(r: &mut impl Read) -> io::Result<ScreenBlock> {
+    let min = ScreenPoint::new(read_u32(r)?, read_u32(r)?);
+    let max = ScreenPoint::new(read_u32(r)?, read_u32(r)?);
+    Ok(ScreenBlock::new(min, max))
+}
+
+/// Reads settings written by `put_settings`.
+/// Time limit is not encoded, it only matters to whoever runs the render loop.
+pub fn read_settings(r: &mut impl Read) -> io::Result<RenderSettings> {
+    let tile_size = read_nonzero_u32(r)?;
+    let sample_count = read_nonzero_u32(r)?;
+    let resolution = ScreenSize::new(read_u32(r)?, read_u32(r)?);
+    let shading_mode = read_shading_mode(r)?;
+
+    Ok(RenderSettings {
+        tile_size,
+        sample_count,
+        resolution,
+        shading_mode,
+        time_limit: None,
+    })
+}
+
+pub fn read_film(r: &mut impl Read) -> io::Result<Film> {
+    let block = read_block(r)?;
+    if block.is_empty() {
+        return Ok(Film::new(block));
+    }
+
+    let size = block.size();
+    if u64::from(size.x) * u64::from(size.y) > MAX_FILM_AREA {
+        return Err(invalid_data("Film too large"));
+    }
+
+    let area = block.area() as usize;
+    let mut sums = Vec::new();
+    let mut sample_counts = Vec::new();
+    let mut aovs = Vec::new();
+    for _ in 0..area {
+        sums.push(Rgba::new(
+            read_f32(r)?,
+            read_f32(r)?,
+            read_f32(r)?,
+            read_f32(r)?,
+        ));
+        sample_counts.push(read_u32(r)?);
+        aovs.push(read_aov_sums(r)?);
+    }
+
+    Ok(Film::from_raw(block, sums, sample_counts, aovs)
+        .unwrap_or_else(|| unreachable!("Buffer sizes always match the block")))
+}
+
+pub fn read_aov_sums(r: &mut impl Read) -> io::Result<AovSums> {
+    Ok(AovSums {
+        albedo: rgb::RGB::new(read_f32(r)?, read_f32(r)?, read_f32(r)?),
+        normal: read_vector(r)?,
+        depth: read_f32(r)?,
+        position: read_vector(r)?,
+        material_id: read_u32(r)?,
+        hit_count: read_u32(r)?,
+    })
+}
+
+pub fn read_vector(r: &mut impl Read) -> io::Result<WorldVector> {
+    Ok(WorldVector::new(read_f32(r)?, read_f32(r)?, read_f32(r)?))
+}
+
+pub fn read_shading_mode(r: &mut impl Read) -> io::Result<ShadingMode> {
+    let index = read_u32(r)?;
+    ShadingMode::ALL
+        .get(index as usize)
+        .copied()
+        .ok_or_else(|| invalid_data("Unknown shading mode"))
+}
+
+pub fn read_stats(r: &mut impl Read) -> io::Result<Stats> {
+    Ok(Stats {
+        count: read_usize(r)?,
+        min: read_usize(r)?,
+        max: read_usize(r)?,
+        avg: read_f32(r)?,
+    })
+}
+
+/// Reads stats written by `put_render_stats`.
+pub fn read_render_stats(r: &mut impl Read) -> io::Result<RenderStats> {
+    Ok(RenderStats {
+        finished_tiles: read_usize(r)?,
+        samples: read_u64(r)?,
+        primary_rays: read_u64(r)?,
+        traversal: TraversalStats {
+            rays: read_u64(r)?,
+            inner_nodes: read_u64(r)?,
+            triangle_packets: read_u64(r)?,
+        },
+        tile_times: read_stats(r)?,
+        ..RenderStats::default()
+    })
+}
+
+pub fn invalid_data(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{renderer::aov::AovSample, scene::triangle_bvh::RayTraversal};
+    use assert2::assert;
+
+    #[test]
+    fn film_round_trip() {
+        let mut film = Film::new(ScreenBlock::new([3, 4].into(), [7, 6].into()));
+        film.add_sample(&ScreenPoint::new(4, 5), Rgba::new(0.25, 0.5, 1.0, 1.0));
+        film.add_aov_sample(
+            &ScreenPoint::new(4, 5),
+            &AovSample {
+                albedo: rgb::RGB::new(1.0, 0.5, 0.25),
+                normal: WorldVector::new(0.0, 1.0, 0.0),
+                depth: 3.5,
+                position: WorldVector::new(1.0, 2.0, 3.0),
+                material_id: 4,
+            },
+        );
+
+        let mut buffer = Vec::new();
+        put_film(&mut buffer, &film);
+        let mut reader = buffer.as_slice();
+        let decoded = read_film(&mut reader).unwrap();
+
+        assert!(reader.is_empty());
+        assert!(decoded.block().min == film.block().min);
+        assert!(decoded.block().max == film.block().max);
+        assert!(decoded.sums() == film.sums());
+        assert!(decoded.sample_counts() == film.sample_counts());
+        assert!(decoded.aovs() == film.aovs());
+    }
+
+    #[test]
+    fn render_stats_round_trip() {
+        let mut stats = RenderStats {
+            finished_tiles: 3,
+            samples: 1000,
+            primary_rays: 1000,
+            ..RenderStats::default()
+        };
+        stats.traversal.add_ray(RayTraversal {
+            inner_nodes: 7,
+            triangle_packets: 2,
+        });
+        stats.add_tile_time(std::time::Duration::from_micros(1234));
+
+        let mut buffer = Vec::new();
+        put_render_stats(&mut buffer, &stats);
+        let mut reader = buffer.as_slice();
+        let decoded = read_render_stats(&mut reader).unwrap();
+
+        assert!(reader.is_empty());
+        assert!(decoded.finished_tiles == stats.finished_tiles);
+        assert!(decoded.primary_rays == stats.primary_rays);
+        assert!(decoded.traversal == stats.traversal);
+        assert!(decoded.tile_times == stats.tile_times);
+    }
+
+    #[test]
+    fn truncated_film() {
+        let film = Film::new(ScreenBlock::new([0, 0].into(), [2, 2].into()));
+        let mut buffer = Vec::new();
+        put_film(&mut buffer, &film);
+        buffer.pop();
+
+        assert!(read_film(&mut buffer.as_slice()).is_err());
+    }
+}
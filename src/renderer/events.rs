@@ -0,0 +1,99 @@
+use std::{
+    sync::{
+        Mutex,
+        mpsc::{self, Receiver, Sender},
+    },
+    time::Duration,
+};
+
+use crate::{
+    geometry::ScreenBlock,
+    renderer::{machinery::RenderProgressSnapshot, stats::RenderStats},
+};
+
+/// Things that happen during a render, sent to subscribers of `RenderEvents`.
+#[derive(Clone, Debug)]
+pub enum RenderEvent {
+    TileStarted {
+        block: ScreenBlock,
+    },
+    TileFinished {
+        block: ScreenBlock,
+        /// Number of samples rendered in the tile. Less than the full count if the render was stopped.
+        samples: u64,
+        /// Time spent rendering the tile.
+        elapsed: Duration,
+        progress: RenderProgressSnapshot,
+    },
+    /// All tiles of the image have been rendered.
+    /// Not sent if the render was stopped early.
+    PassFinished,
+    /// The render has ended, either by finishing, or by being stopped.
+    /// This is always the last event of a render.
+    RenderFinished {
+        stats: RenderStats,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Distributes render events to any number of subscribers.
+#[derive(Default)]
+pub struct RenderEvents {
+    subscribers: Mutex<Vec<Sender<RenderEvent>>>,
+}
+
+impl RenderEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a receiver of all events sent from now on.
+    pub fn subscribe(&self) -> Receiver<RenderEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers
+            .lock()
+            .expect("Poisoned lock!")
+            .push(sender);
+        receiver
+    }
+
+    /// Sends the event to all subscribers, forgetting the ones whose receiver was dropped.
+    pub fn send(&self, event: RenderEvent) {
+        self.subscribers
+            .lock()
+            .expect("Poisoned lock!")
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::{assert, let_assert};
+
+    #[test]
+    fn all_subscribers_receive() {
+        let events = RenderEvents::new();
+        let a = events.subscribe();
+        let b = events.subscribe();
+
+        events.send(RenderEvent::PassFinished);
+
+        let_assert!(Ok(RenderEvent::PassFinished) = a.try_recv());
+        let_assert!(Ok(RenderEvent::PassFinished) = b.try_recv());
+    }
+
+    #[test]
+    fn dropped_subscriber_is_forgotten() {
+        let events = RenderEvents::new();
+        let a = events.subscribe();
+        drop(events.subscribe());
+
+        events.send(RenderEvent::PassFinished);
+
+        assert!(events.subscribers.lock().unwrap().len() == 1);
+        assert!(a.try_recv().is_ok());
+    }
+}
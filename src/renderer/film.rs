@@ -0,0 +1,281 @@
+use image::{Rgb32FImage, RgbaImage};
+
+use crate::{
+    geometry::{ScreenBlock, ScreenPoint, ScreenSize},
+    renderer::{
+        aov::{Aov, AovSample, AovSums},
+        tonemap::DisplaySettings,
+        worker::color_to_image,
+    },
+    util::Rgba,
+};
+
+/// Floating point buffer of rendered samples covering a block of the screen.
+/// Keeps sums of samples and per pixel sample counts, so that films rendered separately
+/// (by different workers, or at different times) can be merged and normalised later.
+/// Auxiliary outputs are summed the same way, next to the image.
+#[derive(Clone, Debug)]
+pub struct Film {
+    block: ScreenBlock,
+    sums: Vec<Rgba>,
+    sample_counts: Vec<u32>,
+    aovs: Vec<AovSums>,
+}
+
+impl Film {
+    pub fn new(block: ScreenBlock) -> Self {
+        let area = block.area() as usize;
+        Film {
+            block,
+            sums: vec![Rgba::default(); area],
+            sample_counts: vec![0; area],
+            aovs: vec![AovSums::default(); area],
+        }
+    }
+
+    /// Creates a film from raw sums, sample counts and AOV sums, in C order.
+    /// Returns None if the buffer sizes don't match the block.
+    pub fn from_raw(
+        block: ScreenBlock,
+        sums: Vec<Rgba>,
+        sample_counts: Vec<u32>,
+        aovs: Vec<AovSums>,
+    ) -> Option<Self> {
+        let area = block.area() as usize;
+        if sums.len() != area || sample_counts.len() != area || aovs.len() != area {
+            return None;
+        }
+        Some(Film {
+            block,
+            sums,
+            sample_counts,
+            aovs,
+        })
+    }
+
+    /// Clears all samples and moves the film to cover a different block, reusing the allocation.
+    pub fn reset(&mut self, block: ScreenBlock) {
+        let area = block.area() as usize;
+        self.block = block;
+        self.sums.clear();
+        self.sums.resize(area, Rgba::default());
+        self.sample_counts.clear();
+        self.sample_counts.resize(area, 0);
+        self.aovs.clear();
+        self.aovs.resize(area, AovSums::default());
+    }
+
+    pub fn block(&self) -> &ScreenBlock {
+        &self.block
+    }
+
+    /// Sums of samples of all pixels, in C order.
+    pub fn sums(&self) -> &[Rgba] {
+        &self.sums
+    }
+
+    /// Sample counts of all pixels, in C order.
+    pub fn sample_counts(&self) -> &[u32] {
+        &self.sample_counts
+    }
+
+    /// AOV sums of all pixels, in C order.
+    pub fn aovs(&self) -> &[AovSums] {
+        &self.aovs
+    }
+
+    /// Sum of sample counts of all pixels.
+    pub fn total_sample_count(&self) -> u64 {
+        self.sample_counts
+            .iter()
+            .map(|&count| u64::from(count))
+            .sum()
+    }
+
+    pub fn add_sample(&mut self, p: &ScreenPoint, value: Rgba) {
+        let index = self.index(p);
+        self.sums[index] += value;
+        self.sample_counts[index] += 1;
+    }
+
+    /// Adds auxiliary values of a sample that hit something.
+    /// Has to be called in addition to `add_sample`, which does the counting.
+    pub fn add_aov_sample(&mut self, p: &ScreenPoint, sample: &AovSample) {
+        let index = self.index(p);
+        self.aovs[index].add(sample);
+    }
+
+    pub fn sample_count(&self, p: &ScreenPoint) -> u32 {
+        self.sample_counts[self.index(p)]
+    }
+
+    /// Returns average of the samples in a pixel, or transparent black if it has no samples yet.
+    pub fn pixel(&self, p: &ScreenPoint) -> Rgba {
+        let index = self.index(p);
+        match self.sample_counts[index] {
+            0 => Rgba::default(),
+            count => self.sums[index] / count as f32,
+        }
+    }
+
+    /// Adds all samples from the other film to this one.
+    /// Panics if the other film is not contained in this one.
+    pub fn accumulate(&mut self, other: &Film) {
+        for p in other.block.internal_points() {
+            let other_index = other.index(&p);
+            let index = self.index(&p);
+            self.sums[index] += other.sums[other_index];
+            self.sample_counts[index] += other.sample_counts[other_index];
+            self.aovs[index].merge(&other.aovs[other_index]);
+        }
+    }
+
+    /// Copies a block of this film, including its samples, into the target film.
+    /// Panics if the block is not contained in this film.
+    pub fn crop_into(&self, block: &ScreenBlock, target: &mut Film) {
+        target.reset(block.clone());
+        for p in block.internal_points() {
+            let index = self.index(&p);
+            let target_index = target.index(&p);
+            target.sums[target_index] = self.sums[index];
+            target.sample_counts[target_index] = self.sample_counts[index];
+            target.aovs[target_index] = self.aovs[index];
+        }
+    }
+
+    /// Replaces samples in the area of the other film with its samples.
+    /// Panics if the other film is not contained in this one.
+    pub fn paste(&mut self, other: &Film) {
+        for p in other.block.internal_points() {
+            let other_index = other.index(&p);
+            let index = self.index(&p);
+            self.sums[index] = other.sums[other_index];
+            self.sample_counts[index] = other.sample_counts[other_index];
+            self.aovs[index] = other.aovs[other_index];
+        }
+    }
+
+    /// Writes normalised pixels of the film to an 8bit image, at the film's position.
+    pub fn develop_into(&self, image: &mut RgbaImage) {
+        for p in self.block.internal_points() {
+            image.put_pixel(p.x, p.y, color_to_image(self.pixel(&p)));
+        }
+    }
+
+    /// Converts the film to an 8bit image of the film's size.
+    pub fn develop(&self) -> RgbaImage {
+        RgbaImage::from_fn(self.block.width(), self.block.height(), |x, y| {
+            color_to_image(self.pixel(&(self.block.min + ScreenSize::new(x, y))))
+        })
+    }
+
+    /// Converts the film to an 8bit image of the film's size, for display.
+    pub fn develop_with(&self, display: &DisplaySettings) -> RgbaImage {
+        RgbaImage::from_fn(self.block.width(), self.block.height(), |x, y| {
+            display.apply(self.pixel(&(self.block.min + ScreenSize::new(x, y))))
+        })
+    }
+
+    /// Converts one of the auxiliary outputs to a float image of the film's size.
+    pub fn develop_aov(&self, aov: Aov) -> Rgb32FImage {
+        Rgb32FImage::from_fn(self.block.width(), self.block.height(), |x, y| {
+            let index = self.index(&(self.block.min + ScreenSize::new(x, y)));
+            image::Rgb(self.aovs[index].value(aov, self.sample_counts[index]))
+        })
+    }
+
+    fn index(&self, p: &ScreenPoint) -> usize {
+        assert!(
+            self.block.contains(p),
+            "{p:?} is outside of {:?}",
+            self.block
+        );
+        ((p.x - self.block.min.x) + (p.y - self.block.min.y) * self.block.width()) as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    #[test]
+    fn pixel_is_normalised() {
+        let mut film = Film::new(ScreenBlock::new([0, 0].into(), [2, 2].into()));
+        let p = ScreenPoint::new(1, 0);
+        film.add_sample(&p, Rgba::new(1.0, 0.0, 0.0, 1.0));
+        film.add_sample(&p, Rgba::new(0.0, 0.0, 1.0, 1.0));
+
+        assert!(film.sample_count(&p) == 2);
+        assert!(film.pixel(&p) == Rgba::new(0.5, 0.0, 0.5, 1.0));
+        assert!(film.pixel(&ScreenPoint::new(0, 1)) == Rgba::default());
+    }
+
+    #[test]
+    fn accumulate_sub_block() {
+        let mut film = Film::new(ScreenBlock::new([0, 0].into(), [10, 10].into()));
+        let mut tile = Film::new(ScreenBlock::new([4, 5].into(), [6, 8].into()));
+        for p in tile.block().internal_points() {
+            tile.add_sample(&p, Rgba::new(1.0, 1.0, 1.0, 1.0));
+        }
+
+        film.accumulate(&tile);
+        film.accumulate(&tile);
+
+        for p in film.block().internal_points() {
+            let expected = if tile.block().contains(&p) { 2 } else { 0 };
+            assert!(film.sample_count(&p) == expected);
+        }
+    }
+
+    #[test]
+    fn crop_and_paste() {
+        let mut film = Film::new(ScreenBlock::new([0, 0].into(), [10, 10].into()));
+        let p = ScreenPoint::new(5, 5);
+        film.add_sample(&p, Rgba::new(1.0, 1.0, 1.0, 1.0));
+
+        let mut tile = Film::new(ScreenBlock::new([0, 0].into(), [0, 0].into()));
+        film.crop_into(&ScreenBlock::new([4, 4].into(), [8, 8].into()), &mut tile);
+        assert!(tile.sample_count(&p) == 1);
+
+        tile.add_sample(&p, Rgba::new(1.0, 1.0, 1.0, 1.0));
+        film.paste(&tile);
+        assert!(film.sample_count(&p) == 2);
+        assert!(film.pixel(&p) == Rgba::new(1.0, 1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn aovs_follow_samples() {
+        let mut film = Film::new(ScreenBlock::new([0, 0].into(), [4, 4].into()));
+        let p = ScreenPoint::new(1, 2);
+        let sample = AovSample {
+            albedo: rgb::RGB::new(1.0, 1.0, 1.0),
+            normal: [0.0, 0.0, 1.0].into(),
+            depth: 2.0,
+            position: [1.0, 2.0, 3.0].into(),
+            material_id: 7,
+        };
+        film.add_sample(&p, Rgba::default());
+        film.add_aov_sample(&p, &sample);
+        film.add_sample(&p, Rgba::default());
+
+        let mut tile = Film::new(ScreenBlock::new([0, 0].into(), [0, 0].into()));
+        film.crop_into(&ScreenBlock::new([0, 2].into(), [2, 4].into()), &mut tile);
+        let mut merged = Film::new(film.block().clone());
+        merged.accumulate(&tile);
+
+        let depth = merged.develop_aov(Aov::Depth);
+        assert!(depth.get_pixel(1, 2).0 == [2.0; 3]);
+        assert!(depth.get_pixel(0, 0).0 == [f32::INFINITY; 3]);
+        assert!(merged.develop_aov(Aov::MaterialId).get_pixel(1, 2).0 == [7.0; 3]);
+        assert!(merged.develop_aov(Aov::SampleCount).get_pixel(1, 2).0 == [2.0; 3]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn accumulate_outside() {
+        let mut film = Film::new(ScreenBlock::new([0, 0].into(), [10, 10].into()));
+        let tile = Film::new(ScreenBlock::new([8, 8].into(), [12, 12].into()));
+        film.accumulate(&tile);
+    }
+}
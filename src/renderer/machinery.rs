@@ -1,144 +1,128 @@
 use std::{
-    ops::Deref as _,
     sync::{
         Arc, Mutex,
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::Receiver,
     },
     thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
-use image::{GenericImage, GenericImageView, RgbaImage};
+use image::RgbaImage;
 
 use crate::{
     camera::Camera,
     geometry::{ScreenBlock, ScreenPoint},
-    renderer::{RenderSettings, worker::Worker},
+    renderer::{
+        RenderSettings,
+        checkpoint::Checkpoint,
+        events::{RenderEvent, RenderEvents},
+        film::Film,
+        stats::RenderStats,
+        worker::{StopCondition, Worker},
+    },
     scene::{Object, Scene},
 };
 
-pub fn render<
-    O: Object + Send + Sync + 'static,
-    F1: Fn(ScreenBlock) + Send + Sync + 'static,
-    F2: Fn(ScreenBlock, RenderProgressSnapshot) + Send + Sync + 'static,
->(
+pub fn render<O: Object + Send + Sync + 'static>(
+    scene: Arc<Scene<O>>,
+    camera: Camera,
+    settings: RenderSettings,
+    events: RenderEvents,
+) -> anyhow::Result<RenderProgress<O>> {
+    start(scene, camera, Checkpoint::new(settings), events)
+}
+
+/// Continues a render from a checkpoint.
+/// Only tiles that are not finished in the checkpoint are rendered, keeping the samples
+/// already in the checkpoint. Progress reports only count the remaining tiles.
+/// Time limit counts from the start of the resumed render.
+pub fn resume<O: Object + Send + Sync + 'static>(
     scene: Arc<Scene<O>>,
     camera: Camera,
     settings: RenderSettings,
-    started_tile_callback: F1,
-    finished_tile_callback: F2,
+    checkpoint: Checkpoint,
+    events: RenderEvents,
+) -> anyhow::Result<RenderProgress<O>> {
+    start(scene, camera, checkpoint.resume_with(settings)?, events)
+}
+
+fn start<O: Object + Send + Sync + 'static>(
+    scene: Arc<Scene<O>>,
+    camera: Camera,
+    checkpoint: Checkpoint,
+    events: RenderEvents,
 ) -> anyhow::Result<RenderProgress<O>> {
+    let settings = *checkpoint.settings();
     let cores = core_affinity::get_core_ids().expect("We need a CPU list!");
     let worker_count = cores.len();
 
-    let image = RgbaImage::new(settings.resolution.x, settings.resolution.y);
+    let start_time = Instant::now();
+    let image = checkpoint.film().develop();
+    let tile_ordering = ScreenBlock::with_size(ScreenPoint::origin(), &settings.resolution)
+        .tile_ordering(settings.tile_size)
+        .into_iter()
+        .filter(|tile| !checkpoint.is_tile_finished(tile))
+        .collect();
     let state = Arc::new(RenderState {
         scene,
         settings,
+        events,
 
         image: Mutex::new(image),
+        checkpoint: Mutex::new(checkpoint),
 
-        tile_ordering: ScreenBlock::with_size(ScreenPoint::origin(), &settings.resolution)
-            .tile_ordering(settings.tile_size),
+        tile_ordering,
         next_tile_index: AtomicUsize::new(0),
+        finished_tile_count: AtomicUsize::new(0),
+        aborted: AtomicBool::new(false),
 
-        start_time: Instant::now(),
+        start_time,
+        deadline: settings.time_limit.map(|limit| start_time + limit),
         end: Mutex::new((0, None)),
+        stats: Mutex::new(RenderStats::default()),
     });
-    let started_tile_callback = Arc::new(started_tile_callback);
-    let finished_tile_callback = Arc::new(finished_tile_callback);
 
     let threads = cores
         .into_iter()
         .enumerate()
         .map(|(worker_id, core)| {
             let state = Arc::clone(&state);
-            let started_tile_callback = Arc::clone(&started_tile_callback);
-            let finished_tile_callback = Arc::clone(&finished_tile_callback);
+            let camera = camera.clone();
 
             thread::Builder::new()
                 .name(format!("worker{worker_id}"))
                 .spawn(move || {
                     core_affinity::set_for_current(core);
-
                     let mut worker =
                         Worker::<O>::new(worker_id, camera.build_sampler(settings.resolution));
-                    let mut buffer =
-                        RgbaImage::new(settings.tile_size.into(), settings.tile_size.into());
-                    let tile_count = state.tile_ordering.len();
-
-                    let (_, Some(mut tile)) = state.get_next_tile() else {
-                        return;
-                    };
-
-                    loop {
-                        (started_tile_callback)(tile.clone());
-
-                        worker.render_tile(&state.scene, &state.settings, tile, &mut buffer);
-                        state
-                            .image
-                            .lock()
-                            .expect("Poisoned lock!")
-                            .copy_from(
-                                buffer.view(0, 0, tile.width(), tile.height()).deref(),
-                                tile.min.x,
-                                tile.min.y,
-                            )
-                            .unwrap_or_else(|_| {
-                                unreachable!("The buffer should always fit into the output")
-                            });
-
-                        let (new_tile_id, new_tile) = state.get_next_tile();
-
-                        (finished_tile_callback)(
-                            tile.clone(),
-                            RenderProgressSnapshot {
-                                finished: new_tile_id.saturating_sub(worker_count),
-                                total: tile_count,
-                            },
-                        );
-
-                        match new_tile {
-                            Some(new_tile) => tile = new_tile,
-                            None => break,
-                        }
-                    }
-
-                    let elapsed = Instant::elapsed(&state.start_time);
-                    let mut lock = state.end.lock().unwrap();
-
-                    lock.0 += 1;
-                    if lock.0 == worker_count {
-                        lock.1 = Some(elapsed);
-                    }
+                    state.run_worker(&mut worker);
+                    state.worker_finished(worker_count, worker.take_stats());
                 })
         })
         .collect::<Result<Vec<_>, _>>()?;
 
     Ok(RenderProgress {
         render_state: state,
-        worker_count,
         threads,
     })
 }
 
 pub struct RenderProgress<O: Object> {
     render_state: Arc<RenderState<O>>,
-    worker_count: usize,
     threads: Vec<JoinHandle<()>>,
 }
 
 impl<O: Object> RenderProgress<O> {
     /// Return number of processed and total tiles.
     pub fn progress(&self) -> RenderProgressSnapshot {
-        RenderProgressSnapshot {
-            finished: self
-                .render_state
-                .next_tile_index
-                .load(Ordering::Acquire)
-                .saturating_sub(self.worker_count),
-            total: self.render_state.tile_ordering.len(),
-        }
+        self.render_state.progress()
+    }
+
+    /// Returns a receiver of render events sent from now on.
+    /// To receive all events of the render, subscribe to `RenderEvents` before starting it.
+    pub fn subscribe(&self) -> Receiver<RenderEvent> {
+        self.render_state.events.subscribe()
     }
 
     pub fn is_finished(&self) -> bool {
@@ -157,11 +141,10 @@ impl<O: Object> RenderProgress<O> {
     }
 
     /// Signal the workers to abort.
-    /// Any running workers will still finish their tiles, but no new ones will be started.
+    /// Running workers stop after their current scanline, samples rendered so far are kept
+    /// in the image and the checkpoint.
     pub fn abort(&self) {
-        self.render_state
-            .next_tile_index
-            .store(self.render_state.tile_ordering.len(), Ordering::Release);
+        self.render_state.aborted.store(true, Ordering::Relaxed);
     }
 
     /// Wait for the workers to finish.
@@ -175,8 +158,42 @@ impl<O: Object> RenderProgress<O> {
     pub fn image(&self) -> &Mutex<RgbaImage> {
         &self.render_state.image
     }
+
+    /// Returns merged stats of the workers that already finished.
+    /// Complete once the render is finished.
+    pub fn stats(&self) -> RenderStats {
+        self.render_state
+            .stats
+            .lock()
+            .expect("Poisoned lock!")
+            .clone()
+    }
+
+    /// Returns samples rendered so far in a block of the image, without copying the whole film.
+    /// Panics if the block is not contained in the image.
+    pub fn crop_film(&self, block: &ScreenBlock) -> Film {
+        let mut film = Film::new(block.clone());
+        self.render_state
+            .checkpoint
+            .lock()
+            .expect("Poisoned lock!")
+            .film()
+            .crop_into(block, &mut film);
+        film
+    }
+
+    /// Returns a snapshot of the render that can be resumed later.
+    /// Tiles being rendered at the moment are not included.
+    pub fn checkpoint(&self) -> Checkpoint {
+        self.render_state
+            .checkpoint
+            .lock()
+            .expect("Poisoned lock!")
+            .clone()
+    }
 }
 
+#[derive(Copy, Clone, Debug)]
 pub struct RenderProgressSnapshot {
     pub finished: usize,
     pub total: usize,
@@ -191,20 +208,109 @@ impl RenderProgressSnapshot {
 struct RenderState<O: Object> {
     scene: Arc<Scene<O>>,
     settings: RenderSettings,
+    events: RenderEvents,
 
     image: Mutex<RgbaImage>,
+    checkpoint: Mutex<Checkpoint>,
 
     tile_ordering: Vec<ScreenBlock>,
     next_tile_index: AtomicUsize,
+    /// Number of tiles that got all of their samples.
+    finished_tile_count: AtomicUsize,
+    aborted: AtomicBool,
 
     start_time: Instant,
+    deadline: Option<Instant>,
     /// Number of workers that finished, elapsed time
     end: Mutex<(usize, Option<Duration>)>,
+    /// Merged stats of the workers that finished.
+    stats: Mutex<RenderStats>,
 }
 
 impl<O: Object> RenderState<O> {
-    fn get_next_tile(&self) -> (usize, Option<&ScreenBlock>) {
+    fn get_next_tile(&self) -> Option<&ScreenBlock> {
         let id = self.next_tile_index.fetch_add(1, Ordering::AcqRel);
-        (id, self.tile_ordering.get(id))
+        self.tile_ordering.get(id)
+    }
+
+    fn progress(&self) -> RenderProgressSnapshot {
+        RenderProgressSnapshot {
+            finished: self.finished_tile_count.load(Ordering::Acquire),
+            total: self.tile_ordering.len(),
+        }
+    }
+}
+
+impl<O: Object + Sync> RenderState<O> {
+    /// Renders tiles until there are none left or the render is stopped.
+    fn run_worker(&self, worker: &mut Worker<O>) {
+        let stop = StopCondition {
+            abort: Some(&self.aborted),
+            deadline: self.deadline,
+        };
+        let mut tile_film = Film::new(ScreenBlock::new(
+            ScreenPoint::origin(),
+            ScreenPoint::origin(),
+        ));
+
+        while !stop.should_stop()
+            && let Some(tile) = self.get_next_tile()
+        {
+            self.events.send(RenderEvent::TileStarted {
+                block: tile.clone(),
+            });
+            let tile_start_time = Instant::now();
+
+            self.checkpoint
+                .lock()
+                .expect("Poisoned lock!")
+                .film()
+                .crop_into(tile, &mut tile_film);
+            let previous_samples = tile_film.total_sample_count();
+
+            let tile_finished =
+                worker.render_tile(&self.scene, &self.settings, &mut tile_film, &stop);
+
+            self.checkpoint
+                .lock()
+                .expect("Poisoned lock!")
+                .update_tile(&tile_film, tile_finished);
+            tile_film.develop_into(&mut self.image.lock().expect("Poisoned lock!"));
+
+            let samples = tile_film.total_sample_count() - previous_samples;
+            if tile_finished {
+                self.finished_tile_count.fetch_add(1, Ordering::AcqRel);
+            }
+
+            self.events.send(RenderEvent::TileFinished {
+                block: tile.clone(),
+                samples,
+                elapsed: tile_start_time.elapsed(),
+                progress: self.progress(),
+            });
+        }
+    }
+
+    /// Records that a worker has no more work, the last worker sends the final events.
+    fn worker_finished(&self, worker_count: usize, worker_stats: RenderStats) {
+        let elapsed = self.start_time.elapsed();
+        let mut lock = self.end.lock().expect("Poisoned lock!");
+        let mut stats = self.stats.lock().expect("Poisoned lock!");
+        *stats = stats.merge(&worker_stats);
+
+        lock.0 += 1;
+        if lock.0 != worker_count {
+            return;
+        }
+        lock.1 = Some(elapsed);
+
+        stats.elapsed = elapsed;
+        stats.stopped = stats.finished_tiles < self.tile_ordering.len();
+        if !stats.stopped {
+            self.events.send(RenderEvent::PassFinished);
+        }
+        self.events.send(RenderEvent::RenderFinished {
+            stats: stats.clone(),
+        });
     }
 }
@@ -1,8 +1,28 @@
+mod aov;
+mod checkpoint;
+mod denoise;
+pub mod distributed;
+pub(crate) mod encoding;
+mod events;
+mod film;
 mod machinery;
+mod shading;
+mod stats;
+mod tonemap;
 mod worker;
 
+use std::time::Duration;
+
 use crate::geometry::ScreenSize;
-pub use crate::renderer::machinery::{RenderProgress, render};
+pub use crate::renderer::aov::{Aov, UnknownAov};
+pub use crate::renderer::checkpoint::{Checkpoint, CheckpointError};
+pub use crate::renderer::denoise::{DenoiseSettings, denoise};
+pub use crate::renderer::events::{RenderEvent, RenderEvents};
+pub use crate::renderer::film::Film;
+pub use crate::renderer::machinery::{RenderProgress, RenderProgressSnapshot, render, resume};
+pub use crate::renderer::shading::{ShadingMode, UnknownShadingMode};
+pub use crate::renderer::stats::RenderStats;
+pub use crate::renderer::tonemap::{DisplaySettings, ToneMap};
 
 #[derive(Copy, Clone, Debug)]
 pub struct RenderSettings {
@@ -10,4 +30,9 @@ pub struct RenderSettings {
     pub sample_count: std::num::NonZeroU32,
 
     pub resolution: ScreenSize,
+    pub shading_mode: ShadingMode,
+
+    /// Stop the render once this much time has elapsed since its start.
+    /// Not sent to distributed workers or stored in checkpoints.
+    pub time_limit: Option<Duration>,
 }
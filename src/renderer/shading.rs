@@ -0,0 +1,192 @@
+use std::{fmt::Display, str::FromStr};
+
+use thiserror::Error;
+
+use crate::{
+    geometry::{HitRecord, Ray, WorldVector},
+    scene::{Object, Scene, triangle_bvh::RayTraversal},
+    util::Rgba,
+};
+
+/// Number of inner nodes per ray that maps to the hot end of the heatmap.
+const HEATMAP_MAX_INNER_NODES: usize = 64;
+/// Number of triangle packets per ray that maps to the hot end of the heatmap.
+const HEATMAP_MAX_TRIANGLE_PACKETS: usize = 64;
+
+/// What gets rendered for each sample.
+/// All modes except `Shaded` are meant for debugging scenes and the BVH.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ShadingMode {
+    #[default]
+    Shaded,
+    GeometricNormal,
+    ShadingNormal,
+    TextureCoords,
+    MaterialId,
+    HitDistance,
+    TriangleIndex,
+    InnerNodeHeatmap,
+    TrianglePacketHeatmap,
+}
+
+#[derive(Debug, Error)]
+#[error("Unknown shading mode {0}")]
+pub struct UnknownShadingMode(String);
+
+impl ShadingMode {
+    pub const ALL: [ShadingMode; 9] = [
+        ShadingMode::Shaded,
+        ShadingMode::GeometricNormal,
+        ShadingMode::ShadingNormal,
+        ShadingMode::TextureCoords,
+        ShadingMode::MaterialId,
+        ShadingMode::HitDistance,
+        ShadingMode::TriangleIndex,
+        ShadingMode::InnerNodeHeatmap,
+        ShadingMode::TrianglePacketHeatmap,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShadingMode::Shaded => "shaded",
+            ShadingMode::GeometricNormal => "geometric-normal",
+            ShadingMode::ShadingNormal => "shading-normal",
+            ShadingMode::TextureCoords => "uv",
+            ShadingMode::MaterialId => "material-id",
+            ShadingMode::HitDistance => "distance",
+            ShadingMode::TriangleIndex => "triangle-index",
+            ShadingMode::InnerNodeHeatmap => "inner-node-heatmap",
+            ShadingMode::TrianglePacketHeatmap => "triangle-packet-heatmap",
+        }
+    }
+
+    /// Calculates color of a sample from the closest hit of the ray (if any) and
+    /// from the BVH nodes visited while tracing it.
+    pub fn shade<O: Object>(
+        &self,
+        scene: &Scene<O>,
+        ray: &Ray,
+        hit: Option<&HitRecord>,
+        traversal: &RayTraversal,
+    ) -> Rgba {
+        match (self, hit) {
+            (ShadingMode::InnerNodeHeatmap, _) => {
+                heatmap_color(traversal.inner_nodes, HEATMAP_MAX_INNER_NODES)
+            }
+            (ShadingMode::TrianglePacketHeatmap, _) => {
+                heatmap_color(traversal.triangle_packets, HEATMAP_MAX_TRIANGLE_PACKETS)
+            }
+            (_, None) => Rgba::new(0.0, 0.0, 0.0, 0.0),
+            (ShadingMode::Shaded, Some(hit)) => {
+                let dot = ray.direction.dot(&hit.normal).abs();
+                let albedo = scene.material(hit.material).albedo;
+                Rgba::new(albedo.r * dot, albedo.g * dot, albedo.b * dot, 1.0)
+            }
+            (ShadingMode::GeometricNormal, Some(hit)) => normal_color(&hit.geometric_normal),
+            (ShadingMode::ShadingNormal, Some(hit)) => normal_color(&hit.normal),
+            (ShadingMode::TextureCoords, Some(hit)) => Rgba::new(
+                hit.texture_coords.x.fract(),
+                hit.texture_coords.y.fract(),
+                0.0,
+                1.0,
+            ),
+            (ShadingMode::MaterialId, Some(hit)) => id_color(hit.material),
+            (ShadingMode::HitDistance, Some(hit)) => {
+                // Scaled by the scene size, so that the falloff is visible regardless of units
+                let scale = scene.object.get_bounding_box().size().norm();
+                let v = (-hit.t / scale).exp();
+                Rgba::new(v, v, v, 1.0)
+            }
+            (ShadingMode::TriangleIndex, Some(hit)) => id_color(hit.triangle_index),
+        }
+    }
+}
+
+impl Display for ShadingMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ShadingMode {
+    type Err = UnknownShadingMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ShadingMode::ALL
+            .into_iter()
+            .find(|mode| mode.name() == s)
+            .ok_or_else(|| UnknownShadingMode(s.to_owned()))
+    }
+}
+
+/// Maps components of a unit vector from -1..1 to 0..1.
+fn normal_color(normal: &WorldVector) -> Rgba {
+    Rgba::new(
+        normal.x * 0.5 + 0.5,
+        normal.y * 0.5 + 0.5,
+        normal.z * 0.5 + 0.5,
+        1.0,
+    )
+}
+
+/// Arbitrary but stable color for an id, neighboring ids get very different colors.
+fn id_color(id: usize) -> Rgba {
+    let hash = (id as u32).wrapping_mul(0x9e37_79b9);
+    let [r, g, b, _] = hash.to_le_bytes();
+    Rgba::new(
+        f32::from(r) / 255.0,
+        f32::from(g) / 255.0,
+        f32::from(b) / 255.0,
+        1.0,
+    )
+}
+
+/// Blue - cyan - green - yellow - red color ramp, with `max` and above being red.
+fn heatmap_color(value: usize, max: usize) -> Rgba {
+    const STOPS: [(f32, f32, f32); 5] = [
+        (0.0, 0.0, 1.0),
+        (0.0, 1.0, 1.0),
+        (0.0, 1.0, 0.0),
+        (1.0, 1.0, 0.0),
+        (1.0, 0.0, 0.0),
+    ];
+
+    let x = (value as f32 / max as f32).clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let i = (x as usize).min(STOPS.len() - 2);
+    let f = x - i as f32;
+    let (a, b) = (STOPS[i], STOPS[i + 1]);
+
+    Rgba::new(
+        a.0 + (b.0 - a.0) * f,
+        a.1 + (b.1 - a.1) * f,
+        a.2 + (b.2 - a.2) * f,
+        1.0,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    #[test]
+    fn names_round_trip() {
+        for mode in ShadingMode::ALL {
+            assert!(mode.name().parse::<ShadingMode>().unwrap() == mode);
+        }
+        assert!("nonsense".parse::<ShadingMode>().is_err());
+    }
+
+    #[test]
+    fn heatmap_ends() {
+        assert!(heatmap_color(0, 10) == Rgba::new(0.0, 0.0, 1.0, 1.0));
+        assert!(heatmap_color(5, 10) == Rgba::new(0.0, 1.0, 0.0, 1.0));
+        assert!(heatmap_color(10, 10) == Rgba::new(1.0, 0.0, 0.0, 1.0));
+        assert!(heatmap_color(1000, 10) == Rgba::new(1.0, 0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn normal_colors() {
+        assert!(normal_color(&WorldVector::new(1.0, 0.0, -1.0)) == Rgba::new(1.0, 0.5, 0.0, 1.0));
+    }
+}
@@ -0,0 +1,131 @@
+use std::{fmt::Display, time::Duration};
+
+use crate::{scene::triangle_bvh::TraversalStats, util::Stats};
+
+/// Counters collected while rendering.
+/// Every worker collects its own stats, they get merged when the render ends.
+#[derive(Clone, Debug, Default)]
+pub struct RenderStats {
+    /// Tiles that got all of their samples.
+    pub finished_tiles: usize,
+    pub samples: u64,
+
+    /// Rays shot from the camera.
+    pub primary_rays: u64,
+    /// BVH nodes tested by all rays.
+    pub traversal: TraversalStats,
+
+    /// Render times of tiles, in microseconds.
+    pub tile_times: Stats,
+
+    /// Wall clock time of the whole render.
+    pub elapsed: Duration,
+    /// The render was aborted or ran out of time before finishing all tiles.
+    pub stopped: bool,
+}
+
+impl RenderStats {
+    /// Millions of rays traced per second of wall clock time.
+    pub fn mrays_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.primary_rays as f64 / seconds / 1e6
+        } else {
+            0.0
+        }
+    }
+
+    pub fn add_tile_time(&mut self, time: Duration) {
+        self.tile_times.add_sample(time.as_micros() as usize);
+    }
+
+    /// Combines stats of two parts of the same render, running at the same time.
+    pub fn merge(&self, other: &Self) -> Self {
+        RenderStats {
+            finished_tiles: self.finished_tiles + other.finished_tiles,
+            samples: self.samples + other.samples,
+            primary_rays: self.primary_rays + other.primary_rays,
+            traversal: self.traversal.merge(&other.traversal),
+            tile_times: self.tile_times.merge(&other.tile_times),
+            elapsed: self.elapsed.max(other.elapsed),
+            stopped: self.stopped || other.stopped,
+        }
+    }
+}
+
+impl Display for RenderStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Finished tiles: {}{}",
+            self.finished_tiles,
+            if self.stopped { " (stopped early)" } else { "" }
+        )?;
+        writeln!(f, "Samples: {}", self.samples)?;
+        writeln!(
+            f,
+            "Rays: {}; {:.2} Mrays/s",
+            self.primary_rays,
+            self.mrays_per_second()
+        )?;
+        writeln!(
+            f,
+            "Inner nodes per ray: {:.1}",
+            self.traversal.inner_nodes_per_ray()
+        )?;
+        writeln!(
+            f,
+            "Triangle packets per ray: {:.1}",
+            self.traversal.triangle_packets_per_ray()
+        )?;
+        writeln!(f, "Tile time [us]: {}", self.tile_times)?;
+        write!(f, "Elapsed: {:.2?}", self.elapsed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    #[test]
+    fn merge() {
+        let mut a = RenderStats {
+            finished_tiles: 2,
+            samples: 10,
+            primary_rays: 10,
+            elapsed: Duration::from_secs(1),
+            ..RenderStats::default()
+        };
+        a.add_tile_time(Duration::from_micros(100));
+        let mut b = RenderStats {
+            finished_tiles: 1,
+            samples: 5,
+            primary_rays: 5,
+            elapsed: Duration::from_secs(2),
+            stopped: true,
+            ..RenderStats::default()
+        };
+        b.add_tile_time(Duration::from_micros(300));
+
+        let merged = a.merge(&b);
+
+        assert!(merged.finished_tiles == 3);
+        assert!(merged.samples == 15);
+        assert!(merged.primary_rays == 15);
+        assert!(merged.tile_times == Stats::new_single(100).merge(&Stats::new_single(300)));
+        assert!(merged.elapsed == Duration::from_secs(2));
+        assert!(merged.stopped);
+    }
+
+    #[test]
+    fn mrays_per_second() {
+        let stats = RenderStats {
+            primary_rays: 3_000_000,
+            elapsed: Duration::from_secs(2),
+            ..RenderStats::default()
+        };
+        assert!(stats.mrays_per_second() == 1.5);
+        assert!(RenderStats::default().mrays_per_second() == 0.0);
+    }
+}
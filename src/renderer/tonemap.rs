@@ -0,0 +1,97 @@
+use crate::util::Rgba;
+
+/// Curve that maps scene radiance to the displayable range.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ToneMap {
+    /// Values above 1 are cut off, same as when saving the image.
+    #[default]
+    Clamp,
+    /// x / (1 + x), compresses highlights while keeping the darks mostly linear.
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve.
+    Filmic,
+}
+
+impl ToneMap {
+    pub const ALL: [ToneMap; 3] = [ToneMap::Clamp, ToneMap::Reinhard, ToneMap::Filmic];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ToneMap::Clamp => "clamp",
+            ToneMap::Reinhard => "reinhard",
+            ToneMap::Filmic => "filmic",
+        }
+    }
+
+    fn apply(&self, x: f32) -> f32 {
+        match self {
+            ToneMap::Clamp => x,
+            ToneMap::Reinhard => x / (1.0 + x),
+            ToneMap::Filmic => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+        }
+    }
+}
+
+/// How the rendered radiance gets converted for display.
+/// The default settings give the same result as the saved images.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DisplaySettings {
+    /// Exposure adjustment in stops, every stop doubles the brightness.
+    pub exposure: f32,
+    pub tone_map: ToneMap,
+}
+
+impl DisplaySettings {
+    /// Converts a pixel to 8bit, alpha is kept as is.
+    pub fn apply(&self, color: Rgba) -> image::Rgba<u8> {
+        let scale = self.exposure.exp2();
+        let channel = |x: f32| {
+            let mapped = self.tone_map.apply((x * scale).max(0.0));
+            (mapped * 255.0).round().clamp(0.0, 255.0) as u8
+        };
+        image::Rgba([
+            channel(color.r),
+            channel(color.g),
+            channel(color.b),
+            (color.a * 255.0).round().clamp(0.0, 255.0) as u8,
+        ])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::renderer::worker::color_to_image;
+    use assert2::assert;
+
+    #[test]
+    fn default_matches_saved_image() {
+        for value in [0.0, 0.1, 0.5, 0.999, 1.0, 3.0] {
+            let color = Rgba::new(value, value / 2.0, 1.0 - value, 1.0);
+            assert!(DisplaySettings::default().apply(color) == color_to_image(color));
+        }
+    }
+
+    #[test]
+    fn exposure_doubles_per_stop() {
+        let settings = DisplaySettings {
+            exposure: 1.0,
+            ..DisplaySettings::default()
+        };
+        let color = Rgba::new(0.25, 0.25, 0.25, 1.0);
+        assert!(settings.apply(color) == color_to_image(Rgba::new(0.5, 0.5, 0.5, 1.0)));
+    }
+
+    #[test]
+    fn curves_are_monotonic() {
+        for tone_map in ToneMap::ALL {
+            assert!(tone_map.apply(0.0) == 0.0);
+            let mut previous = 0.0;
+            for i in 0..1000 {
+                let value = tone_map.apply(i as f32 * 0.1);
+                assert!(value >= previous);
+                previous = value;
+            }
+        }
+    }
+}
@@ -1,13 +1,16 @@
-use std::marker::PhantomData;
+use std::{
+    marker::PhantomData,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
 
-use image::RgbaImage;
 use rand::{SeedableRng, rngs::SmallRng};
 
 use crate::scene::triangle_bvh;
 use crate::{
     camera::CameraSampler,
-    geometry::{ScreenBlock, ScreenPoint},
-    renderer::RenderSettings,
+    geometry::ScreenPoint,
+    renderer::{RenderSettings, aov::AovSample, film::Film, stats::RenderStats},
     scene::{Object, Scene},
     util::Rgba,
 };
@@ -16,6 +19,7 @@ pub struct Worker<O: Object> {
     rng: SmallRng,
     bvh_stack_cache: triangle_bvh::StackCache,
     camera_sampler: CameraSampler,
+    stats: RenderStats,
     _phantom: PhantomData<O>,
 }
 
@@ -25,46 +29,121 @@ impl<O: Object + Sync> Worker<O> {
             rng: SmallRng::from_os_rng(),
             bvh_stack_cache: Default::default(),
             camera_sampler,
+            stats: RenderStats::default(),
             _phantom: Default::default(),
         }
     }
 
+    /// Renders samples into the film until every pixel has the sample count from the settings.
+    /// Samples already present in the film are kept.
+    ///
+    /// Samples are rendered in passes over the whole tile and the stop condition is checked
+    /// before every scanline, so a stopped tile has all of its pixels within one sample
+    /// of each other.
+    /// Returns false if the rendering was stopped before finishing the tile.
     pub fn render_tile(
         &mut self,
         scene: &Scene<O>,
         settings: &RenderSettings,
-        tile: &ScreenBlock,
-        buffer: &mut RgbaImage,
-    ) {
-        for point in tile.internal_points() {
-            let mut pixel_sum = Rgba::new(0.0, 0.0, 0.0, 0.0);
-            for _i in 0..settings.sample_count.get() {
-                pixel_sum += self.render_sample(scene, settings, &point);
-            }
-            let pixel = pixel_sum * (1.0 / settings.sample_count.get() as f32);
+        film: &mut Film,
+        stop: &StopCondition,
+    ) -> bool {
+        let start_time = Instant::now();
+        let previous_samples = film.total_sample_count();
+
+        let finished = self.render_passes(scene, settings, film, stop);
+
+        self.stats.finished_tiles += finished as usize;
+        self.stats.samples += film.total_sample_count() - previous_samples;
+        self.stats.add_tile_time(start_time.elapsed());
+        self.stats.traversal = self
+            .stats
+            .traversal
+            .merge(&self.bvh_stack_cache.take_stats());
+
+        finished
+    }
+
+    /// Returns stats collected since the last call, and resets them.
+    pub fn take_stats(&mut self) -> RenderStats {
+        std::mem::take(&mut self.stats)
+    }
+
+    fn render_passes(
+        &mut self,
+        scene: &Scene<O>,
+        settings: &RenderSettings,
+        film: &mut Film,
+        stop: &StopCondition,
+    ) -> bool {
+        let target = settings.sample_count.get();
+        let block = film.block().clone();
+        let min_count = film.sample_counts().iter().copied().min().unwrap_or(target);
 
-            let buffer_position = point - tile.min;
-            buffer.put_pixel(buffer_position.x, buffer_position.y, color_to_image(pixel));
+        for _pass in min_count..target {
+            for y in block.min.y..block.max.y {
+                if stop.should_stop() {
+                    return false;
+                }
+                for x in block.min.x..block.max.x {
+                    let point = ScreenPoint::new(x, y);
+                    if film.sample_count(&point) < target {
+                        self.render_sample(scene, settings, film, &point);
+                    }
+                }
+            }
         }
+
+        true
     }
 
+    /// Renders a single sample of a pixel into the film, together with its AOVs.
     fn render_sample(
         &mut self,
         scene: &Scene<O>,
-        _settings: &RenderSettings,
+        settings: &RenderSettings,
+        film: &mut Film,
         point: &ScreenPoint,
-    ) -> Rgba {
+    ) {
         let ray = self.camera_sampler.sample_ray(point, &mut self.rng);
+        self.stats.primary_rays += 1;
 
-        if let Some(intersection) = scene.object.intersect(&ray, &mut self.bvh_stack_cache) {
-            let dot = ray.direction.dot(&intersection.normal).abs();
-            Rgba::new(dot, dot, dot, 1.0)
-        } else {
-            Rgba::new(0.0, 0.0, 0.0, 0.0)
+        let hit = scene.object.intersect(&ray, &mut self.bvh_stack_cache);
+        let color = settings.shading_mode.shade(
+            scene,
+            &ray,
+            hit.as_ref(),
+            &self.bvh_stack_cache.last_ray(),
+        );
+
+        film.add_sample(point, color);
+        if let Some(hit) = &hit {
+            let depth = self.camera_sampler.depth(&hit.point);
+            film.add_aov_sample(
+                point,
+                &AovSample::new(hit, &scene.material(hit.material), depth),
+            );
         }
     }
 }
 
+/// Decides when a worker should stop rendering a tile before it is finished.
+#[derive(Default)]
+pub struct StopCondition<'a> {
+    pub abort: Option<&'a AtomicBool>,
+    pub deadline: Option<Instant>,
+}
+
+impl StopCondition<'_> {
+    pub fn should_stop(&self) -> bool {
+        self.abort
+            .is_some_and(|abort| abort.load(Ordering::Relaxed))
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
 /// Maps a 0-1 f32 rgba pixel to pixel type compatible with module image.
 pub fn color_to_image(color: Rgba) -> image::Rgba<u8> {
     image::Rgba([
@@ -74,3 +153,93 @@ pub fn color_to_image(color: Rgba) -> image::Rgba<u8> {
         (color.a * 255.0).round().clamp(0.0, 255.0) as u8,
     ])
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        camera::Camera,
+        geometry::{ScreenBlock, ScreenSize, WorldPoint},
+        renderer::ShadingMode,
+        scene::primitives::Sphere,
+    };
+    use assert2::assert;
+
+    fn test_setup() -> (Scene<Sphere>, RenderSettings, Worker<Sphere>) {
+        let scene = Scene::new(Sphere {
+            center: WorldPoint::new(0.0, 0.0, -5.0),
+            radius: 1.0,
+        });
+        let settings = RenderSettings {
+            tile_size: 8.try_into().unwrap(),
+            sample_count: 3.try_into().unwrap(),
+            resolution: ScreenSize::new(16, 16),
+            shading_mode: ShadingMode::Shaded,
+            time_limit: None,
+        };
+        let worker = Worker::new(0, Camera::default().build_sampler(settings.resolution));
+        (scene, settings, worker)
+    }
+
+    #[test]
+    fn render_tile_completes_partial_film() {
+        let (scene, settings, mut worker) = test_setup();
+        let mut film = Film::new(ScreenBlock::new([4, 4].into(), [12, 12].into()));
+        film.add_sample(&ScreenPoint::new(5, 5), Rgba::default());
+
+        let finished = worker.render_tile(&scene, &settings, &mut film, &StopCondition::default());
+
+        assert!(finished);
+        assert!(film.sample_counts().iter().all(|&count| count == 3));
+        assert!(film.aovs().iter().any(|aov| aov.hit_count == 3));
+
+        let stats = worker.take_stats();
+        assert!(stats.finished_tiles == 1);
+        assert!(stats.samples == 8 * 8 * 3 - 1);
+        assert!(stats.primary_rays == stats.samples);
+        assert!(stats.tile_times.count == 1);
+        assert!(worker.take_stats().samples == 0);
+    }
+
+    #[test]
+    fn material_albedo_is_rendered() {
+        let (mut scene, settings, mut worker) = test_setup();
+        scene.materials[0].albedo = rgb::RGB::new(1.0, 0.5, 0.0);
+        let mut film = Film::new(ScreenBlock::new([4, 4].into(), [12, 12].into()));
+
+        worker.render_tile(&scene, &settings, &mut film, &StopCondition::default());
+
+        for (sum, aov) in film.sums().iter().zip(film.aovs()) {
+            assert!(sum.b == 0.0);
+            assert!(sum.g * 2.0 == sum.r);
+            assert!(aov.albedo == rgb::RGB::new(1.0, 0.5, 0.0) * aov.hit_count as f32);
+        }
+        assert!(film.aovs().iter().any(|aov| aov.hit_count > 0));
+    }
+
+    #[test]
+    fn aborted_tile_is_left_untouched() {
+        let (scene, settings, mut worker) = test_setup();
+        let mut film = Film::new(ScreenBlock::new([4, 4].into(), [12, 12].into()));
+        let abort = AtomicBool::new(true);
+        let stop = StopCondition {
+            abort: Some(&abort),
+            deadline: None,
+        };
+
+        let finished = worker.render_tile(&scene, &settings, &mut film, &stop);
+
+        assert!(!finished);
+        assert!(film.sample_counts().iter().all(|&count| count == 0));
+    }
+
+    #[test]
+    fn expired_deadline_stops() {
+        let stop = StopCondition {
+            abort: None,
+            deadline: Some(Instant::now()),
+        };
+        assert!(stop.should_stop());
+        assert!(!StopCondition::default().should_stop());
+    }
+}
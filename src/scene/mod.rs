@@ -1,6 +1,8 @@
 pub mod primitives;
 pub mod triangle_bvh;
 
+use std::sync::Arc;
+
 use crate::geometry::{HitRecord, Ray, WorldBox};
 
 /// Renderable object
@@ -9,7 +11,40 @@ pub trait Object {
     fn get_bounding_box(&self) -> WorldBox;
 }
 
+/// Geometry with the materials that its hits refer to by material ID.
+/// The object is shared, so that materials can be edited without copying the geometry.
 #[derive(Clone)]
 pub struct Scene<O: Object> {
-    pub object: O,
+    pub object: Arc<O>,
+    /// Indexed by material ID, IDs past the end use the default material.
+    pub materials: Vec<Material>,
+}
+
+impl<O: Object> Scene<O> {
+    /// Scene with a single default material.
+    pub fn new(object: O) -> Self {
+        Scene {
+            object: Arc::new(object),
+            materials: vec![Material::default()],
+        }
+    }
+
+    pub fn material(&self, id: usize) -> Material {
+        self.materials.get(id).copied().unwrap_or_default()
+    }
+}
+
+/// Surface properties looked up by the material ID of a hit.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Material {
+    /// Fraction of light reflected, per color channel.
+    pub albedo: rgb::RGB<f32>,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Material {
+            albedo: rgb::RGB::new(1.0, 1.0, 1.0),
+        }
+    }
 }
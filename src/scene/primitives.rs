@@ -41,7 +41,9 @@ impl Object for Sphere {
             t,
             point,
             normal,
+            geometric_normal: normal,
             material: 0,
+            triangle_index: 0,
             texture_coords: TexturePoint::origin(), // TODO?
         })
     }
@@ -1,9 +1,14 @@
-use std::{array, ops::Range, path::Path};
+use std::{
+    ops::Range,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
 
 use crate::{
     geometry::{
-        AABB, SimdMaskType, TexturePoint, Triangle, WorldBox, WorldBoxSized8, WorldPoint,
-        WorldPoint8, WorldVector,
+        AABB, FloatType, SimdMaskType, TexturePoint, Triangle, WorldBox, WorldBox8, WorldBoxSized8,
+        WorldPoint, WorldPoint8, WorldVector,
     },
     scene::triangle_bvh::TriangleShadingData,
     util::simba::simd_windows,
@@ -14,26 +19,45 @@ use assert2::assert;
 use index_vec::IndexVec;
 use indexmap::IndexMap;
 use itertools::Itertools as _;
-use nalgebra::Vector3;
+use nalgebra::{Vector2, Vector3};
+use ordered_float::OrderedFloat;
 use simba::simd::SimdValue as _;
 use thiserror::Error;
 
 use super::{
     CompressedNodeLink, INNER_NODE_CHILDREN, InnerNode, LEAF_NODE_MAX_TRIANGLES,
-    LEAF_NODE_PACKET_SIZE, TriangleBvh,
+    LEAF_NODE_PACKET_SIZE, LEAF_NODE_TARGET_TRIANGLES, TriangleBvh,
     compressed_geometry::{RelativeBox8, RelativeTriangle8},
+    spatial_split::Reference,
 };
 
 impl TriangleBvh {
     pub fn with_obj(p: impl AsRef<Path>) -> Result<TriangleBvh, ObjOpenError> {
+        Self::with_obj_progress(p, &BuildProgress::default())
+    }
+
+    /// Same as `with_obj`, but reports how far the build got, so that it can be watched from
+    /// another thread.
+    pub fn with_obj_progress(
+        p: impl AsRef<Path>,
+        progress: &BuildProgress,
+    ) -> Result<TriangleBvh, ObjOpenError> {
+        Self::with_obj_split_mode(p, SplitMode::default(), progress)
+    }
+
+    pub fn with_obj_split_mode(
+        p: impl AsRef<Path>,
+        split_mode: SplitMode,
+        progress: &BuildProgress,
+    ) -> Result<TriangleBvh, ObjOpenError> {
         let parsed = obj::Obj::load(p)?;
 
         let (triangles, vertices) = Self::load_obj(parsed);
 
-        Ok(Self::build(triangles, vertices))
+        Ok(Self::build_mesh(triangles, vertices, split_mode, progress))
     }
 
-    fn load_obj(obj: obj::Obj) -> (Vec<Triangle<usize>>, Vec<VertexData>) {
+    pub(super) fn load_obj(obj: obj::Obj) -> (Vec<MeshTriangle>, Vec<VertexData>) {
         let mut triangles = Vec::new();
         let mut vertices = IndexMap::new();
 
@@ -70,7 +94,11 @@ impl TriangleBvh {
                     let b = handle_vertex(b);
                     let c = handle_vertex(c);
 
-                    triangles.push(Triangle::new(a, b, c));
+                    triangles.push(MeshTriangle {
+                        vertices: Triangle::new(a, b, c),
+                        material: 0,
+                        source_index: triangles.len(),
+                    });
                 }
             }
         }
@@ -80,21 +108,59 @@ impl TriangleBvh {
         (triangles, vertices)
     }
 
-    pub fn build(mut triangles: Vec<Triangle<usize>>, vertices: Vec<VertexData>) -> TriangleBvh {
+    /// Builds the tree from triangles that carry their material.
+    pub(super) fn build_mesh(
+        triangles: Vec<MeshTriangle>,
+        vertices: Vec<VertexData>,
+        split_mode: SplitMode,
+        progress: &BuildProgress,
+    ) -> TriangleBvh {
+        Self::build_internal(
+            triangles,
+            vertices,
+            split_mode,
+            progress,
+            PARALLEL_BUILD_MIN_TRIANGLES,
+        )
+    }
+
+    /// Builds the tree, nodes with at least `parallel_min_triangles` triangles get their
+    /// binning and children built on multiple threads (object splits only).
+    /// The result doesn't depend on the threshold, nor on how many threads were available.
+    fn build_internal(
+        mut triangles: Vec<MeshTriangle>,
+        vertices: Vec<VertexData>,
+        split_mode: SplitMode,
+        progress: &BuildProgress,
+        parallel_min_triangles: usize,
+    ) -> TriangleBvh {
+        progress.total.store(triangles.len(), Ordering::Relaxed);
+        progress.done.store(0, Ordering::Relaxed);
+
         let bounding_box =
             WorldBox::from_points(vertices_iter(&triangles, &vertices)).unwrap_or_default();
 
-        let mut bvh = TriangleBvh {
-            bounding_box: bounding_box.clone(),
-            root: CompressedNodeLink::default(),
-
-            inner_nodes: IndexVec::new(),
-            triangle_geometry: IndexVec::new(),
-            triangle_shading_data: IndexVec::new(),
-            vertex_data: IndexVec::new(),
+        let mut bvh = TriangleBvh::empty(bounding_box.clone());
+        let thread_count = num_cpus::get();
+        let context = BuildContext {
+            vertices: &vertices,
+            progress,
+            parallel_min_triangles,
+            spare_threads: AtomicUsize::new(thread_count - 1),
         };
-
-        bvh.root = bvh.build_recursive(&mut triangles, &vertices, &bounding_box);
+        if !triangles.is_empty() {
+            bvh.root = match split_mode {
+                SplitMode::Object => bvh.build_recursive(&mut triangles, &bounding_box, &context),
+                SplitMode::Spatial => {
+                    let references = triangles
+                        .into_iter()
+                        .map(|triangle| Reference::new(triangle, &vertices))
+                        .collect();
+                    bvh.build_spatial_recursive(references, &bounding_box, &context)
+                }
+            };
+        }
+        debug_assert!(context.spare_threads.into_inner() == thread_count - 1);
         bvh.vertex_data = vertices
             .into_iter()
             .map(|v| super::VertexShadingData {
@@ -102,61 +168,132 @@ impl TriangleBvh {
                 texture_coords: v.tex,
             })
             .collect();
+        bvh.split_mode = split_mode;
+        bvh.build_cost = bvh.sah_cost();
 
         bvh
     }
 
+    /// Tree without any nodes, used as a container for separately built subtrees.
+    fn empty(bounding_box: WorldBox) -> TriangleBvh {
+        TriangleBvh {
+            bounding_box,
+            root: CompressedNodeLink::default(),
+
+            inner_nodes: IndexVec::new(),
+            triangle_geometry: IndexVec::new(),
+            triangle_shading_data: IndexVec::new(),
+            vertex_data: IndexVec::new(),
+
+            split_mode: SplitMode::default(),
+            build_cost: 0.0,
+        }
+    }
+
     fn build_recursive(
         &mut self,
-        triangles: &mut [Triangle<usize>],
-        vertices: &[VertexData],
+        triangles: &mut [MeshTriangle],
         enclosing_box: &WorldBox,
+        context: &BuildContext,
     ) -> CompressedNodeLink {
-        if triangles.len() <= LEAF_NODE_MAX_TRIANGLES {
-            self.build_leaf(triangles, vertices, enclosing_box)
+        // Splitting triangles with coincident centroids doesn't make the leaves any smaller,
+        // these are better kept together if the links can store a leaf that big
+        if triangles.len() <= LEAF_NODE_TARGET_TRIANGLES
+            || (triangles.len() <= LEAF_NODE_MAX_TRIANGLES
+                && centroids_coincide(triangles, context.vertices))
+        {
+            let link = self.build_leaf(triangles, context.vertices, enclosing_box);
+            context
+                .progress
+                .done
+                .fetch_add(triangles.len(), Ordering::Relaxed);
+            link
         } else {
-            self.build_inner_node(triangles, vertices, enclosing_box)
+            self.build_inner_node(triangles, enclosing_box, context)
         }
     }
 
     fn build_inner_node(
         &mut self,
-        triangles: &mut [Triangle<usize>],
-        vertices: &[VertexData],
+        triangles: &mut [MeshTriangle],
         enclosing_box: &WorldBox,
+        context: &BuildContext,
     ) -> CompressedNodeLink {
-        let split_indices = split_triangles(triangles, vertices);
+        let parallel = triangles.len() >= context.parallel_min_triangles;
+        let extra_threads = if parallel {
+            context.reserve_threads(usize::MAX)
+        } else {
+            0
+        };
+        let split_indices = split_triangles(triangles, context.vertices, extra_threads + 1);
+        context.release_threads(extra_threads);
 
         // Create placeholder node that will be overwriten later
         self.inner_nodes.push(InnerNode::default());
         let node_index = self.inner_nodes.last_idx();
 
-        let enclosing_box = WorldBoxSized8::splat(enclosing_box.into());
-        let compressed_child_boxes = simd_windows(
+        let (compressed_child_boxes, decompressed_child_boxes) = compress_child_boxes(
             split_indices
                 .iter()
                 .map(|(_range, child_box)| child_box.clone()),
-        )
-        .map(|(child_boxes, mask)| {
-            RelativeBox8::compress_round_out(child_boxes, &enclosing_box, &mask)
-        })
-        .exactly_one()
-        .unwrap_or_else(|_| unreachable!());
-
-        // Compression is lossy, so the bounding box will change.
-        // We have to use the decompressed value for the children, same as what is used when
-        // traversing the tree
-        let decompressed_child_boxes = compressed_child_boxes.decompress(&enclosing_box);
+            enclosing_box,
+        );
 
         // Insert the children
-        let child_links = array::from_fn(|i| {
-            if let Some((range, _child_box)) = split_indices.get(i) {
-                let triangles = &mut triangles[range.clone()];
-                self.build_recursive(triangles, vertices, &decompressed_child_boxes.extract(i))
-            } else {
-                CompressedNodeLink::NULL
+        let children = split_indices
+            .iter()
+            .enumerate()
+            .map(|(i, (range, _child_box))| (range.clone(), decompressed_child_boxes.extract(i)));
+        let mut child_links = [CompressedNodeLink::NULL; INNER_NODE_CHILDREN];
+        if parallel {
+            // Children are built as separate trees and then appended in order, this gives
+            // the same layout as building them one after another, on whichever threads
+            let mut remaining = &mut *triangles;
+            let children: Vec<_> = children
+                .map(|(range, child_box)| {
+                    let (child_triangles, rest) =
+                        std::mem::take(&mut remaining).split_at_mut(range.len());
+                    remaining = rest;
+                    (child_triangles, child_box)
+                })
+                .collect();
+            let build_subtree = |(child_triangles, child_box): (&mut [MeshTriangle], WorldBox)| {
+                let mut subtree = TriangleBvh::empty(child_box.clone());
+                subtree.root = subtree.build_recursive(child_triangles, &child_box, context);
+                subtree
+            };
+
+            // This thread builds one of the children itself, the others get a thread only
+            // while the budget lasts, so that nested parallel nodes don't oversubscribe the CPU
+            let extra_threads = context.reserve_threads(children.len() - 1);
+            let subtrees = thread::scope(|scope| {
+                let mut children = children.into_iter();
+                let handles: Vec<_> = children
+                    .by_ref()
+                    .take(extra_threads)
+                    .map(|child| {
+                        scope.spawn(move || {
+                            let subtree = build_subtree(child);
+                            context.release_threads(1);
+                            subtree
+                        })
+                    })
+                    .collect();
+                let local: Vec<_> = children.map(build_subtree).collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("BVH build thread panicked"))
+                    .chain(local)
+                    .collect::<Vec<_>>()
+            });
+            for (link, subtree) in child_links.iter_mut().zip(subtrees) {
+                *link = self.append_subtree(subtree);
             }
-        });
+        } else {
+            for (link, (range, child_box)) in child_links.iter_mut().zip(children) {
+                *link = self.build_recursive(&mut triangles[range], &child_box, context);
+            }
+        }
 
         // Replace the placeholder with an actual inner node
         self.inner_nodes[node_index] = InnerNode {
@@ -167,9 +304,43 @@ impl TriangleBvh {
         CompressedNodeLink::new_inner(node_index)
     }
 
+    /// Moves nodes and triangles of a separately built tree to the end of this one,
+    /// returns link to its root.
+    fn append_subtree(&mut self, subtree: TriangleBvh) -> CompressedNodeLink {
+        let inner_offset = self.inner_nodes.len();
+        let packet_offset = self.triangle_geometry.len();
+
+        self.inner_nodes
+            .extend(subtree.inner_nodes.into_iter().map(|mut node| {
+                for link in &mut node.child_links {
+                    *link = link.offset(inner_offset, packet_offset);
+                }
+                node
+            }));
+        self.triangle_geometry.extend(subtree.triangle_geometry);
+        self.triangle_shading_data
+            .extend(subtree.triangle_shading_data);
+
+        subtree.root.offset(inner_offset, packet_offset)
+    }
+
     fn build_leaf(
         &mut self,
-        triangles: &[Triangle<usize>],
+        triangles: &[MeshTriangle],
+        vertices: &[VertexData],
+        enclosing_box: &WorldBox,
+    ) -> CompressedNodeLink {
+        let triangles: Vec<_> = triangles
+            .iter()
+            .map(|triangle| LeafTriangle::whole(triangle, vertices))
+            .collect();
+        self.build_leaf_parts(&triangles, vertices, enclosing_box)
+    }
+
+    /// Builds a leaf from triangles that may be just parts of triangles of the mesh.
+    pub(super) fn build_leaf_parts(
+        &mut self,
+        triangles: &[LeafTriangle],
         vertices: &[VertexData],
         enclosing_box: &WorldBox,
     ) -> CompressedNodeLink {
@@ -184,22 +355,25 @@ impl TriangleBvh {
             CompressedNodeLink::new_leaf(self.triangle_geometry.next_idx(), packet_count as u32);
 
         self.triangle_geometry.extend(
-            simd_windows(
-                triangles
-                    .iter()
-                    .map(|t: &Triangle<usize>| t.map(|i| vertices[*i].pos)),
-            )
-            .map(|(t, mask): (Triangle<WorldPoint8>, SimdMaskType)| {
-                RelativeTriangle8::compress(&t, &enclosing_box, &mask)
-            }),
+            simd_windows(triangles.iter().map(|t| t.positions.clone())).map(
+                |(t, mask): (Triangle<WorldPoint8>, SimdMaskType)| {
+                    RelativeTriangle8::compress(&t, &enclosing_box, &mask)
+                },
+            ),
         );
 
-        self.triangle_shading_data
-            .extend(triangles.iter().map(|t| TriangleShadingData {
-                vertex_indices: t.clone(),
-                flat_shading: t.iter().any(|i| vertices[*i].normal.norm_squared() == 0.0),
-                material: 0,
-            }));
+        self.triangle_shading_data.extend(triangles.iter().map(|t| {
+            TriangleShadingData {
+                vertex_indices: t.vertex_indices.clone(),
+                flat_shading: t
+                    .vertex_indices
+                    .iter()
+                    .any(|i| vertices[*i].normal.norm_squared() == 0.0),
+                material: t.material,
+                source_uv: t.source_uv.clone(),
+                source_index: t.source_index,
+            }
+        }));
         self.triangle_shading_data
             .extend((0..padding).map(|_| Default::default()));
 
@@ -216,52 +390,206 @@ pub enum ObjOpenError {
     ParseError(#[from] obj::ObjError),
 }
 
+/// How the builder divides triangles of a node between its children.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SplitMode {
+    /// Every triangle goes to exactly one child.
+    #[default]
+    Object,
+    /// SBVH-style splits, nodes may also be split by planes cutting through triangles, so that
+    /// long thin triangles don't make the child boxes overlap.
+    /// A triangle can then end up in several leaves. Slower to build and built on one thread.
+    Spatial,
+}
+
+/// Nodes with at least this many triangles are built using multiple threads.
+const PARALLEL_BUILD_MIN_TRIANGLES: usize = 16384;
+
+/// Parameters shared by the whole recursive build.
+pub(super) struct BuildContext<'a> {
+    pub vertices: &'a [VertexData],
+    pub progress: &'a BuildProgress,
+    parallel_min_triangles: usize,
+    /// How many more threads may be started, on top of the ones already building
+    spare_threads: AtomicUsize,
+}
+
+impl BuildContext<'_> {
+    /// Takes up to `wanted` threads from the budget, returns how many were taken.
+    fn reserve_threads(&self, wanted: usize) -> usize {
+        let spare = self
+            .spare_threads
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |spare| {
+                Some(spare - spare.min(wanted))
+            })
+            .unwrap_or_else(|_| unreachable!());
+        spare.min(wanted)
+    }
+
+    /// Returns threads that finished their work to the budget.
+    fn release_threads(&self, count: usize) {
+        self.spare_threads.fetch_add(count, Ordering::Relaxed);
+    }
+}
+
+/// Compresses boxes of up to 8 children relative to the node's box.
+/// Returns the compressed boxes and their decompressed version that is seen when traversing
+/// the tree and has to be used for building the children.
+pub(super) fn compress_child_boxes(
+    child_boxes: impl IntoIterator<Item = WorldBox>,
+    enclosing_box: &WorldBox,
+) -> (RelativeBox8, WorldBox8) {
+    let enclosing_box = WorldBoxSized8::splat(enclosing_box.into());
+    let compressed = simd_windows(child_boxes)
+        .map(|(child_boxes, mask)| {
+            RelativeBox8::compress_round_out(child_boxes, &enclosing_box, &mask)
+        })
+        .exactly_one()
+        .unwrap_or_else(|_| unreachable!());
+
+    // Compression is lossy, so the bounding box will change.
+    let decompressed = compressed.decompress(&enclosing_box);
+    (compressed, decompressed)
+}
+
+/// Progress of a BVH build, shared with the thread that does the building.
+#[derive(Debug, Default)]
+pub struct BuildProgress {
+    /// Number of triangles of the model being built
+    total: AtomicUsize,
+    /// Number of triangles already stored in leaves
+    pub(super) done: AtomicUsize,
+}
+
+impl BuildProgress {
+    /// Returns the fraction of triangles already stored in leaves, between 0 and 1.
+    /// Stays at zero while the model file is being loaded.
+    /// With spatial splits triangles are counted once per leaf, so this reaches 1 a bit early.
+    pub fn fraction(&self) -> f32 {
+        let total = self.total.load(Ordering::Relaxed);
+        if total == 0 {
+            0.0
+        } else {
+            (self.done.load(Ordering::Relaxed) as f32 / total as f32).min(1.0)
+        }
+    }
+}
+
 /// Per-vertex data of the model.
+#[derive(Clone, Debug)]
 pub struct VertexData {
-    pos: WorldPoint,
-    tex: TexturePoint,
-    normal: WorldVector,
+    pub(super) pos: WorldPoint,
+    pub(super) tex: TexturePoint,
+    pub(super) normal: WorldVector,
+}
+
+/// Triangle of the mesh being built.
+#[derive(Clone, Debug)]
+pub(super) struct MeshTriangle {
+    pub vertices: Triangle<usize>,
+    pub material: usize,
+    /// Position of the triangle in the input of the build
+    pub source_index: usize,
+}
+
+impl MeshTriangle {
+    pub fn positions(&self, vertices: &[VertexData]) -> Triangle<WorldPoint> {
+        self.vertices.map(|i| vertices[*i].pos)
+    }
+}
+
+/// Triangle to be stored in a leaf, possibly just a part of a mesh triangle that was cut by
+/// spatial splits.
+pub(super) struct LeafTriangle {
+    pub vertex_indices: Triangle<usize>,
+    pub material: usize,
+    pub positions: Triangle<WorldPoint>,
+    /// Corners in barycentric coordinates of the mesh triangle
+    pub source_uv: Triangle<Vector2<FloatType>>,
+    pub source_index: usize,
+}
+
+impl LeafTriangle {
+    pub fn whole(triangle: &MeshTriangle, vertices: &[VertexData]) -> Self {
+        LeafTriangle {
+            vertex_indices: triangle.vertices.clone(),
+            material: triangle.material,
+            positions: triangle.positions(vertices),
+            source_uv: whole_triangle_uv(),
+            source_index: triangle.source_index,
+        }
+    }
+}
+
+/// Barycentric coordinates of corners of a triangle in itself.
+pub(super) fn whole_triangle_uv() -> Triangle<Vector2<FloatType>> {
+    Triangle::new(
+        Vector2::new(0.0, 0.0),
+        Vector2::new(1.0, 0.0),
+        Vector2::new(0.0, 1.0),
+    )
 }
 
 /// Iterates over vertices of indexed triangles
 fn vertices_iter<'a>(
-    triangles: &[Triangle<usize>],
+    triangles: &[MeshTriangle],
     vertices: &'a [VertexData],
 ) -> impl Iterator<Item = &'a WorldPoint> {
     triangles
         .iter()
-        .flat_map(|t| t.iter())
+        .flat_map(|t| t.vertices.iter())
         .map(|i| &vertices[*i].pos)
 }
 
+fn centroids_coincide(triangles: &[MeshTriangle], vertices: &[VertexData]) -> bool {
+    let mut centroids = triangles
+        .iter()
+        .map(|triangle| triangle.positions(vertices).centroid());
+    let first = centroids.next();
+    centroids.all(|centroid| Some(centroid) == first)
+}
+
 /// Reorders the triangles and returns index range and a bounding box for child of the node.
+/// The triangles are binned using `thread_count` threads, including the calling one.
 fn split_triangles(
-    triangles: &mut [Triangle<usize>],
+    triangles: &mut [MeshTriangle],
     vertices: &[VertexData],
+    thread_count: usize,
 ) -> ArrayVec<(Range<usize>, WorldBox), INNER_NODE_CHILDREN> {
     let centroids_box = AABB::from_points(
         triangles
             .iter()
-            .map(|triangle| triangle.map(|i| vertices[*i].pos).centroid()),
+            .map(|triangle| triangle.positions(vertices).centroid()),
     )
     .unwrap();
     let bin_count = (triangles.len() / 64).clamp(128, 1024);
-    let bin_grid = BinGrid::with_approximate_bin_count(centroids_box, bin_count);
-
-    let mut bins = Vec::new();
-    bins.extend((0..bin_grid.bin_count()).map(|i| SplittingBin {
-        parent: i,
-        ..Default::default()
-    }));
-
-    for triangle in triangles.iter() {
-        let triangle = triangle.map(|i| vertices[*i].pos);
-        let centroid = triangle.centroid();
-
-        let bin = &mut bins[bin_grid.bin_index(&centroid)];
-        bin.bounding_box.extend_points(triangle.iter());
-        bin.count += 1;
-    }
+    let Some(bin_grid) = BinGrid::with_approximate_bin_count(centroids_box.clone(), bin_count)
+    else {
+        // All centroids coincide
+        return split_evenly(triangles, vertices, &centroids_box);
+    };
+
+    let mut bins = if thread_count > 1 {
+        let chunk_size = triangles.len().div_ceil(thread_count);
+        thread::scope(|scope| {
+            let mut chunks = triangles.chunks(chunk_size);
+            let first = chunks.next().unwrap();
+            let handles: Vec<_> = chunks
+                .map(|chunk| scope.spawn(|| fill_bins(chunk, vertices, &bin_grid)))
+                .collect();
+            let first_bins = fill_bins(first, vertices, &bin_grid);
+            std::iter::once(first_bins)
+                .chain(
+                    handles
+                        .into_iter()
+                        .map(|handle| handle.join().expect("BVH build thread panicked")),
+                )
+                .reduce(|a, b| a.iter().zip(&b).map(|(a, b)| a.merge(b)).collect())
+                .unwrap()
+        })
+    } else {
+        fill_bins(triangles, vertices, &bin_grid)
+    };
 
     let mut groups: Vec<_> = bins
         .iter()
@@ -269,10 +597,9 @@ fn split_triangles(
         .map(Clone::clone)
         .collect();
 
-    // This is a weak point of this algorithm, technically it could still fail if all centroids
-    // end up in a single bin
-    // TODO: Fix this
-    assert!(groups.len() >= 2);
+    if groups.len() < 2 {
+        return split_evenly(triangles, vertices, &centroids_box);
+    }
 
     // We can't merge any more if there's only two groups
     while groups.len() > 2 {
@@ -293,7 +620,7 @@ fn split_triangles(
     }
 
     triangles.sort_unstable_by_key(|triangle| {
-        let centroid = triangle.map(|i| vertices[*i].pos).centroid();
+        let centroid = triangle.positions(vertices).centroid();
         let mut i = bin_grid.bin_index(&centroid);
 
         // Disjoint-set data structure
@@ -314,7 +641,7 @@ fn split_triangles(
 
     let chunked_triangles = triangles
         .iter()
-        .map(|triangle| triangle.map(|i| vertices[*i].pos))
+        .map(|triangle| triangle.positions(vertices))
         .chunk_by(|triangle| {
             let centroid = triangle.centroid();
             let grid_index = bin_grid.bin_index(&centroid);
@@ -344,6 +671,89 @@ fn split_triangles(
         .collect()
 }
 
+/// Fallback for when binning can't separate the triangles.
+/// Sorts the triangles along the longest axis of their centroids and splits them into groups
+/// of equal size, which works even if all the centroids are the same.
+fn split_evenly(
+    triangles: &mut [MeshTriangle],
+    vertices: &[VertexData],
+    centroids_box: &WorldBox,
+) -> ArrayVec<(Range<usize>, WorldBox), INNER_NODE_CHILDREN> {
+    let axis = centroids_box.size().imax();
+    triangles
+        .sort_by_cached_key(|triangle| OrderedFloat(triangle.positions(vertices).centroid()[axis]));
+
+    let child_count = triangles
+        .len()
+        .div_ceil(LEAF_NODE_MAX_TRIANGLES)
+        .clamp(2, INNER_NODE_CHILDREN);
+    let chunk_size = triangles.len().div_ceil(child_count);
+    (0..triangles.len())
+        .step_by(chunk_size)
+        .map(|start| {
+            let range = start..(start + chunk_size).min(triangles.len());
+            let chunk_box =
+                WorldBox::from_points(vertices_iter(&triangles[range.clone()], vertices)).unwrap();
+            (range, chunk_box)
+        })
+        .collect()
+}
+
+/// Returns bins of the grid with bounding boxes and counts of triangles whose centroids fall
+/// in them.
+fn fill_bins(
+    triangles: &[MeshTriangle],
+    vertices: &[VertexData],
+    bin_grid: &BinGrid,
+) -> Vec<SplittingBin> {
+    let mut bins = Vec::new();
+    bins.extend((0..bin_grid.bin_count()).map(|i| SplittingBin {
+        parent: i,
+        ..Default::default()
+    }));
+
+    for triangle in triangles.iter() {
+        let triangle = triangle.positions(vertices);
+        let centroid = triangle.centroid();
+
+        let bin = &mut bins[bin_grid.bin_index(&centroid)];
+        bin.bounding_box.extend_points(triangle.iter());
+        bin.count += 1;
+    }
+
+    bins
+}
+
+/// Estimated traversal cost of a subtree with the given number of triangles, relative to
+/// its surface area.
+pub(super) fn subtree_cost(triangle_count: usize) -> f32 {
+    // triangle_count as f32
+    // TODO: Perf: This is just a second stab at what the node traversal cost might look like.
+    // It performs better than plain area * self.count, but not by much and behaves weirdly with
+    // changes in C_LEAF_PACKET. Investigate more.
+
+    const B: f32 = INNER_NODE_CHILDREN as f32;
+
+    let packet_count = triangle_count.div_ceil(LEAF_NODE_PACKET_SIZE);
+
+    let leaf_cost = if triangle_count <= LEAF_NODE_TARGET_TRIANGLES {
+        C_LEAF_PACKET * packet_count as f32
+    } else {
+        f32::INFINITY
+    };
+
+    let packet_count = packet_count as f32;
+    let depth = packet_count.log(B).floor();
+    let tree_cost = C_INNER * depth + C_LEAF_PACKET * (packet_count / B.powi(depth as i32)).ceil();
+
+    leaf_cost.min(tree_cost)
+}
+
+/// Traversal cost of an inner node, relative to its surface area.
+pub(super) const C_INNER: f32 = 1.0;
+/// Traversal cost of a leaf per triangle packet, relative to its surface area.
+pub(super) const C_LEAF_PACKET: f32 = 0.75;
+
 #[derive(Clone, Debug, Default)]
 struct SplittingBin {
     bounding_box: WorldBox,
@@ -356,30 +766,7 @@ impl SplittingBin {
     /// Evaluate surface area heuristic component for a single box.
     /// The value is scaled relative to the parent box.
     fn sah(&self) -> f32 {
-        // self.bounding_box.surface_area() * self.count as f32
-        // TODO: Perf: This is just a second stab at what the node traversal cost might look like.
-        // It performs better than plain area * self.count, but not by much and behaves weirdly with
-        // changes in C_LEAF_PACKET. Investigate more.
-
-        const B: f32 = INNER_NODE_CHILDREN as f32;
-
-        const C_INNER: f32 = 1.0;
-        const C_LEAF_PACKET: f32 = 0.75;
-
-        let packet_count = self.count.div_ceil(LEAF_NODE_PACKET_SIZE);
-
-        let leaf_cost = if packet_count <= CompressedNodeLink::MAX_COUNT as usize {
-            C_LEAF_PACKET * packet_count as f32
-        } else {
-            f32::INFINITY
-        };
-
-        let packet_count = packet_count as f32;
-        let depth = packet_count.log(B).floor();
-        let tree_cost =
-            C_INNER * depth + C_LEAF_PACKET * (packet_count / B.powi(depth as i32)).ceil();
-
-        self.bounding_box.surface_area() * leaf_cost.min(tree_cost)
+        self.bounding_box.surface_area() * subtree_cost(self.count)
     }
 
     fn merge(&self, other: &SplittingBin) -> SplittingBin {
@@ -420,13 +807,28 @@ struct BinGrid {
 }
 
 impl BinGrid {
-    pub fn with_approximate_bin_count(enclosing_box: WorldBox, bin_count: usize) -> Self {
-        let bin_size = (enclosing_box.volume() / (bin_count as f32)).cbrt();
-        Self::with_bin_size(enclosing_box, bin_size)
+    /// Returns None if the box is a single point.
+    /// Axes where the box is thinner than a bin get only a single layer of bins, so that flat
+    /// and thin boxes don't end up with a huge number of bins.
+    pub fn with_approximate_bin_count(enclosing_box: WorldBox, bin_count: usize) -> Option<Self> {
+        let size = enclosing_box.size();
+        let mut axes: ArrayVec<f32, 3> = size.iter().copied().filter(|&x| x > 0.0).collect();
+        loop {
+            if axes.is_empty() {
+                return None;
+            }
+            let volume: f32 = axes.iter().product();
+            let bin_size = (volume / (bin_count as f32)).powf(1.0 / axes.len() as f32);
+            let previous_len = axes.len();
+            axes.retain(|x| *x >= bin_size);
+            if axes.len() == previous_len {
+                return Some(Self::with_bin_size(enclosing_box, bin_size));
+            }
+        }
     }
 
     pub fn with_bin_size(enclosing_box: WorldBox, bin_size: f32) -> Self {
-        let bin_counts = (enclosing_box.size() / bin_size).map(|x| x.ceil() as usize);
+        let bin_counts = (enclosing_box.size() / bin_size).map(|x| (x.ceil() as usize).max(1));
 
         BinGrid {
             enclosing_box,
@@ -440,7 +842,10 @@ impl BinGrid {
     }
 
     pub fn bin_coords(&self, p: &WorldPoint) -> Vector3<usize> {
-        (p - self.enclosing_box.min).map(|x| (x / self.bin_size).floor() as usize)
+        // Points on the maximum edge would land just outside of the grid
+        (p - self.enclosing_box.min).zip_map(&self.bin_counts, |x, count| {
+            ((x / self.bin_size).floor() as usize).min(count - 1)
+        })
     }
 
     pub fn bin_index(&self, p: &WorldPoint) -> usize {
@@ -448,3 +853,260 @@ impl BinGrid {
         coords.x + coords.y * self.bin_counts.x + coords.z * self.bin_counts.xy().product()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        geometry::Ray,
+        scene::{
+            Object,
+            triangle_bvh::{Mesh, NodeLink, StackCache},
+        },
+    };
+    use assert2::{assert, let_assert};
+    use proptest::prelude::Strategy;
+    use test_strategy::proptest;
+
+    fn vertex(pos: WorldPoint) -> VertexData {
+        VertexData {
+            pos,
+            tex: TexturePoint::origin(),
+            normal: WorldVector::zeros(),
+        }
+    }
+
+    /// Builds the mesh and checks that every triangle made it to a leaf.
+    fn build_checked(
+        triangles: Vec<Triangle<usize>>,
+        vertices: Vec<VertexData>,
+        split_mode: SplitMode,
+    ) -> TriangleBvh {
+        let triangle_count = triangles.len();
+        let input = triangles.clone();
+        let progress = BuildProgress::default();
+        let bvh = Mesh::new(vertices.iter().map(|v| v.pos).collect(), triangles)
+            .normals(vertices.iter().map(|v| v.normal).collect())
+            .texture_coords(vertices.iter().map(|v| v.tex).collect())
+            .split_mode(split_mode)
+            .build_with_progress(&progress)
+            .unwrap();
+        assert!(triangle_count == 0 || progress.fraction() == 1.0);
+        let_assert!(Ok(()) = bvh.validate());
+        if split_mode == SplitMode::Object {
+            // Every input triangle is stored exactly once
+            let mut stored = bvh
+                .triangle_shading_data
+                .iter()
+                .filter(|t| !t.is_padding())
+                .map(|t| {
+                    [
+                        t.vertex_indices[0],
+                        t.vertex_indices[1],
+                        t.vertex_indices[2],
+                    ]
+                })
+                .collect_vec();
+            let mut expected = input.iter().map(|t| [t[0], t[1], t[2]]).collect_vec();
+            stored.sort();
+            expected.sort();
+            assert!(stored == expected);
+        }
+        bvh
+    }
+
+    /// Long thin triangles crossing each other, with texture coordinates following the position.
+    fn slivers() -> (Vec<Triangle<usize>>, Vec<VertexData>) {
+        let mut triangles = Vec::new();
+        let mut vertices = Vec::new();
+        for i in 0..200 {
+            let angle = i as f32 * 0.1;
+            let direction = WorldVector::new(angle.cos(), angle.sin(), 0.0);
+            let center = WorldPoint::new(0.0, 0.0, i as f32 * 0.01);
+            let points = [
+                center - direction * 10.0,
+                center + direction * 10.0,
+                center + direction * 10.0 + WorldVector::new(-direction.y, direction.x, 0.0),
+            ];
+            triangles.push(Triangle::new(
+                vertices.len(),
+                vertices.len() + 1,
+                vertices.len() + 2,
+            ));
+            vertices.extend(points.map(|pos| VertexData {
+                pos,
+                tex: TexturePoint::new(pos.x, pos.y, 0.0),
+                normal: WorldVector::zeros(),
+            }));
+        }
+        (triangles, vertices)
+    }
+
+    /// Vertices on a tiny grid, so that there are many duplicates and degenerate triangles.
+    fn grid_vertices_strategy(flat: bool) -> impl Strategy<Value = Vec<VertexData>> {
+        proptest::collection::vec((-2i8..=2, -2i8..=2, -2i8..=2), 1..20).prop_map(move |points| {
+            points
+                .into_iter()
+                .map(|(x, y, z)| {
+                    let z = if flat { 0 } else { z };
+                    vertex(WorldPoint::new(x as f32, y as f32, z as f32))
+                })
+                .collect()
+        })
+    }
+
+    fn soup_strategy(flat: bool) -> impl Strategy<Value = (Vec<Triangle<usize>>, Vec<VertexData>)> {
+        grid_vertices_strategy(flat).prop_flat_map(|vertices| {
+            let n = vertices.len();
+            let triangle = (0..n, 0..n, 0..n).prop_map(|(a, b, c)| Triangle::new(a, b, c));
+            (
+                proptest::collection::vec(triangle, 0..200),
+                proptest::strategy::Just(vertices),
+            )
+        })
+    }
+
+    #[test]
+    fn parallel_build_matches_serial() {
+        let load = || TriangleBvh::load_obj(obj::Obj::load("data/teapot.obj").unwrap());
+
+        let (triangles, vertices) = load();
+        let serial = TriangleBvh::build_internal(
+            triangles,
+            vertices,
+            SplitMode::Object,
+            &BuildProgress::default(),
+            usize::MAX,
+        );
+        let (triangles, vertices) = load();
+        let progress = BuildProgress::default();
+        let parallel =
+            TriangleBvh::build_internal(triangles, vertices, SplitMode::Object, &progress, 64);
+
+        assert!(progress.fraction() == 1.0);
+        assert!(format!("{parallel:?}") == format!("{serial:?}"));
+    }
+
+    #[proptest(cases = 16)]
+    fn coincident_triangles_build(#[strategy(1usize..2000)] count: usize) {
+        let vertices = vec![
+            vertex(WorldPoint::new(0.0, 0.0, 0.0)),
+            vertex(WorldPoint::new(1.0, 0.0, 0.0)),
+            vertex(WorldPoint::new(0.0, 1.0, 0.0)),
+        ];
+        let triangles = vec![Triangle::new(0, 1, 2); count];
+        let bvh = build_checked(triangles, vertices, SplitMode::Object);
+
+        let ray = Ray::new(
+            WorldPoint::new(0.25, 0.25, 1.0),
+            WorldVector::new(0.0, 0.0, -1.0),
+        );
+        let_assert!(Some(hit) = bvh.intersect(&ray, &mut StackCache::default()));
+        assert!((hit.t - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn coincident_triangles_fill_largest_leaf() {
+        let vertices = vec![
+            vertex(WorldPoint::new(0.0, 0.0, 0.0)),
+            vertex(WorldPoint::new(1.0, 0.0, 0.0)),
+            vertex(WorldPoint::new(0.0, 1.0, 0.0)),
+        ];
+        let triangles = vec![Triangle::new(0, 1, 2); LEAF_NODE_MAX_TRIANGLES];
+        let bvh = build_checked(triangles, vertices, SplitMode::Object);
+
+        let_assert!(NodeLink::Leaf { indices } = bvh.root.decode());
+        assert!(indices.len() == CompressedNodeLink::MAX_COUNT as usize);
+    }
+
+    #[proptest(cases = 16)]
+    fn triangle_soup_builds(
+        #[strategy(soup_strategy(false))] soup: (Vec<Triangle<usize>>, Vec<VertexData>),
+    ) {
+        let (triangles, vertices) = soup;
+        build_checked(triangles, vertices, SplitMode::Object);
+    }
+
+    #[proptest(cases = 16)]
+    fn flat_triangle_soup_builds(
+        #[strategy(soup_strategy(true))] soup: (Vec<Triangle<usize>>, Vec<VertexData>),
+    ) {
+        let (triangles, vertices) = soup;
+        build_checked(triangles, vertices, SplitMode::Object);
+    }
+
+    #[proptest(cases = 16)]
+    fn spatial_triangle_soup_builds(
+        #[strategy(soup_strategy(false))] soup: (Vec<Triangle<usize>>, Vec<VertexData>),
+    ) {
+        let (triangles, vertices) = soup;
+        build_checked(triangles, vertices, SplitMode::Spatial);
+    }
+
+    #[test]
+    fn empty_mesh_builds() {
+        for split_mode in [SplitMode::Object, SplitMode::Spatial] {
+            let bvh = build_checked(Vec::new(), Vec::new(), split_mode);
+            let ray = Ray::new(WorldPoint::origin(), WorldVector::new(0.0, 0.0, 1.0));
+            assert!(bvh.intersect(&ray, &mut StackCache::default()).is_none());
+        }
+    }
+
+    #[test]
+    fn spatial_splits_find_nearest_hits() {
+        let (triangles, vertices) = slivers();
+        let object = build_checked(triangles.clone(), vertices.clone(), SplitMode::Object);
+        let spatial = build_checked(triangles.clone(), vertices.clone(), SplitMode::Spatial);
+
+        // Some triangles were cut into several leaves
+        assert!(spatial.triangle_shading_data.len() > object.triangle_shading_data.len());
+
+        let mut stack = StackCache::default();
+        for x in -20..=20 {
+            for y in -20..=20 {
+                let (x, y) = (x as f32 * 0.37, y as f32 * 0.37);
+
+                // Height of the topmost triangle under the point, None if the point is too close
+                // to some edge for the result to be reliable
+                let mut expected_z = Some(FloatType::NEG_INFINITY);
+                for triangle in &triangles {
+                    let [a, b, c] = [0, 1, 2].map(|i| vertices[triangle[i]].pos);
+                    let edge = |p: &WorldPoint, q: &WorldPoint| {
+                        (q.x - p.x) * (y - p.y) - (q.y - p.y) * (x - p.x)
+                    };
+                    let area = edge(&a, &b) + edge(&b, &c) + edge(&c, &a);
+                    let inside = [edge(&a, &b), edge(&b, &c), edge(&c, &a)]
+                        .map(|e| e / area)
+                        .into_iter()
+                        .fold(FloatType::INFINITY, FloatType::min);
+                    if inside.abs() < 1e-3 {
+                        expected_z = None;
+                        break;
+                    } else if inside > 0.0 {
+                        expected_z = expected_z.map(|z| z.max(a.z));
+                    }
+                }
+                let Some(expected_z) = expected_z else {
+                    continue;
+                };
+
+                let ray = Ray::new(
+                    WorldPoint::new(x, y, 10.0),
+                    WorldVector::new(0.0, 0.0, -1.0),
+                );
+                for bvh in [&object, &spatial] {
+                    let hit = bvh.intersect(&ray, &mut stack);
+                    if expected_z.is_finite() {
+                        let_assert!(Some(hit) = hit);
+                        assert!((hit.t - (10.0 - expected_z)).abs() < 1e-3);
+                        // Texture coordinates are equal to the position
+                        assert!((hit.texture_coords.x - x).abs() < 1e-3);
+                        assert!((hit.texture_coords.y - y).abs() < 1e-3);
+                    } else {
+                        assert!(hit.is_none());
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,378 @@
+//! Binary cache files of built BVHs, so that large meshes don't have to be parsed and built
+//! on every start.
+
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+};
+
+use index_vec::IndexVec;
+use nalgebra::Vector2;
+use thiserror::Error;
+
+use crate::{
+    geometry::{AABB, FloatType, TexturePoint, Triangle, WorldPoint},
+    renderer::encoding::{
+        invalid_data, put_f32, put_u16, put_u32, put_u64, put_vector, read_f32, read_u8, read_u16,
+        read_u32, read_u64, read_usize, read_vector,
+    },
+};
+
+use super::{
+    BuildProgress, CompressedNodeLink, InnerNode, LEAF_NODE_PACKET_SIZE, LinkRaw, ObjOpenError,
+    SplitMode, TriangleBvh, TriangleShadingData, VertexShadingData,
+    compressed_geometry::{RelativeBox8, RelativePoint8, RelativeTriangle8},
+};
+
+const MAGIC: [u8; 4] = *b"MPBV";
+const VERSION: u32 = 5;
+
+#[derive(Debug, Error)]
+pub enum BvhCacheError {
+    #[error("Failed to access BVH cache: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Not a BVH cache file")]
+    NotACache,
+
+    #[error("Unsupported BVH cache version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("BVH cache was built from a different mesh")]
+    MeshChanged,
+
+    #[error("BVH cache uses node links of a different width")]
+    LinkWidthMismatch,
+
+    #[error(transparent)]
+    Obj(#[from] ObjOpenError),
+}
+
+/// Hashes contents of a mesh file to identify the mesh that a cache was built from (64bit FNV-1a).
+pub fn mesh_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl TriangleBvh {
+    /// Loads the BVH from an OBJ file through a cache file.
+    /// If the cache is missing, damaged or was built from a different mesh or with a different
+    /// split mode, the BVH is built from the OBJ and the cache gets replaced.
+    pub fn with_obj_cached(
+        p: impl AsRef<Path>,
+        cache_path: impl AsRef<Path>,
+        split_mode: SplitMode,
+        progress: &BuildProgress,
+    ) -> Result<TriangleBvh, BvhCacheError> {
+        let mut data = fs::read(p.as_ref()).map_err(ObjOpenError::from)?;
+        data.push(split_mode as u8);
+        let hash = mesh_hash(&data);
+        if let Ok(bvh) = Self::load_cache(&cache_path, hash) {
+            return Ok(bvh);
+        }
+
+        let bvh = Self::with_obj_split_mode(p, split_mode, progress)?;
+        bvh.save_cache(cache_path, hash)?;
+        Ok(bvh)
+    }
+
+    /// Saves the built BVH to a file, tagged with hash of the mesh it was built from.
+    /// The file is replaced atomically, same as checkpoints.
+    pub fn save_cache(&self, path: impl AsRef<Path>, mesh_hash: u64) -> Result<(), BvhCacheError> {
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        fs::write(&tmp_path, self.encode(mesh_hash))?;
+        fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+
+    /// Loads a BVH saved by `save_cache`, failing with `MeshChanged` if it was saved with
+    /// a different mesh hash.
+    pub fn load_cache(path: impl AsRef<Path>, mesh_hash: u64) -> Result<Self, BvhCacheError> {
+        let data = fs::read(path)?;
+        Self::decode(&mut data.as_slice(), mesh_hash)
+    }
+
+    fn encode(&self, mesh_hash: u64) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&MAGIC);
+        put_u32(&mut buffer, VERSION);
+        put_u64(&mut buffer, mesh_hash);
+        buffer.push(size_of::<LinkRaw>() as u8);
+
+        put_vector(&mut buffer, &self.bounding_box.min.coords);
+        put_vector(&mut buffer, &self.bounding_box.max.coords);
+        put_link(&mut buffer, self.root);
+        buffer.push(self.split_mode as u8);
+
+        put_u64(&mut buffer, self.inner_nodes.len() as u64);
+        for node in &self.inner_nodes {
+            put_relative_point(&mut buffer, &node.child_bounds.min);
+            put_relative_point(&mut buffer, &node.child_bounds.max);
+            for link in &node.child_links {
+                put_link(&mut buffer, *link);
+            }
+        }
+
+        put_u64(&mut buffer, self.triangle_geometry.len() as u64);
+        for triangle in &self.triangle_geometry {
+            for point in triangle.iter() {
+                put_relative_point(&mut buffer, point);
+            }
+        }
+
+        put_u64(&mut buffer, self.triangle_shading_data.len() as u64);
+        for triangle in &self.triangle_shading_data {
+            for &index in triangle.vertex_indices.iter() {
+                put_u64(&mut buffer, index as u64);
+            }
+            buffer.push(triangle.flat_shading as u8);
+            put_u64(&mut buffer, triangle.material as u64);
+            put_u64(&mut buffer, triangle.source_index as u64);
+            for uv in triangle.source_uv.iter() {
+                put_f32(&mut buffer, uv.x);
+                put_f32(&mut buffer, uv.y);
+            }
+        }
+
+        put_u64(&mut buffer, self.vertex_data.len() as u64);
+        for vertex in &self.vertex_data {
+            put_vector(&mut buffer, &vertex.normal);
+            put_vector(&mut buffer, &vertex.texture_coords.coords);
+        }
+
+        buffer
+    }
+
+    fn decode(r: &mut impl Read, mesh_hash: u64) -> Result<Self, BvhCacheError> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(BvhCacheError::NotACache);
+        }
+
+        let version = read_u32(r)?;
+        if version != VERSION {
+            return Err(BvhCacheError::UnsupportedVersion(version));
+        }
+        if read_u64(r)? != mesh_hash {
+            return Err(BvhCacheError::MeshChanged);
+        }
+        if usize::from(read_u8(r)?) != size_of::<LinkRaw>() {
+            return Err(BvhCacheError::LinkWidthMismatch);
+        }
+
+        let bounding_box = AABB::new(
+            WorldPoint::from(read_vector(r)?),
+            WorldPoint::from(read_vector(r)?),
+        );
+        let root = read_link(r)?;
+        let split_mode = match read_u8(r)? {
+            0 => SplitMode::Object,
+            1 => SplitMode::Spatial,
+            _ => return Err(invalid_data("Unknown split mode").into()),
+        };
+
+        let mut inner_nodes = IndexVec::new();
+        for _ in 0..read_usize(r)? {
+            let child_bounds = RelativeBox8::new(read_relative_point(r)?, read_relative_point(r)?);
+            let mut child_links = [CompressedNodeLink::NULL; 8];
+            for link in &mut child_links {
+                *link = read_link(r)?;
+            }
+            inner_nodes.push(InnerNode {
+                child_bounds,
+                child_links,
+            });
+        }
+
+        let mut triangle_geometry = IndexVec::new();
+        for _ in 0..read_usize(r)? {
+            triangle_geometry.push(RelativeTriangle8::new(
+                read_relative_point(r)?,
+                read_relative_point(r)?,
+                read_relative_point(r)?,
+            ));
+        }
+
+        let mut triangle_shading_data = IndexVec::new();
+        for _ in 0..read_usize(r)? {
+            triangle_shading_data.push(TriangleShadingData {
+                vertex_indices: Triangle::new(read_usize(r)?, read_usize(r)?, read_usize(r)?),
+                flat_shading: read_u8(r)? != 0,
+                material: read_usize(r)?,
+                source_index: read_usize(r)?,
+                source_uv: Triangle::new(
+                    read_source_uv(r)?,
+                    read_source_uv(r)?,
+                    read_source_uv(r)?,
+                ),
+            });
+        }
+
+        let mut vertex_data = IndexVec::new();
+        for _ in 0..read_usize(r)? {
+            vertex_data.push(VertexShadingData {
+                normal: read_vector(r)?,
+                texture_coords: TexturePoint::from(read_vector(r)?),
+            });
+        }
+
+        let mut bvh = TriangleBvh {
+            bounding_box,
+            root,
+            inner_nodes,
+            triangle_geometry,
+            triangle_shading_data,
+            vertex_data,
+            split_mode,
+            build_cost: 0.0,
+        };
+        bvh.check_structure()?;
+        // The tree is loaded as it was built, its current cost is the baseline
+        bvh.build_cost = bvh.sah_cost();
+        Ok(bvh)
+    }
+
+    /// Checks that the tree is consistent, so that a damaged cache can't make the traversal
+    /// panic or loop forever through shared or cyclic links.
+    fn check_structure(&self) -> io::Result<()> {
+        // Validation looks up shading data of every stored triangle
+        if self.triangle_shading_data.len() != self.triangle_geometry.len() * LEAF_NODE_PACKET_SIZE
+        {
+            return Err(invalid_data("Triangle count mismatch"));
+        }
+        self.validate()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+fn read_source_uv(r: &mut impl Read) -> io::Result<Vector2<FloatType>> {
+    Ok(Vector2::new(read_f32(r)?, read_f32(r)?))
+}
+
+fn put_link(buffer: &mut Vec<u8>, link: CompressedNodeLink) {
+    buffer.extend_from_slice(&link.0.to_le_bytes());
+}
+
+fn read_link(r: &mut impl Read) -> io::Result<CompressedNodeLink> {
+    let mut bytes = [0; size_of::<LinkRaw>()];
+    r.read_exact(&mut bytes)?;
+    let link = CompressedNodeLink(LinkRaw::from_le_bytes(bytes));
+    // Indices above the limit don't fit the index types and would panic when decoded
+    if !link.is_null() && link.0 >> CompressedNodeLink::COUNT_BITS > CompressedNodeLink::MAX_INDEX {
+        return Err(invalid_data("Node link index out of range"));
+    }
+    Ok(link)
+}
+
+fn put_relative_point(buffer: &mut Vec<u8>, point: &RelativePoint8) {
+    for lanes in point.to_raw() {
+        for v in lanes {
+            put_u16(buffer, v);
+        }
+    }
+}
+
+fn read_relative_point(r: &mut impl Read) -> io::Result<RelativePoint8> {
+    let mut raw = [[0; 8]; 3];
+    for lanes in &mut raw {
+        for v in lanes {
+            *v = read_u16(r)?;
+        }
+    }
+    Ok(RelativePoint8::from_raw(raw))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        geometry::{Ray, WorldVector},
+        scene::{
+            Object,
+            triangle_bvh::{NodeLink, StackCache, test::test_bvh},
+        },
+    };
+    use assert2::{assert, let_assert};
+
+    #[test]
+    fn round_trip() {
+        let bvh = test_bvh();
+        let decoded = TriangleBvh::decode(&mut bvh.encode(1234).as_slice(), 1234).unwrap();
+
+        assert!(decoded.root == bvh.root);
+        assert!(decoded.inner_nodes.len() == bvh.inner_nodes.len());
+        assert!(decoded.triangle_shading_data.len() == bvh.triangle_shading_data.len());
+        assert!(decoded.encode(1234) == bvh.encode(1234));
+
+        let ray = Ray::new(
+            WorldPoint::new(0.0, 1.5, 10.0),
+            WorldVector::new(0.0, 0.0, -1.0),
+        );
+        let mut stack = StackCache::default();
+        let_assert!(Some(expected) = bvh.intersect(&ray, &mut stack));
+        let_assert!(Some(hit) = decoded.intersect(&ray, &mut stack));
+        assert!(hit.t == expected.t);
+    }
+
+    #[test]
+    fn different_mesh() {
+        let data = test_bvh().encode(1);
+        let_assert!(Err(BvhCacheError::MeshChanged) = TriangleBvh::decode(&mut data.as_slice(), 2));
+    }
+
+    #[test]
+    fn different_link_width() {
+        let mut data = test_bvh().encode(1);
+        // Link width follows magic, version and mesh hash
+        data[16] = 12;
+        let_assert!(
+            Err(BvhCacheError::LinkWidthMismatch) = TriangleBvh::decode(&mut data.as_slice(), 1)
+        );
+    }
+
+    #[test]
+    fn cyclic_links() {
+        let mut bvh = test_bvh().clone();
+        let NodeLink::Inner { index } = bvh.root.decode() else {
+            panic!("Teapot root is a leaf");
+        };
+        bvh.inner_nodes[index].child_links[0] = bvh.root;
+        let data = bvh.encode(1);
+        let_assert!(Err(BvhCacheError::Io(e)) = TriangleBvh::decode(&mut data.as_slice(), 1));
+        assert!(e.kind() == io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn root_link_all_ones() {
+        let mut bvh = test_bvh().clone();
+        bvh.root = CompressedNodeLink(LinkRaw::MAX);
+        let data = bvh.encode(1);
+        let_assert!(Err(BvhCacheError::Io(e)) = TriangleBvh::decode(&mut data.as_slice(), 1));
+        assert!(e.kind() == io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn not_a_cache() {
+        let data = b"PNG whatever";
+        let_assert!(Err(BvhCacheError::NotACache) = TriangleBvh::decode(&mut &data[..], 0));
+    }
+
+    #[test]
+    fn truncated() {
+        let mut data = test_bvh().encode(1);
+        data.pop();
+        let_assert!(Err(BvhCacheError::Io(_)) = TriangleBvh::decode(&mut data.as_slice(), 1));
+    }
+
+    #[test]
+    fn hash_depends_on_content() {
+        assert!(mesh_hash(b"v 0 0 0") != mesh_hash(b"v 0 0 1"));
+    }
+}
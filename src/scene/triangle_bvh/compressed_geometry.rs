@@ -4,7 +4,7 @@
 
 use assert2::debug_assert;
 
-use simba::simd::{SimdBool as _, SimdPartialOrd as _, WideBoolF32x8, WideF32x8};
+use simba::simd::{SimdBool as _, SimdPartialOrd as _, SimdValue as _, WideBoolF32x8, WideF32x8};
 // This module uses wide directly, as simba doesn't support integer vectors
 // This limits robustness to change -- if/when we move to std::simd
 use wide::{CmpGe as _, CmpLe as _, f32x8, i32x8, u16x8};
@@ -14,6 +14,10 @@ use crate::geometry::{
     WorldVector8,
 };
 
+/// How far outside of the enclosing box a compressed point may be, relative to the box size.
+/// Such points get clamped to the box.
+pub(super) const OUTSIDE_TOLERANCE: f32 = 1e-6;
+
 /// Represents 8 closed real intervals [0, 1] compressed to u16 each.
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(transparent)]
@@ -28,11 +32,11 @@ impl UnitInterval8 {
         mask: &SimdMaskType,
     ) -> Self {
         debug_assert!(
-            (v.cmp_ge(f32x8::splat(-1e-6)) | !mask.0).all(),
+            (v.cmp_ge(f32x8::splat(-OUTSIDE_TOLERANCE)) | !mask.0).all(),
             "v: {v}, mask: {mask:?}"
         );
         debug_assert!(
-            (v.cmp_le(f32x8::splat(1.0 + 1e-6)) | !mask.0).all(),
+            (v.cmp_le(f32x8::splat(1.0 + OUTSIDE_TOLERANCE)) | !mask.0).all(),
             "v: {v}, mask: {mask:?}"
         );
         let max = u16x8_to_f32x8(u16x8::MAX);
@@ -80,11 +84,14 @@ impl RelativePoint8 {
         debug_assert!(
             enclosing_box
                 .size
-                .fold(true, |acc, x| acc & x.simd_gt(WideF32x8::ZERO).all()),
+                .fold(true, |acc, x| acc & x.simd_ge(WideF32x8::ZERO).all()),
             "{:?}",
             enclosing_box.size
         );
-        let relative = (p - enclosing_box.min).component_div(&enclosing_box.size);
+        let relative = (p - enclosing_box.min).zip_map(&enclosing_box.size, |offset, size| {
+            // Boxes can be flat (planar geometry), all points are at the minimum then
+            (offset / size).select(size.simd_gt(WideF32x8::ZERO), WideF32x8::ZERO)
+        });
         Self {
             x: UnitInterval8::compress_internal(relative.x.0, rounding, mask),
             y: UnitInterval8::compress_internal(relative.y.0, rounding, mask),
@@ -112,6 +119,23 @@ impl RelativePoint8 {
     pub fn is_zero(&self) -> WideBoolF32x8 {
         self.x.is_zero() & self.y.is_zero() & self.z.is_zero()
     }
+
+    /// Compressed x, y and z coordinates of the 8 points, for serialization.
+    pub fn to_raw(self) -> [[u16; 8]; 3] {
+        [
+            self.x.0.to_array(),
+            self.y.0.to_array(),
+            self.z.0.to_array(),
+        ]
+    }
+
+    pub fn from_raw([x, y, z]: [[u16; 8]; 3]) -> Self {
+        Self {
+            x: UnitInterval8(u16x8::new(x)),
+            y: UnitInterval8(u16x8::new(y)),
+            z: UnitInterval8(u16x8::new(z)),
+        }
+    }
 }
 
 pub type RelativeBox8 = AABB<RelativePoint8>;
@@ -184,7 +208,6 @@ mod test {
     use super::*;
 
     use assert2::assert;
-    use simba::simd::SimdValue as _;
     use test_strategy::proptest;
 
     #[proptest]
@@ -0,0 +1,298 @@
+//! Building the tree from geometry in memory, for procedural meshes and other loaders than OBJ.
+
+use thiserror::Error;
+
+use crate::geometry::{TexturePoint, Triangle, WorldPoint, WorldVector};
+
+use super::{
+    BuildProgress, SplitMode, TriangleBvh,
+    building::{MeshTriangle, VertexData},
+};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MeshError {
+    #[error("Mesh has {expected} vertices, but {actual} normals")]
+    NormalCountMismatch { expected: usize, actual: usize },
+
+    #[error("Mesh has {expected} vertices, but {actual} texture coordinates")]
+    TextureCoordCountMismatch { expected: usize, actual: usize },
+
+    #[error("Mesh has {expected} triangles, but {actual} materials")]
+    MaterialCountMismatch { expected: usize, actual: usize },
+
+    #[error("Triangle {triangle} refers to vertex {vertex}, which doesn't exist")]
+    VertexIndexOutOfRange { triangle: usize, vertex: usize },
+
+    #[error("Vertex {0} has a position that is not finite")]
+    NonFinitePosition(usize),
+
+    #[error("Vertex {0} has a normal that is not finite")]
+    NonFiniteNormal(usize),
+}
+
+/// Indexed triangle mesh, built into a `TriangleBvh` after checking that it's consistent.
+///
+/// Vertices keep their indices, so the positions can be passed to `TriangleBvh::refit`
+/// directly when the mesh deforms.
+#[derive(Clone, Debug, Default)]
+pub struct Mesh {
+    positions: Vec<WorldPoint>,
+    triangles: Vec<Triangle<usize>>,
+    normals: Option<Vec<WorldVector>>,
+    texture_coords: Option<Vec<TexturePoint>>,
+    materials: Option<Vec<usize>>,
+    split_mode: SplitMode,
+}
+
+impl Mesh {
+    /// Mesh with vertex positions and triangles made of indices into them.
+    pub fn new(positions: Vec<WorldPoint>, triangles: Vec<Triangle<usize>>) -> Self {
+        Mesh {
+            positions,
+            triangles,
+            ..Default::default()
+        }
+    }
+
+    /// Per vertex normals for smooth shading, they don't need to be normalized.
+    /// Triangles with a zero normal at any of their vertices are shaded flat,
+    /// as are all triangles of meshes without normals.
+    pub fn normals(mut self, normals: Vec<WorldVector>) -> Self {
+        self.normals = Some(normals);
+        self
+    }
+
+    /// Per vertex texture coordinates, zero if not set.
+    pub fn texture_coords(mut self, texture_coords: Vec<TexturePoint>) -> Self {
+        self.texture_coords = Some(texture_coords);
+        self
+    }
+
+    /// Per triangle material IDs, reported in hit records, zero if not set.
+    pub fn materials(mut self, materials: Vec<usize>) -> Self {
+        self.materials = Some(materials);
+        self
+    }
+
+    pub fn split_mode(mut self, split_mode: SplitMode) -> Self {
+        self.split_mode = split_mode;
+        self
+    }
+
+    pub fn build(self) -> Result<TriangleBvh, MeshError> {
+        self.build_with_progress(&BuildProgress::default())
+    }
+
+    pub fn build_with_progress(self, progress: &BuildProgress) -> Result<TriangleBvh, MeshError> {
+        self.validate()?;
+
+        let vertex_count = self.positions.len();
+        let normals = self
+            .normals
+            .unwrap_or_else(|| vec![WorldVector::zeros(); vertex_count]);
+        let texture_coords = self
+            .texture_coords
+            .unwrap_or_else(|| vec![TexturePoint::origin(); vertex_count]);
+        let vertices = self
+            .positions
+            .into_iter()
+            .zip(normals)
+            .zip(texture_coords)
+            .map(|((pos, normal), tex)| VertexData {
+                pos,
+                tex,
+                normal: normal.try_normalize(0.0).unwrap_or_else(WorldVector::zeros),
+            })
+            .collect();
+
+        let materials = self
+            .materials
+            .unwrap_or_else(|| vec![0; self.triangles.len()]);
+        let triangles = self
+            .triangles
+            .into_iter()
+            .zip(materials)
+            .enumerate()
+            .map(|(source_index, (vertices, material))| MeshTriangle {
+                vertices,
+                material,
+                source_index,
+            })
+            .collect();
+
+        Ok(TriangleBvh::build_mesh(
+            triangles,
+            vertices,
+            self.split_mode,
+            progress,
+        ))
+    }
+
+    fn validate(&self) -> Result<(), MeshError> {
+        let vertex_count = self.positions.len();
+        if let Some(normals) = &self.normals
+            && normals.len() != vertex_count
+        {
+            return Err(MeshError::NormalCountMismatch {
+                expected: vertex_count,
+                actual: normals.len(),
+            });
+        }
+        if let Some(texture_coords) = &self.texture_coords
+            && texture_coords.len() != vertex_count
+        {
+            return Err(MeshError::TextureCoordCountMismatch {
+                expected: vertex_count,
+                actual: texture_coords.len(),
+            });
+        }
+        if let Some(materials) = &self.materials
+            && materials.len() != self.triangles.len()
+        {
+            return Err(MeshError::MaterialCountMismatch {
+                expected: self.triangles.len(),
+                actual: materials.len(),
+            });
+        }
+
+        for (triangle, indices) in self.triangles.iter().enumerate() {
+            if let Some(&vertex) = indices.iter().find(|&&i| i >= vertex_count) {
+                return Err(MeshError::VertexIndexOutOfRange { triangle, vertex });
+            }
+        }
+        if let Some(vertex) = self
+            .positions
+            .iter()
+            .position(|p| !p.iter().all(|x| x.is_finite()))
+        {
+            return Err(MeshError::NonFinitePosition(vertex));
+        }
+        if let Some(vertex) = self
+            .normals
+            .iter()
+            .flatten()
+            .position(|n| !n.iter().all(|x| x.is_finite()))
+        {
+            return Err(MeshError::NonFiniteNormal(vertex));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        geometry::{HitRecord, Ray},
+        scene::{Object, triangle_bvh::StackCache},
+    };
+    use assert2::{assert, let_assert};
+
+    /// Two unit squares next to each other in the XY plane, each made of two triangles.
+    fn squares() -> Mesh {
+        let positions = (0..3)
+            .flat_map(|x| (0..2).map(move |y| WorldPoint::new(x as f32, y as f32, 0.0)))
+            .collect();
+        let triangles = vec![
+            Triangle::new(0, 2, 3),
+            Triangle::new(0, 3, 1),
+            Triangle::new(2, 4, 5),
+            Triangle::new(2, 5, 3),
+        ];
+        Mesh::new(positions, triangles)
+    }
+
+    fn hit(bvh: &TriangleBvh, x: f32, y: f32) -> HitRecord {
+        let ray = Ray::new(WorldPoint::new(x, y, 1.0), WorldVector::new(0.0, 0.0, -1.0));
+        let_assert!(Some(hit) = bvh.intersect(&ray, &mut StackCache::default()));
+        hit
+    }
+
+    #[test]
+    fn materials_are_per_triangle() {
+        let bvh = squares().materials(vec![3, 3, 5, 5]).build().unwrap();
+        assert!(bvh.validate() == Ok(()));
+        assert!(hit(&bvh, 0.5, 0.3).material == 3);
+        assert!(hit(&bvh, 1.5, 0.7).material == 5);
+    }
+
+    #[test]
+    fn shading_data_is_interpolated() {
+        let normals = (0..6)
+            .map(|i| WorldVector::new(i as f32, 0.0, 2.0))
+            .collect();
+        let texture_coords = (0..6)
+            .map(|i| TexturePoint::new(i as f32, 0.0, 0.0))
+            .collect();
+        let bvh = squares()
+            .normals(normals)
+            .texture_coords(texture_coords)
+            .split_mode(SplitMode::Spatial)
+            .build()
+            .unwrap();
+
+        // Barycentric coordinates (0.25, 0.25) in triangle 2, 4, 5
+        let hit = hit(&bvh, 1.5, 0.25);
+        assert!((hit.texture_coords.x - 3.25).abs() < 1e-3);
+        let normal = |i: f32| WorldVector::new(i, 0.0, 2.0).normalize();
+        let expected = (normal(2.0) * 0.5 + normal(4.0) * 0.25 + normal(5.0) * 0.25).normalize();
+        assert!((hit.normal.into_inner() - expected).norm() < 1e-3);
+    }
+
+    #[test]
+    fn without_normals_shading_is_flat() {
+        let bvh = squares().build().unwrap();
+        let hit = hit(&bvh, 0.5, 0.5);
+        assert!(hit.normal == hit.geometric_normal);
+    }
+
+    #[test]
+    fn count_mismatches() {
+        let_assert!(
+            Err(MeshError::NormalCountMismatch {
+                expected: 6,
+                actual: 1
+            }) = squares().normals(vec![WorldVector::z()]).build()
+        );
+        let_assert!(
+            Err(MeshError::TextureCoordCountMismatch { .. }) =
+                squares().texture_coords(Vec::new()).build()
+        );
+        let_assert!(
+            Err(MeshError::MaterialCountMismatch {
+                expected: 4,
+                actual: 6
+            }) = squares().materials(vec![0; 6]).build()
+        );
+    }
+
+    #[test]
+    fn vertex_index_out_of_range() {
+        let mut mesh = squares();
+        mesh.triangles[2][1] = 6;
+        let_assert!(
+            Err(MeshError::VertexIndexOutOfRange {
+                triangle: 2,
+                vertex: 6
+            }) = mesh.build()
+        );
+    }
+
+    #[test]
+    fn non_finite_values() {
+        let mut mesh = squares();
+        mesh.positions[4].y = f32::NAN;
+        let_assert!(Err(MeshError::NonFinitePosition(4)) = mesh.build());
+
+        let mut normals = vec![WorldVector::z(); 6];
+        normals[1].x = f32::INFINITY;
+        let_assert!(Err(MeshError::NonFiniteNormal(1)) = squares().normals(normals).build());
+    }
+
+    #[test]
+    fn empty_mesh() {
+        let bvh = Mesh::default().build().unwrap();
+        assert!(bvh.validate() == Ok(()));
+    }
+}
@@ -1,20 +1,36 @@
 mod building;
+mod cache;
 mod compressed_geometry;
+mod mesh;
 mod printing;
+mod quality;
 mod ray_bvh_intersection;
+mod refit;
+mod spatial_split;
+mod validation;
 
 use compressed_geometry::{RelativeBox8, RelativeTriangle8};
 
-use crate::geometry::{TexturePoint, Triangle, WorldBox, WorldVector};
+use crate::geometry::{FloatType, TexturePoint, Triangle, WorldBox, WorldVector};
 
 use index_vec::IndexVec;
+use nalgebra::Vector2;
 
-pub use ray_bvh_intersection::StackCache;
+pub use building::{BuildProgress, ObjOpenError, SplitMode};
+pub use cache::{BvhCacheError, mesh_hash};
+pub use mesh::{Mesh, MeshError};
+pub use quality::BvhQuality;
+pub use ray_bvh_intersection::{RayTraversal, StackCache, TraversalStats};
+pub use refit::RefitError;
+pub use validation::BvhValidationError;
 
 const INNER_NODE_CHILDREN: usize = 8;
 const LEAF_NODE_PACKET_SIZE: usize = 8;
 const LEAF_NODE_MAX_TRIANGLES: usize =
     LEAF_NODE_PACKET_SIZE * CompressedNodeLink::MAX_COUNT as usize;
+/// Leaf size the builder aims for, only triangles that can't be separated end up
+/// in larger leaves.
+const LEAF_NODE_TARGET_TRIANGLES: usize = LEAF_NODE_PACKET_SIZE * 7;
 
 #[derive(Clone, Debug)]
 pub struct TriangleBvh {
@@ -27,6 +43,11 @@ pub struct TriangleBvh {
     triangle_shading_data: IndexVec<TriangleIdx, TriangleShadingData>,
 
     vertex_data: IndexVec<VertexIdx, VertexShadingData>,
+
+    /// How the tree was built, rebuilds after refitting use the same mode
+    split_mode: SplitMode,
+    /// SAH cost right after the build, baseline for deciding when refitting is not enough
+    build_cost: f32,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -42,6 +63,19 @@ struct TriangleShadingData {
     vertex_indices: Triangle<usize>,
     flat_shading: bool,
     material: usize,
+    /// Corners of the stored triangle in barycentric coordinates of the mesh triangle,
+    /// differs from the identity only for parts of triangles cut by spatial splits.
+    source_uv: Triangle<Vector2<FloatType>>,
+    /// Position of the mesh triangle in the input of the build, shared by all of its parts.
+    source_index: usize,
+}
+
+impl TriangleShadingData {
+    /// Slots filling up the last packet of a leaf have all source UVs zero,
+    /// no real triangle (or its part) has that.
+    fn is_padding(&self) -> bool {
+        self.source_uv.iter().all(|uv| *uv == Vector2::zeros())
+    }
 }
 
 /// Additional data for triangle shading.
@@ -52,9 +86,18 @@ struct VertexShadingData {
     texture_coords: TexturePoint,
 }
 
+/// Raw representation of node links and of the indices they store.
+/// The default 32 bit links limit the tree to 2^29 - 1 inner nodes and as many triangle packets
+/// and leaves to 7 packets, the `wide-node-links` feature doubles the size of inner nodes to lift
+/// these limits to 2^59 - 1 and 31 packets.
+#[cfg(not(feature = "wide-node-links"))]
+type LinkRaw = u32;
+#[cfg(feature = "wide-node-links")]
+type LinkRaw = u64;
+
 #[repr(transparent)]
 #[derive(Clone, Copy, PartialEq, Eq)]
-struct CompressedNodeLink(u32);
+struct CompressedNodeLink(LinkRaw);
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 enum NodeLink {
@@ -64,11 +107,14 @@ enum NodeLink {
 }
 
 impl CompressedNodeLink {
+    #[cfg(not(feature = "wide-node-links"))]
     const COUNT_BITS: u32 = 3;
-    const COUNT_MASK: u32 = (1 << Self::COUNT_BITS) - 1;
-    const NULL_VALUE: u32 = (u32::MAX >> Self::COUNT_BITS) << Self::COUNT_BITS;
+    #[cfg(feature = "wide-node-links")]
+    const COUNT_BITS: u32 = 5;
+    const COUNT_MASK: LinkRaw = (1 << Self::COUNT_BITS) - 1;
+    const NULL_VALUE: LinkRaw = (LinkRaw::MAX >> Self::COUNT_BITS) << Self::COUNT_BITS;
 
-    pub const MAX_INDEX: u32 = (u32::MAX >> Self::COUNT_BITS) - 1;
+    pub const MAX_INDEX: LinkRaw = (LinkRaw::MAX >> Self::COUNT_BITS) - 1;
     pub const MIN_COUNT: u32 = 1;
     pub const MAX_COUNT: u32 = (1 << Self::COUNT_BITS) - 1;
 
@@ -76,13 +122,15 @@ impl CompressedNodeLink {
 
     /// Create a new leaf link, panics if size or count are out of range
     fn new_leaf(index: TrianglePackIdx, count: u32) -> Self {
+        assert!(index.raw() <= Self::MAX_INDEX);
         assert!(count >= Self::MIN_COUNT);
         assert!(count <= Self::MAX_COUNT);
-        Self(index.raw() << Self::COUNT_BITS | count)
+        Self(index.raw() << Self::COUNT_BITS | LinkRaw::from(count))
     }
 
     /// Create a new inner node link, panics if size is out of range
     fn new_inner(index: InnerNodeIdx) -> Self {
+        assert!(index.raw() <= Self::MAX_INDEX);
         Self(index.raw() << Self::COUNT_BITS)
     }
 
@@ -90,7 +138,9 @@ impl CompressedNodeLink {
         if self.is_null() {
             NodeLink::Null
         } else {
-            let count = self.0 & Self::COUNT_MASK;
+            // Cast is not a no-op with wide links, the masked value always fits
+            #[allow(clippy::unnecessary_cast)]
+            let count = (self.0 & Self::COUNT_MASK) as u32;
             let index = self.0 >> Self::COUNT_BITS;
 
             if count == 0 {
@@ -111,6 +161,18 @@ impl CompressedNodeLink {
     fn is_null(&self) -> bool {
         self.0 == Self::NULL_VALUE
     }
+
+    /// Returns the link moved by the given number of inner nodes and triangle packets,
+    /// for when the nodes it points to get appended to a larger tree.
+    fn offset(&self, inner_offset: usize, packet_offset: usize) -> Self {
+        match self.decode() {
+            NodeLink::Null => *self,
+            NodeLink::Inner { index } => Self::new_inner(index + inner_offset),
+            NodeLink::Leaf { indices } => {
+                Self::new_leaf(indices.first + packet_offset, indices.len() as u32)
+            }
+        }
+    }
 }
 
 impl Default for CompressedNodeLink {
@@ -129,14 +191,15 @@ impl std::fmt::Debug for CompressedNodeLink {
 }
 
 index_vec::define_index_type! {
-    struct InnerNodeIdx = u32;
+    struct InnerNodeIdx = LinkRaw;
     MAX_INDEX = CompressedNodeLink::MAX_INDEX as usize;
     IMPL_RAW_CONVERSIONS = true;
 }
 
 index_vec::define_index_type! {
-    struct TrianglePackIdx = u32;
-    MAX_INDEX = CompressedNodeLink::MAX_INDEX as usize;
+    struct TrianglePackIdx = LinkRaw;
+    // End of the packet range of a leaf that starts at the highest index
+    MAX_INDEX = (CompressedNodeLink::MAX_INDEX + CompressedNodeLink::MAX_COUNT as LinkRaw) as usize;
     IMPL_RAW_CONVERSIONS = true;
 }
 
@@ -170,12 +233,16 @@ impl TrianglePackIdxRange {
         }
     }
 
+    pub fn len(&self) -> usize {
+        (self.last.raw() - self.first.raw()) as usize
+    }
+
     pub fn into_range(self) -> std::ops::Range<TrianglePackIdx> {
         self.first..self.last
     }
 
     pub fn iter(&self) -> impl Iterator<Item = TrianglePackIdx> {
-        (u32::from(self.first)..u32::from(self.last)).map(TrianglePackIdx::from)
+        (LinkRaw::from(self.first)..LinkRaw::from(self.last)).map(TrianglePackIdx::from)
     }
 }
 
@@ -184,22 +251,47 @@ mod test {
     use super::*;
 
     use assert2::{assert, let_assert};
+    use std::sync::LazyLock;
     use test_strategy::proptest;
 
+    /// Teapot built with the default split mode, shared by tests of all the submodules.
+    pub(super) fn test_bvh() -> &'static TriangleBvh {
+        test_bvh_with_split_mode(SplitMode::default())
+    }
+
+    /// Building the teapot in debug mode is slow, so each split mode is built only once.
+    pub(super) fn test_bvh_with_split_mode(split_mode: SplitMode) -> &'static TriangleBvh {
+        fn build(split_mode: SplitMode) -> TriangleBvh {
+            TriangleBvh::with_obj_split_mode(
+                "data/teapot.obj",
+                split_mode,
+                &BuildProgress::default(),
+            )
+            .unwrap()
+        }
+        static OBJECT: LazyLock<TriangleBvh> = LazyLock::new(|| build(SplitMode::Object));
+        static SPATIAL: LazyLock<TriangleBvh> = LazyLock::new(|| build(SplitMode::Spatial));
+
+        match split_mode {
+            SplitMode::Object => &OBJECT,
+            SplitMode::Spatial => &SPATIAL,
+        }
+    }
+
     #[proptest]
     fn node_link_construction_leaf(
-        #[strategy(0u32..=CompressedNodeLink::MAX_INDEX)] index: u32,
+        #[strategy(0..=CompressedNodeLink::MAX_INDEX)] index: LinkRaw,
         #[strategy(1u32..=CompressedNodeLink::MAX_COUNT)] count: u32,
     ) {
-        dbg!(CompressedNodeLink::MAX_INDEX);
         let tag = CompressedNodeLink::new_leaf(index.into(), count);
         let_assert!(NodeLink::Leaf { indices } = tag.decode());
         assert!(indices.first.raw() == index);
         assert!(indices.iter().count() == count as usize);
+        assert!(indices.len() == count as usize);
     }
 
     #[proptest]
-    fn node_link_construction_inner(#[strategy(0u32..=CompressedNodeLink::MAX_INDEX)] index: u32) {
+    fn node_link_construction_inner(#[strategy(0..=CompressedNodeLink::MAX_INDEX)] index: LinkRaw) {
         let tag = CompressedNodeLink::new_inner(index.into());
         let_assert!(NodeLink::Inner { index: decoded } = tag.decode());
         assert!(decoded.raw() == index);
@@ -214,13 +306,16 @@ mod test {
     #[test]
     #[should_panic]
     fn node_link_invalid_leaf_packet_count_zero() {
-        CompressedNodeLink::new_leaf(0u32.into(), 0);
+        CompressedNodeLink::new_leaf(TrianglePackIdx::from_raw(0), 0);
     }
 
     #[test]
     #[should_panic]
     fn node_link_invalid_leaf_packet_count_too_high() {
-        CompressedNodeLink::new_leaf(0u32.into(), CompressedNodeLink::MAX_COUNT + 1);
+        CompressedNodeLink::new_leaf(
+            TrianglePackIdx::from_raw(0),
+            CompressedNodeLink::MAX_COUNT + 1,
+        );
     }
 
     #[test]
@@ -234,4 +329,53 @@ mod test {
     fn node_link_inner_index_out_of_range() {
         CompressedNodeLink::new_inner((CompressedNodeLink::MAX_INDEX + 1).into());
     }
+
+    #[test]
+    fn node_link_max_index() {
+        let index = CompressedNodeLink::MAX_INDEX;
+        let leaf = CompressedNodeLink::new_leaf(index.into(), CompressedNodeLink::MAX_COUNT);
+        let_assert!(NodeLink::Leaf { indices } = leaf.decode());
+        assert!(indices.first.raw() == index);
+        assert!(indices.len() == CompressedNodeLink::MAX_COUNT as usize);
+
+        let inner = CompressedNodeLink::new_inner(index.into());
+        assert!(!inner.is_null());
+        let_assert!(NodeLink::Inner { index: decoded } = inner.decode());
+        assert!(decoded.raw() == index);
+    }
+
+    #[test]
+    #[should_panic]
+    fn node_link_offset_out_of_range() {
+        let link = CompressedNodeLink::new_inner(CompressedNodeLink::MAX_INDEX.into());
+        link.offset(1, 0);
+    }
+
+    #[cfg(not(feature = "wide-node-links"))]
+    #[test]
+    fn node_link_narrow_limit() {
+        assert!(CompressedNodeLink::MAX_INDEX == (1 << 29) - 2);
+        assert!(CompressedNodeLink::MAX_COUNT == 7);
+        assert!(std::mem::size_of::<CompressedNodeLink>() == 4);
+    }
+
+    #[cfg(feature = "wide-node-links")]
+    #[test]
+    fn node_link_wide_beyond_narrow_limit() {
+        assert!(CompressedNodeLink::MAX_INDEX == (1 << 59) - 2);
+        assert!(CompressedNodeLink::MAX_COUNT == 31);
+        assert!(std::mem::size_of::<CompressedNodeLink>() == 8);
+
+        // First index and packet count that the 32 bit links can't store and one past 32 bits
+        for index in [(1 << 29) - 1, 1 << 32] {
+            let leaf = CompressedNodeLink::new_leaf(TrianglePackIdx::from_raw(index), 8);
+            let_assert!(NodeLink::Leaf { indices } = leaf.decode());
+            assert!(indices.first.raw() == index);
+            assert!(indices.iter().count() == 8);
+
+            let inner = CompressedNodeLink::new_inner(InnerNodeIdx::from_raw(index));
+            let_assert!(NodeLink::Inner { index: decoded } = inner.decode());
+            assert!(decoded.raw() == index);
+        }
+    }
 }
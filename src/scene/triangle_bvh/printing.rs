@@ -1,11 +1,191 @@
-use crate::util::{Stats, simba::simd_element_iter};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
 
-use super::{CompressedNodeLink, TriangleBvh};
+use simba::simd::SimdValue as _;
+
+use crate::{
+    geometry::{WorldBox, WorldBoxSized8, WorldPoint},
+    util::Stats,
+};
+
+use super::{CompressedNodeLink, NodeLink, TriangleBvh, TrianglePackIdxRange};
 
 impl TriangleBvh {
+    /// Prints every node of the tree, with decompressed bounds, on a separate line.
     pub fn print_tree(&self) {
-        todo!()
-        // self.print_recursive(0, self.root, &self.bounding_box);
+        self.write_tree(&mut io::stdout().lock())
+            .expect("Failed to write to stdout");
+    }
+
+    /// Writes the tree dump, one node per line, indented by depth.
+    /// Inner nodes show their index and number of children, leaves the range of triangle
+    /// packets and number of triangles in them (without padding).
+    /// Floats are printed exactly, so that dumps of two builds can be diffed.
+    pub fn write_tree(&self, w: &mut impl Write) -> io::Result<()> {
+        self.visit_nodes(&mut |depth, link, node_box| {
+            let indent = "  ".repeat(depth);
+            let bounds = format_box(node_box);
+            match link.decode() {
+                NodeLink::Null => Ok(()),
+                NodeLink::Inner { index } => {
+                    let child_count = self.inner_nodes[index]
+                        .child_links
+                        .iter()
+                        .filter(|link| !link.is_null())
+                        .count();
+                    writeln!(
+                        w,
+                        "{indent}I{}: {child_count} children, {bounds}",
+                        index.index()
+                    )
+                }
+                NodeLink::Leaf { indices } => writeln!(
+                    w,
+                    "{indent}L{}..{}: {} triangles, {bounds}",
+                    indices.first.index(),
+                    indices.last.index(),
+                    self.leaf_triangle_count(indices)
+                ),
+            }
+        })
+    }
+
+    /// Exports boxes of all nodes as a wireframe, with a separate OBJ object for each depth
+    /// (`depth_0` is the root).
+    /// If the path ends with `.json`, writes a JSON with array of boxes for each depth instead.
+    pub fn export_boxes(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut w = BufWriter::new(File::create(path)?);
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            self.write_boxes_json(&mut w)?;
+        } else {
+            self.write_boxes_obj(&mut w)?;
+        }
+        w.flush()
+    }
+
+    pub fn write_boxes_obj(&self, w: &mut impl Write) -> io::Result<()> {
+        // Corners are numbered by bits, x = bit 0, y = bit 1, z = bit 2
+        const EDGES: [(usize, usize); 12] = [
+            (0, 1),
+            (2, 3),
+            (4, 5),
+            (6, 7),
+            (0, 2),
+            (1, 3),
+            (4, 6),
+            (5, 7),
+            (0, 4),
+            (1, 5),
+            (2, 6),
+            (3, 7),
+        ];
+
+        let mut vertex_count = 0;
+        for (depth, boxes) in self.boxes_by_depth().iter().enumerate() {
+            writeln!(w, "o depth_{depth}")?;
+            for b in boxes {
+                for corner in 0..8 {
+                    let pick = |bit: usize, axis: usize| {
+                        if corner & (1 << bit) == 0 {
+                            b.min[axis]
+                        } else {
+                            b.max[axis]
+                        }
+                    };
+                    writeln!(w, "v {:?} {:?} {:?}", pick(0, 0), pick(1, 1), pick(2, 2))?;
+                }
+                for (a, b) in EDGES {
+                    // OBJ indices start at 1
+                    writeln!(w, "l {} {}", vertex_count + a + 1, vertex_count + b + 1)?;
+                }
+                vertex_count += 8;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `[[{"min": [x, y, z], "max": [x, y, z]}, ...], ...]`, outer array is indexed by depth.
+    pub fn write_boxes_json(&self, w: &mut impl Write) -> io::Result<()> {
+        let depths = self.boxes_by_depth();
+        writeln!(w, "[")?;
+        for (depth, boxes) in depths.iter().enumerate() {
+            writeln!(w, "  [")?;
+            for (i, b) in boxes.iter().enumerate() {
+                let separator = if i + 1 < boxes.len() { "," } else { "" };
+                writeln!(
+                    w,
+                    "    {{\"min\": {}, \"max\": {}}}{separator}",
+                    format_point(&b.min),
+                    format_point(&b.max)
+                )?;
+            }
+            let separator = if depth + 1 < depths.len() { "," } else { "" };
+            writeln!(w, "  ]{separator}")?;
+        }
+        writeln!(w, "]")
+    }
+
+    /// Decompressed boxes of all nodes, grouped by depth.
+    fn boxes_by_depth(&self) -> Vec<Vec<WorldBox>> {
+        let mut depths: Vec<Vec<WorldBox>> = Vec::new();
+        self.visit_nodes(&mut |depth, _link, node_box| {
+            if depths.len() <= depth {
+                depths.resize(depth + 1, Vec::new());
+            }
+            depths[depth].push(node_box.clone());
+            Ok(())
+        })
+        .unwrap_or_else(|_: io::Error| unreachable!());
+        depths
+    }
+
+    /// Calls the function for every non-null node in depth first order, with its depth
+    /// and the decompressed box that is used for it during traversal.
+    pub(super) fn visit_nodes<E>(
+        &self,
+        f: &mut impl FnMut(usize, CompressedNodeLink, &WorldBox) -> Result<(), E>,
+    ) -> Result<(), E> {
+        self.visit_recursive(0, self.root, &self.bounding_box, f)
+    }
+
+    fn visit_recursive<E>(
+        &self,
+        depth: usize,
+        link: CompressedNodeLink,
+        node_box: &WorldBox,
+        f: &mut impl FnMut(usize, CompressedNodeLink, &WorldBox) -> Result<(), E>,
+    ) -> Result<(), E> {
+        if link.is_null() {
+            return Ok(());
+        }
+        f(depth, link, node_box)?;
+
+        if let NodeLink::Inner { index } = link.decode() {
+            let node = &self.inner_nodes[index];
+            let child_boxes = node
+                .child_bounds
+                .decompress(&WorldBoxSized8::splat(node_box.into()));
+            for (i, child_link) in node.child_links.iter().enumerate() {
+                self.visit_recursive(depth + 1, *child_link, &child_boxes.extract(i), f)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of triangles in the leaf, not counting the padding.
+    fn leaf_triangle_count(&self, indices: TrianglePackIdxRange) -> usize {
+        let triangles = indices.first.to_triangle_idx(0)..indices.last.to_triangle_idx(0);
+        self.triangle_shading_data[triangles]
+            .iter()
+            .filter(|t| !t.is_padding())
+            .count()
     }
 
     pub fn print_statistics(&self) {
@@ -52,46 +232,90 @@ impl TriangleBvh {
 
                 (depth_stats, inner_stats, leaf_stats)
             }
-            super::NodeLink::Leaf { indices } => {
-                let leaf_fill = self.triangle_geometry[indices.into_range()]
-                    .iter()
-                    .flat_map(|ts| {
-                        simd_element_iter(ts[0].is_zero() & ts[1].is_zero() & ts[2].is_zero())
-                    })
-                    .filter(|x| !*x)
-                    .count();
-                (
-                    Stats::new_single(1),
-                    Stats::default(),
-                    Stats::new_single(leaf_fill),
-                )
-            }
+            super::NodeLink::Leaf { indices } => (
+                Stats::new_single(1),
+                Stats::default(),
+                Stats::new_single(self.leaf_triangle_count(indices)),
+            ),
         }
     }
+}
+
+fn format_point(p: &WorldPoint) -> String {
+    format!("[{:?}, {:?}, {:?}]", p.x, p.y, p.z)
+}
+
+fn format_box(b: &WorldBox) -> String {
+    format!("{} - {}", format_point(&b.min), format_point(&b.max))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        geometry::Triangle,
+        scene::triangle_bvh::{Mesh, test::test_bvh},
+    };
+    use assert2::{assert, let_assert};
+
+    fn node_count(bvh: &TriangleBvh) -> usize {
+        bvh.boxes_by_depth().iter().map(Vec::len).sum()
+    }
+
+    #[test]
+    fn tree_has_line_per_node() {
+        let bvh = test_bvh();
+        let mut buffer = Vec::new();
+        bvh.write_tree(&mut buffer).unwrap();
+        let dump = String::from_utf8(buffer).unwrap();
+
+        assert!(dump.lines().count() == node_count(bvh));
+        assert!(dump.starts_with("I0: "));
+        assert!(
+            dump.lines()
+                .filter(|line| line.trim_start().starts_with('I'))
+                .count()
+                == bvh.inner_nodes.len()
+        );
+    }
+
+    #[test]
+    fn obj_has_wireframe_per_node() {
+        let bvh = test_bvh();
+        let mut buffer = Vec::new();
+        bvh.write_boxes_obj(&mut buffer).unwrap();
+        let obj = String::from_utf8(buffer).unwrap();
 
-    // fn print_recursive(&self, indent: usize, node: CompressedNodeLink, enclosing_box: &WorldBox) {
-    //     println!(
-    //         "{}- {}{}: {:?}-{:?}",
-    //         "  ".repeat(indent),
-    //         if node.is_leaf() { "L" } else { "I" },
-    //         node.index(),
-    //         enclosing_box.min,
-    //         enclosing_box.max,
-    //     );
+        let count = |prefix: &str| obj.lines().filter(|line| line.starts_with(prefix)).count();
+        assert!(count("o ") == bvh.boxes_by_depth().len());
+        assert!(count("v ") == 8 * node_count(bvh));
+        assert!(count("l ") == 12 * node_count(bvh));
+    }
 
-    //     let enclosing_box = WorldBox8::splat(enclosing_box.clone());
+    #[test]
+    fn json_has_entry_per_node() {
+        let bvh = test_bvh();
+        let mut buffer = Vec::new();
+        bvh.write_boxes_json(&mut buffer).unwrap();
+        let json = String::from_utf8(buffer).unwrap();
 
-    //     if node.is_leaf() {
-    //         self.leaf_geometry_arena[node.index()].print(indent, &enclosing_box);
-    //         return;
-    //     }
+        assert!(json.matches("\"min\"").count() == node_count(bvh));
+        assert!(json.trim_end().ends_with(']'));
+    }
 
-    //     let node = &self.inner_node_arena[node.index()];
-    //     let child_boxes = node.child_bounds.decompress(&enclosing_box);
+    #[test]
+    fn degenerate_triangle_in_corner_is_counted() {
+        // The degenerate triangle sits in the minimum corner of the leaf box, so all of its
+        // quantized coordinates are zero, just like those of the padding
+        let positions = vec![
+            WorldPoint::new(0.0, 0.0, 0.0),
+            WorldPoint::new(1.0, 0.0, 0.0),
+            WorldPoint::new(0.0, 1.0, 1.0),
+        ];
+        let triangles = vec![Triangle::new(0, 1, 2), Triangle::new(0, 0, 0)];
+        let bvh = Mesh::new(positions, triangles).build().unwrap();
 
-    //     for (i, child_link) in node.child_links.iter().enumerate() {
-    //         let child_box = child_boxes.extract(i);
-    //         self.print_recursive(indent + 1, *child_link, &child_box);
-    //     }
-    // }
+        let_assert!(NodeLink::Leaf { indices } = bvh.root.decode());
+        assert!(bvh.leaf_triangle_count(indices) == 2);
+    }
 }
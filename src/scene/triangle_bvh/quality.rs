@@ -0,0 +1,262 @@
+//! Quality metrics of a built tree, for comparing build settings.
+
+use std::convert::Infallible;
+
+use simba::simd::SimdValue as _;
+
+use crate::{
+    geometry::{Triangle, WorldBox, WorldBoxSized8, WorldPoint},
+    util::simba::simd_element_iter,
+};
+
+use super::{
+    CompressedNodeLink, NodeLink, TriangleBvh, TrianglePackIdxRange,
+    building::{C_INNER, C_LEAF_PACKET},
+    spatial_split::{Polygon, box_area},
+};
+
+/// Metrics of tree quality as used in BVH literature.
+/// All of them are computed with the decompressed boxes that the traversal uses.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BvhQuality {
+    /// Expected cost of traversing the tree with a random ray that hits the root box,
+    /// using the same per node costs as the builder.
+    pub sah_cost: f32,
+    /// Sum of surface areas of intersections of sibling boxes, relative to the root box.
+    pub sibling_overlap: f32,
+    /// End-point overlap (Aila et al. 2013): area of geometry inside boxes of nodes that don't
+    /// contain it, weighted by the node costs, relative to the total geometry area.
+    pub epo: f32,
+    /// Surface area that the rounding of compressed child boxes adds to exact bounds of their
+    /// geometry, summed over all children and relative to the root box.
+    pub quantization_slack: f32,
+}
+
+impl TriangleBvh {
+    /// Computes the quality metrics, all zero for an empty tree.
+    /// The end-point overlap clips triangles against boxes of overlapping nodes, so this is
+    /// considerably slower than walking the tree.
+    pub fn quality(&self) -> BvhQuality {
+        if self.root.is_null() {
+            return BvhQuality::default();
+        }
+
+        let mut totals = QualityTotals::default();
+        self.quality_recursive(self.root, &self.bounding_box, &mut totals);
+
+        let root_area = self.bounding_box.surface_area();
+        BvhQuality {
+            sah_cost: self.sah_cost(),
+            sibling_overlap: relative(totals.sibling_overlap, root_area),
+            epo: relative(totals.epo, totals.geometry_area),
+            quantization_slack: relative(totals.quantization_slack, root_area),
+        }
+    }
+
+    /// Just the `sah_cost` of `quality`, cheap enough to be checked after every refit.
+    pub fn sah_cost(&self) -> f32 {
+        let mut cost = 0.0;
+        self.visit_nodes(&mut |_depth, link, node_box| {
+            cost += node_cost(link) * node_box.surface_area();
+            Ok(())
+        })
+        .unwrap_or_else(|_: Infallible| unreachable!());
+        relative(cost, self.bounding_box.surface_area())
+    }
+
+    /// Adds metrics of a subtree to the totals and returns the exact bounds of its geometry.
+    fn quality_recursive(
+        &self,
+        link: CompressedNodeLink,
+        node_box: &WorldBox,
+        totals: &mut QualityTotals,
+    ) -> Option<WorldBox> {
+        if link.is_null() {
+            return None;
+        }
+        totals.epo +=
+            node_cost(link) * self.area_inside(self.root, &self.bounding_box, node_box, link);
+
+        match link.decode() {
+            NodeLink::Null => None,
+            NodeLink::Inner { index } => {
+                let node = &self.inner_nodes[index];
+                let child_boxes = node
+                    .child_bounds
+                    .decompress(&WorldBoxSized8::splat(node_box.into()));
+                let children = node
+                    .child_links
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, link)| !link.is_null())
+                    .map(|(i, link)| (*link, child_boxes.extract(i)))
+                    .collect::<Vec<_>>();
+
+                for (i, (_, a)) in children.iter().enumerate() {
+                    for (_, b) in &children[i + 1..] {
+                        totals.sibling_overlap += box_area(&a.intersection(b));
+                    }
+                }
+
+                let mut bounds: Option<WorldBox> = None;
+                for (child_link, child_box) in children {
+                    let child_bounds = self.quality_recursive(child_link, &child_box, totals);
+                    let exact_area = child_bounds.as_ref().map_or(0.0, box_area);
+                    totals.quantization_slack += child_box.surface_area() - exact_area;
+                    bounds = match (bounds, child_bounds) {
+                        (Some(a), Some(b)) => Some(a.union(&b)),
+                        (a, b) => a.or(b),
+                    };
+                }
+                bounds
+            }
+            NodeLink::Leaf { indices } => {
+                let triangles = self.leaf_triangles(indices, node_box).collect::<Vec<_>>();
+                totals.geometry_area += triangles
+                    .iter()
+                    .map(|triangle| triangle.normal().norm() / 2.0)
+                    .sum::<f32>();
+                WorldBox::from_points(triangles.iter().flat_map(|triangle| triangle.iter()))
+            }
+        }
+    }
+
+    /// Area of geometry in the subtree under `link` that lies inside `query_box`,
+    /// not counting the subtree under `excluded`.
+    fn area_inside(
+        &self,
+        link: CompressedNodeLink,
+        node_box: &WorldBox,
+        query_box: &WorldBox,
+        excluded: CompressedNodeLink,
+    ) -> f32 {
+        let overlap = node_box.intersection(query_box);
+        if link == excluded || (0..3).any(|axis| overlap.min[axis] > overlap.max[axis]) {
+            return 0.0;
+        }
+
+        match link.decode() {
+            NodeLink::Null => 0.0,
+            NodeLink::Inner { index } => {
+                let node = &self.inner_nodes[index];
+                let child_boxes = node
+                    .child_bounds
+                    .decompress(&WorldBoxSized8::splat(node_box.into()));
+                node.child_links
+                    .iter()
+                    .enumerate()
+                    .map(|(i, child_link)| {
+                        self.area_inside(*child_link, &child_boxes.extract(i), query_box, excluded)
+                    })
+                    .sum()
+            }
+            NodeLink::Leaf { indices } => self
+                .leaf_triangles(indices, node_box)
+                .map(|triangle| {
+                    Polygon::from_triangle(&triangle)
+                        .clip_to_box(query_box)
+                        .area()
+                })
+                .sum(),
+        }
+    }
+
+    /// Decompressed triangles of a leaf, without the padding.
+    fn leaf_triangles(
+        &self,
+        indices: TrianglePackIdxRange,
+        leaf_box: &WorldBox,
+    ) -> impl Iterator<Item = Triangle<WorldPoint>> {
+        let sized_box = WorldBoxSized8::splat(leaf_box.into());
+        self.triangle_geometry[indices.into_range()]
+            .iter()
+            .flat_map(move |compressed| {
+                let triangles = compressed.decompress(&sized_box);
+                let is_zero =
+                    compressed[0].is_zero() & compressed[1].is_zero() & compressed[2].is_zero();
+                simd_element_iter(is_zero)
+                    .enumerate()
+                    .filter(|(_, is_zero)| !is_zero)
+                    .map(move |(lane, _)| triangles.extract(lane))
+            })
+    }
+}
+
+/// Traversal cost of a node relative to its surface area, same as used by the builder.
+fn node_cost(link: CompressedNodeLink) -> f32 {
+    match link.decode() {
+        NodeLink::Null => 0.0,
+        NodeLink::Inner { .. } => C_INNER,
+        NodeLink::Leaf { indices } => C_LEAF_PACKET * indices.len() as f32,
+    }
+}
+
+fn relative(value: f32, total: f32) -> f32 {
+    if total > 0.0 { value / total } else { 0.0 }
+}
+
+/// Absolute sums of the metrics, normalized at the end.
+#[derive(Default)]
+struct QualityTotals {
+    sibling_overlap: f32,
+    epo: f32,
+    quantization_slack: f32,
+    geometry_area: f32,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scene::triangle_bvh::{
+        Mesh, SplitMode,
+        test::{test_bvh, test_bvh_with_split_mode},
+    };
+    use assert2::assert;
+
+    #[test]
+    fn empty_tree() {
+        let bvh = Mesh::default().build().unwrap();
+        assert!(bvh.quality() == BvhQuality::default());
+    }
+
+    #[test]
+    fn teapot_metrics() {
+        let bvh = test_bvh();
+        let quality = bvh.quality();
+        // At least the root node is always traversed
+        assert!(quality.sah_cost > C_INNER);
+        assert!(quality.sah_cost < 100.0);
+        assert!(quality.sibling_overlap > 0.0);
+        assert!(quality.epo > 0.0);
+        assert!(quality.quantization_slack > 0.0);
+
+        // Every side of a child box gets rounded outwards by less than one 16 bit step of its
+        // parent's extent, so no child can grow more than its parent would if it was grown by
+        // a step on each side (doubled to leave room for float rounding)
+        let mut max_slack = 0.0;
+        bvh.visit_nodes(&mut |_depth, link, node_box| {
+            if let NodeLink::Inner { index } = link.decode() {
+                let child_count = bvh.inner_nodes[index]
+                    .child_links
+                    .iter()
+                    .filter(|link| !link.is_null())
+                    .count();
+                let step = node_box.size() * (2.0 / f32::from(u16::MAX));
+                let grown = WorldBox::new(node_box.min - step, node_box.max + step);
+                max_slack += child_count as f32 * (grown.surface_area() - node_box.surface_area());
+            }
+            Ok(())
+        })
+        .unwrap_or_else(|_: Infallible| unreachable!());
+        let max_slack = relative(max_slack, bvh.bounding_box.surface_area());
+        assert!(quality.quantization_slack <= max_slack);
+    }
+
+    #[test]
+    fn spatial_splits_reduce_overlap() {
+        let object = test_bvh_with_split_mode(SplitMode::Object).quality();
+        let spatial = test_bvh_with_split_mode(SplitMode::Spatial).quality();
+        assert!(spatial.epo < object.epo);
+        assert!(spatial.sibling_overlap < object.sibling_overlap);
+    }
+}
@@ -17,9 +17,70 @@ use crate::{
 };
 
 #[derive(Clone, Default)]
-#[repr(transparent)]
 pub struct StackCache {
     stack: Vec<(CompressedNodeLink, WorldBoxSized, FloatType)>,
+    stats: TraversalStats,
+    last_ray: RayTraversal,
+}
+
+impl StackCache {
+    /// Returns counts of nodes tested by the most recently traced ray.
+    pub fn last_ray(&self) -> RayTraversal {
+        self.last_ray
+    }
+
+    /// Returns traversal statistics collected since the last call, and resets them.
+    pub fn take_stats(&mut self) -> TraversalStats {
+        std::mem::take(&mut self.stats)
+    }
+}
+
+/// Counts of BVH nodes tested while tracing a single ray.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RayTraversal {
+    pub inner_nodes: usize,
+    pub triangle_packets: usize,
+}
+
+/// Counts of BVH nodes tested during traversal, summed over many rays.
+/// Kept as integer sums so that the averages stay exact even for billions of rays.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraversalStats {
+    pub rays: u64,
+    pub inner_nodes: u64,
+    pub triangle_packets: u64,
+}
+
+impl TraversalStats {
+    pub fn add_ray(&mut self, ray: RayTraversal) {
+        self.rays += 1;
+        self.inner_nodes += ray.inner_nodes as u64;
+        self.triangle_packets += ray.triangle_packets as u64;
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        TraversalStats {
+            rays: self.rays + other.rays,
+            inner_nodes: self.inner_nodes + other.inner_nodes,
+            triangle_packets: self.triangle_packets + other.triangle_packets,
+        }
+    }
+
+    pub fn inner_nodes_per_ray(&self) -> f64 {
+        per_ray(self.inner_nodes, self.rays)
+    }
+
+    pub fn triangle_packets_per_ray(&self) -> f64 {
+        per_ray(self.triangle_packets, self.rays)
+    }
+}
+
+fn per_ray(count: u64, rays: u64) -> f64 {
+    if rays > 0 {
+        count as f64 / rays as f64
+    } else {
+        0.0
+    }
 }
 
 impl Object for TriangleBvh {
@@ -35,6 +96,8 @@ impl Object for TriangleBvh {
             t: FloatType::MAX,
             ..LeafHitRecord::default()
         };
+        let mut inner_nodes_tested = 0;
+        let mut triangle_packets_tested = 0;
 
         while let Some((link, enclosing_box, node_t1)) = stack.stack.pop() {
             if node_t1 > best.t {
@@ -49,11 +112,13 @@ impl Object for TriangleBvh {
                 super::NodeLink::Null => continue,
                 super::NodeLink::Inner { index } => {
                     let node = &self.inner_nodes[index];
+                    inner_nodes_tested += 1;
                     stack
                         .stack
                         .extend(node.intersect(ray, &enclosing_box, best.t));
                 }
                 super::NodeLink::Leaf { indices } => {
+                    triangle_packets_tested += indices.len();
                     let hit = self.intersect_triangles(indices, ray, &enclosing_box, best.t);
 
                     if hit.t < best.t {
@@ -63,6 +128,12 @@ impl Object for TriangleBvh {
             }
         }
 
+        stack.last_ray = RayTraversal {
+            inner_nodes: inner_nodes_tested,
+            triangle_packets: triangle_packets_tested,
+        };
+        stack.stats.add_ray(stack.last_ray);
+
         if best.triangle_index == TriangleIdx::default() {
             None
         } else {
@@ -70,7 +141,11 @@ impl Object for TriangleBvh {
                 ref vertex_indices,
                 flat_shading,
                 material,
+                ref source_uv,
+                ..
             } = self.triangle_shading_data[best.triangle_index];
+            let uv = best.uv.interpolate_triangle(source_uv);
+            let uv = BarycentricCoordinates { u: uv.x, v: uv.y };
             let vertex_shading_data = vertex_indices.map(|i| &self.vertex_data[*i]);
 
             let tex = vertex_shading_data.map(|d| d.texture_coords);
@@ -78,10 +153,9 @@ impl Object for TriangleBvh {
                 best.geometric_normal
             } else {
                 let normals = vertex_shading_data.map(|d| d.normal);
-                best.uv.interpolate_triangle(&normals)
+                uv.interpolate_triangle(&normals)
             });
-            let texture_coords = best
-                .uv
+            let texture_coords = uv
                 .interpolate(&tex[0].coords, &tex[1].coords, &tex[2].coords)
                 .into();
 
@@ -89,8 +163,10 @@ impl Object for TriangleBvh {
                 t: best.t,
                 point: ray.point_at(best.t),
                 normal,
+                geometric_normal: Unit::new_normalize(best.geometric_normal),
                 material,
                 texture_coords,
+                triangle_index: best.triangle_index.index(),
             })
         }
     }
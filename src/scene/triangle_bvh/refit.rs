@@ -0,0 +1,340 @@
+//! Updating the tree for deforming meshes, without building it again from scratch.
+
+use arrayvec::ArrayVec;
+use index_vec::IndexVec;
+use itertools::Itertools as _;
+use simba::simd::SimdValue as _;
+use thiserror::Error;
+
+use crate::{
+    geometry::{
+        BarycentricCoordinates, Triangle, WorldBox, WorldBoxSized8, WorldPoint, WorldPoint8,
+    },
+    util::simba::simd_windows,
+};
+
+use super::{
+    BuildProgress, CompressedNodeLink, INNER_NODE_CHILDREN, InnerNodeIdx, LEAF_NODE_PACKET_SIZE,
+    NodeLink, TriangleBvh, TriangleIdx,
+    building::{MeshTriangle, VertexData, compress_child_boxes},
+    compressed_geometry::RelativeTriangle8,
+};
+
+/// Refitted tree is built again once its SAH cost grows over this multiple of the cost
+/// right after the build.
+const REBUILD_COST_RATIO: f32 = 1.5;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RefitError {
+    #[error("Mesh has {expected} vertices, got {actual} positions")]
+    VertexCountMismatch { expected: usize, actual: usize },
+}
+
+impl TriangleBvh {
+    /// Moves the vertices to new positions like `refit` does, but builds the tree again if
+    /// the refitted one got too expensive to traverse.
+    /// Returns true if the tree was rebuilt.
+    pub fn update_positions(&mut self, positions: &[WorldPoint]) -> Result<bool, RefitError> {
+        self.refit(positions)?;
+        if self.sah_cost() <= self.build_cost * REBUILD_COST_RATIO {
+            return Ok(false);
+        }
+        self.rebuild(positions);
+        Ok(true)
+    }
+
+    /// Moves the vertices to new positions, keeping the structure of the tree and only
+    /// updating bounds of the nodes, bottom up.
+    /// Positions are indexed the same as the vertices that the tree was built from, which for
+    /// trees built from a `Mesh` is the order of its positions.
+    /// Loading an OBJ file merges vertices with the same position, texture coordinates and
+    /// normal into an order that isn't exposed, so such trees can't be refitted this way.
+    /// Shading normals and texture coordinates stay the same.
+    pub fn refit(&mut self, positions: &[WorldPoint]) -> Result<(), RefitError> {
+        if positions.len() != self.vertex_data.len() {
+            return Err(RefitError::VertexCountMismatch {
+                expected: self.vertex_data.len(),
+                actual: positions.len(),
+            });
+        }
+
+        let mut child_bounds = IndexVec::from_vec(vec![
+            [const { None }; INNER_NODE_CHILDREN];
+            self.inner_nodes.len()
+        ]);
+        let Some(bounding_box) = self.refit_bounds(self.root, positions, &mut child_bounds) else {
+            // Empty tree, nothing to move
+            return Ok(());
+        };
+
+        // Compression of children depends on the decompressed box of the parent, so the
+        // new bounds have to be applied top down
+        self.recompress(self.root, &bounding_box, positions, &child_bounds);
+        self.bounding_box = bounding_box;
+
+        Ok(())
+    }
+
+    /// Returns exact bounds of the moved geometry of a subtree, storing the bounds of
+    /// children of the inner nodes on the way.
+    fn refit_bounds(
+        &self,
+        link: CompressedNodeLink,
+        positions: &[WorldPoint],
+        child_bounds: &mut ChildBounds,
+    ) -> Option<WorldBox> {
+        match link.decode() {
+            NodeLink::Null => None,
+            NodeLink::Inner { index } => {
+                let node = &self.inner_nodes[index];
+                for (i, child_link) in node.child_links.iter().enumerate() {
+                    child_bounds[index][i] =
+                        self.refit_bounds(*child_link, positions, child_bounds);
+                }
+                child_bounds[index]
+                    .iter()
+                    .flatten()
+                    .cloned()
+                    .reduce(|a, b| a.union(&b))
+            }
+            NodeLink::Leaf { indices } => WorldBox::from_points(
+                indices
+                    .iter()
+                    .flat_map(|packet| {
+                        (0..LEAF_NODE_PACKET_SIZE).map(move |lane| packet.to_triangle_idx(lane))
+                    })
+                    .filter_map(|index| self.moved_triangle(index, positions))
+                    .flat_map(|triangle| [triangle[0], triangle[1], triangle[2]]),
+            ),
+        }
+    }
+
+    /// Compresses the refitted child boxes and triangles relative to the decompressed boxes
+    /// of their parents.
+    fn recompress(
+        &mut self,
+        link: CompressedNodeLink,
+        node_box: &WorldBox,
+        positions: &[WorldPoint],
+        child_bounds: &ChildBounds,
+    ) {
+        match link.decode() {
+            NodeLink::Null => {}
+            NodeLink::Inner { index } => {
+                let child_links = self.inner_nodes[index].child_links;
+                let child_count = child_links
+                    .iter()
+                    .rposition(|link| !link.is_null())
+                    .map_or(0, |i| i + 1);
+                // Boxes of null links between children are never looked at
+                let (compressed, decompressed) = compress_child_boxes(
+                    child_bounds[index][..child_count]
+                        .iter()
+                        .map(|b| b.clone().unwrap_or_else(|| node_box.clone())),
+                    node_box,
+                );
+                self.inner_nodes[index].child_bounds = compressed;
+
+                for (i, child_link) in child_links.iter().enumerate() {
+                    self.recompress(
+                        *child_link,
+                        &decompressed.extract(i),
+                        positions,
+                        child_bounds,
+                    );
+                }
+            }
+            NodeLink::Leaf { indices } => {
+                let enclosing_box = WorldBoxSized8::splat(node_box.into());
+                for packet in indices.iter() {
+                    // Padding is always at the end, masking keeps it zero
+                    let triangles: ArrayVec<_, LEAF_NODE_PACKET_SIZE> = (0..LEAF_NODE_PACKET_SIZE)
+                        .filter_map(|lane| {
+                            self.moved_triangle(packet.to_triangle_idx(lane), positions)
+                        })
+                        .collect();
+                    let (triangles, mask) = simd_windows::<Triangle<WorldPoint8>>(triangles)
+                        .exactly_one()
+                        .unwrap_or_else(|_| unreachable!());
+                    self.triangle_geometry[packet] =
+                        RelativeTriangle8::compress(&triangles, &enclosing_box, &mask);
+                }
+            }
+        }
+    }
+
+    /// Position of a stored triangle (or its part) with vertices at the new positions,
+    /// None for padding.
+    fn moved_triangle(
+        &self,
+        index: TriangleIdx,
+        positions: &[WorldPoint],
+    ) -> Option<Triangle<WorldPoint>> {
+        let shading_data = &self.triangle_shading_data[index];
+        if shading_data.is_padding() {
+            return None;
+        }
+        let corners = shading_data.vertex_indices.map(|&i| positions[i].coords);
+        Some(shading_data.source_uv.map(|uv| {
+            WorldPoint::from(
+                BarycentricCoordinates { u: uv.x, v: uv.y }.interpolate_triangle(&corners),
+            )
+        }))
+    }
+
+    /// Builds the tree again from the triangles stored in it, in their original order and
+    /// with the same split mode.
+    fn rebuild(&mut self, positions: &[WorldPoint]) {
+        let mut triangles: Vec<_> = self
+            .triangle_shading_data
+            .iter()
+            .filter(|t| !t.is_padding())
+            .map(|t| MeshTriangle {
+                vertices: t.vertex_indices.clone(),
+                material: t.material,
+                source_index: t.source_index,
+            })
+            .collect();
+        // Spatial splits store parts of one mesh triangle in several leaves
+        triangles.sort_unstable_by_key(|t| t.source_index);
+        triangles.dedup_by_key(|t| t.source_index);
+        let vertices = self
+            .vertex_data
+            .iter()
+            .zip(positions)
+            .map(|(vertex, pos)| VertexData {
+                pos: *pos,
+                tex: vertex.texture_coords,
+                normal: vertex.normal,
+            })
+            .collect();
+
+        *self = Self::build_mesh(
+            triangles,
+            vertices,
+            self.split_mode,
+            &BuildProgress::default(),
+        );
+    }
+}
+
+/// Refitted bounds of children of every inner node, None for null links
+type ChildBounds = IndexVec<InnerNodeIdx, [Option<WorldBox>; INNER_NODE_CHILDREN]>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        geometry::{Ray, WorldVector},
+        scene::{
+            Object,
+            triangle_bvh::{Mesh, SplitMode, StackCache, test::test_bvh_with_split_mode},
+        },
+    };
+    use assert2::{assert, let_assert};
+
+    /// Shared teapot tree and the positions of its vertices, in the order the tree indexes them.
+    fn teapot(split_mode: SplitMode) -> (TriangleBvh, Vec<WorldPoint>) {
+        let (_triangles, vertices) =
+            TriangleBvh::load_obj(obj::Obj::load("data/teapot.obj").unwrap());
+        let positions = vertices.iter().map(|v| v.pos).collect();
+        (test_bvh_with_split_mode(split_mode).clone(), positions)
+    }
+
+    fn hit_distance(bvh: &TriangleBvh, origin: WorldPoint) -> f32 {
+        let ray = Ray::new(origin, WorldVector::new(0.0, 0.0, -1.0));
+        let_assert!(Some(hit) = bvh.intersect(&ray, &mut StackCache::default()));
+        hit.t
+    }
+
+    #[test]
+    fn moved_teapot_is_hit_at_new_position() {
+        let (mut bvh, positions) = teapot(SplitMode::Object);
+        let distance = hit_distance(&bvh, WorldPoint::new(0.0, 1.5, 10.0));
+
+        let offset = WorldVector::new(3.0, -1.0, 2.0);
+        let moved = positions.iter().map(|p| p + offset).collect::<Vec<_>>();
+        let_assert!(Ok(false) = bvh.update_positions(&moved));
+
+        assert!(bvh.validate() == Ok(()));
+        let moved_distance = hit_distance(&bvh, WorldPoint::new(0.0, 1.5, 10.0) + offset);
+        assert!((moved_distance - distance).abs() < 1e-3);
+        // Rigid motion doesn't change the cost, except for rounding
+        assert!((bvh.sah_cost() - bvh.build_cost).abs() < bvh.build_cost * 0.01);
+    }
+
+    #[test]
+    fn deformed_spatial_split_tree_stays_valid() {
+        let (mut bvh, positions) = teapot(SplitMode::Spatial);
+        let twisted = positions
+            .iter()
+            .map(|p| {
+                let (sin, cos) = (p.y * 0.5).sin_cos();
+                WorldPoint::new(p.x * cos - p.z * sin, p.y, p.x * sin + p.z * cos)
+            })
+            .collect::<Vec<_>>();
+        let_assert!(Ok(()) = bvh.refit(&twisted));
+        assert!(bvh.validate() == Ok(()));
+
+        // Refitted tree finds the same surface as a freshly built one
+        let (mut rebuilt, _) = teapot(SplitMode::Spatial);
+        rebuilt.rebuild(&twisted);
+        let origin = WorldPoint::new(0.3, 1.2, 10.0);
+        assert!((hit_distance(&bvh, origin) - hit_distance(&rebuilt, origin)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn scrambled_mesh_is_rebuilt() {
+        let (mut bvh, mut positions) = teapot(SplitMode::Object);
+        positions.reverse();
+        let_assert!(Ok(true) = bvh.update_positions(&positions));
+        assert!(bvh.validate() == Ok(()));
+        assert!(bvh.sah_cost() == bvh.build_cost);
+    }
+
+    #[test]
+    fn rebuild_keeps_duplicate_triangles() {
+        // The same triangle twice with different materials, large enough to be cut by spatial
+        // splits of the small triangles around it
+        let mut positions = vec![
+            WorldPoint::new(-10.0, -10.0, 0.0),
+            WorldPoint::new(10.0, -10.0, 0.0),
+            WorldPoint::new(0.0, 10.0, 0.0),
+        ];
+        let mut triangles = vec![Triangle::new(0, 1, 2), Triangle::new(0, 1, 2)];
+        for i in 0..64 {
+            let corner =
+                WorldPoint::new((i % 8) as f32 * 2.0 - 8.0, (i / 8) as f32 * 2.0 - 8.0, 1.0);
+            let first = positions.len();
+            positions.extend([corner, corner + WorldVector::x(), corner + WorldVector::y()]);
+            triangles.push(Triangle::new(first, first + 1, first + 2));
+        }
+        let mut materials = vec![0; triangles.len()];
+        materials[1] = 1;
+        let mut bvh = Mesh::new(positions.clone(), triangles)
+            .materials(materials)
+            .split_mode(SplitMode::Spatial)
+            .build()
+            .unwrap();
+
+        let count_sources = |bvh: &TriangleBvh| {
+            bvh.triangle_shading_data
+                .iter()
+                .filter(|t| !t.is_padding())
+                .map(|t| (t.source_index, t.material))
+                .unique()
+                .count()
+        };
+        assert!(count_sources(&bvh) == 66);
+        bvh.rebuild(&positions);
+        assert!(bvh.validate() == Ok(()));
+        assert!(count_sources(&bvh) == 66);
+    }
+
+    #[test]
+    fn wrong_vertex_count() {
+        let (mut bvh, mut positions) = teapot(SplitMode::Object);
+        positions.pop();
+        let_assert!(Err(RefitError::VertexCountMismatch { .. }) = bvh.refit(&positions));
+    }
+}
@@ -0,0 +1,616 @@
+//! Spatial split mode of the builder (SBVH, Stich et al. 2009).
+//! Besides partitioning the triangles, a node can be split by a plane that cuts through them.
+//! Triangles crossing the plane are referenced from both sides, each reference with bounds
+//! clipped to its side.
+//! Leaf geometry is compressed relative to the leaf box, so leaves store only the parts of
+//! triangles that lie inside the box.
+
+use std::sync::atomic::Ordering;
+
+use arrayvec::ArrayVec;
+use itertools::Itertools as _;
+use nalgebra::Vector2;
+use ordered_float::OrderedFloat;
+use simba::simd::SimdValue as _;
+
+use crate::geometry::{FloatType, Triangle, WorldBox, WorldPoint, WorldVector};
+
+use super::{
+    CompressedNodeLink, INNER_NODE_CHILDREN, InnerNode, LEAF_NODE_PACKET_SIZE,
+    LEAF_NODE_TARGET_TRIANGLES, TriangleBvh,
+    building::{
+        BuildContext, C_INNER, C_LEAF_PACKET, LeafTriangle, MeshTriangle, VertexData,
+        compress_child_boxes, subtree_cost, whole_triangle_uv,
+    },
+    compressed_geometry::OUTSIDE_TOLERANCE,
+};
+
+/// Number of candidate split planes per axis is one less than this.
+const BIN_COUNT: usize = 32;
+
+/// Spatial splits are only considered when children of the best object split overlap by more
+/// than this fraction of the surface area of the whole tree.
+const SPATIAL_SPLIT_ALPHA: f32 = 1e-5;
+
+/// A triangle, or a part of it, assigned to a node.
+#[derive(Clone, Debug)]
+pub(super) struct Reference {
+    triangle: MeshTriangle,
+    /// Bounds of the part of the triangle that belongs to this reference
+    bounds: WorldBox,
+}
+
+impl Reference {
+    pub fn new(triangle: MeshTriangle, vertices: &[VertexData]) -> Self {
+        let bounds = WorldBox::from_points(triangle.positions(vertices).iter()).unwrap();
+        Reference { triangle, bounds }
+    }
+
+    /// Part of the triangle within bounds of the reference.
+    fn polygon(&self, vertices: &[VertexData]) -> Polygon {
+        Polygon::from_triangle(&self.triangle.positions(vertices)).clip_to_box(&self.bounds)
+    }
+
+    fn centroid(&self, axis: usize) -> FloatType {
+        (self.bounds.min[axis] + self.bounds.max[axis]) / 2.0
+    }
+}
+
+impl TriangleBvh {
+    pub(super) fn build_spatial_recursive(
+        &mut self,
+        references: Vec<Reference>,
+        enclosing_box: &WorldBox,
+        context: &BuildContext,
+    ) -> CompressedNodeLink {
+        let parts = (references.len() <= LEAF_NODE_TARGET_TRIANGLES)
+            .then(|| {
+                let mut parts = leaf_parts(&references, enclosing_box, context.vertices);
+                // A single clipped triangle always fits, this is just to be safe against rounding
+                if references.len() == 1 {
+                    parts.truncate(LEAF_NODE_TARGET_TRIANGLES);
+                }
+                parts
+            })
+            .filter(|parts| parts.len() <= LEAF_NODE_TARGET_TRIANGLES);
+
+        let reference_count = references.len();
+        let make_leaf = |bvh: &mut Self, parts: &[LeafTriangle]| {
+            context
+                .progress
+                .done
+                .fetch_add(reference_count, Ordering::Relaxed);
+            if parts.is_empty() {
+                // All references only touched the box
+                CompressedNodeLink::NULL
+            } else {
+                bvh.build_leaf_parts(parts, context.vertices, enclosing_box)
+            }
+        };
+
+        if let Some(parts) = parts.as_ref()
+            && parts.len() <= LEAF_NODE_PACKET_SIZE
+        {
+            return make_leaf(self, parts);
+        }
+
+        let min_overlap = self.bounding_box.surface_area() * SPATIAL_SPLIT_ALPHA;
+        let children = split_references(references, context.vertices, min_overlap);
+
+        if let Some(parts) = parts {
+            // Same cost estimate as used by the object split builder
+            let area = enclosing_box.surface_area();
+            let leaf_cost =
+                C_LEAF_PACKET * parts.len().div_ceil(LEAF_NODE_PACKET_SIZE) as f32 * area;
+            let split_cost = C_INNER * area
+                + children
+                    .iter()
+                    .map(|(references, child_box)| {
+                        child_box.surface_area() * subtree_cost(references.len())
+                    })
+                    .sum::<f32>();
+            if leaf_cost <= split_cost {
+                return make_leaf(self, &parts);
+            }
+        }
+
+        let (compressed_child_boxes, decompressed_child_boxes) = compress_child_boxes(
+            children
+                .iter()
+                .map(|(_references, child_box)| child_box.clone()),
+            enclosing_box,
+        );
+
+        // Create placeholder node that will be overwriten later
+        self.inner_nodes.push(InnerNode::default());
+        let node_index = self.inner_nodes.last_idx();
+
+        let mut child_links = [CompressedNodeLink::NULL; INNER_NODE_CHILDREN];
+        for (i, (link, (references, _child_box))) in
+            child_links.iter_mut().zip(children).enumerate()
+        {
+            *link = self.build_spatial_recursive(
+                references,
+                &decompressed_child_boxes.extract(i),
+                context,
+            );
+        }
+
+        self.inner_nodes[node_index] = InnerNode {
+            child_bounds: compressed_child_boxes,
+            child_links,
+        };
+
+        CompressedNodeLink::new_inner(node_index)
+    }
+}
+
+/// Cuts the referenced triangles to the leaf box.
+/// Triangles that fit are kept whole, the others are replaced by a triangle fan of their
+/// part inside the box.
+fn leaf_parts(
+    references: &[Reference],
+    enclosing_box: &WorldBox,
+    vertices: &[VertexData],
+) -> Vec<LeafTriangle> {
+    // Triangles sticking out less than the compression accepts get clamped instead of cut,
+    // with half of its tolerance left for rounding of the relative coordinates
+    let tolerance = enclosing_box.size() * (OUTSIDE_TOLERANCE / 2.0);
+    let min = enclosing_box.min - tolerance;
+    let max = enclosing_box.max + tolerance;
+
+    let mut parts = Vec::new();
+    for reference in references {
+        let whole = LeafTriangle::whole(&reference.triangle, vertices);
+        let fits = whole.positions.iter().all(|p| {
+            p.iter()
+                .zip(min.iter().zip(max.iter()))
+                .all(|(x, (min, max))| min <= x && x <= max)
+        });
+        if fits {
+            parts.push(whole);
+            continue;
+        }
+
+        let polygon = Polygon::from_triangle(&whole.positions).clip_to_box(enclosing_box);
+        let Some((first, rest)) = polygon.0.split_first() else {
+            continue;
+        };
+        // Fan keeps the winding of the original triangle
+        for (b, c) in rest.iter().tuple_windows() {
+            // Degenerate parts could never be hit and their zero UVs would look like padding
+            if (b.uv - first.uv).perp(&(c.uv - first.uv)) == 0.0 {
+                continue;
+            }
+            parts.push(LeafTriangle {
+                vertex_indices: reference.triangle.vertices.clone(),
+                material: reference.triangle.material,
+                positions: Triangle::new(first.position, b.position, c.position),
+                source_uv: Triangle::new(first.uv, b.uv, c.uv),
+                source_index: reference.triangle.source_index,
+            });
+        }
+    }
+    parts
+}
+
+/// Divides the references into up to 8 children by repeated binary splits, always splitting
+/// the child with the highest surface area heuristic.
+fn split_references(
+    references: Vec<Reference>,
+    vertices: &[VertexData],
+    min_overlap: f32,
+) -> ArrayVec<(Vec<Reference>, WorldBox), INNER_NODE_CHILDREN> {
+    let mut children = ArrayVec::new();
+    let bounds = references_bounds(&references);
+    children.push((references, bounds));
+
+    while children.len() < INNER_NODE_CHILDREN {
+        let candidate = children
+            .iter()
+            .enumerate()
+            .filter(|(_, (references, _))| {
+                // The node itself has to be split even if it has just a few references whose
+                // parts didn't fit into a leaf
+                references.len() > LEAF_NODE_PACKET_SIZE
+                    || (children.len() == 1 && references.len() > 1)
+            })
+            .max_by_key(|(_, (references, child_box))| {
+                OrderedFloat(child_box.surface_area() * subtree_cost(references.len()))
+            })
+            .map(|(i, _)| i);
+        let Some(i) = candidate else {
+            break;
+        };
+
+        let (references, bounds) = children.swap_remove(i);
+        let (left, right) = split_binary(references, &bounds, vertices, min_overlap);
+        for side in [left, right] {
+            let side_bounds = references_bounds(&side);
+            children.push((side, side_bounds));
+        }
+    }
+
+    children
+}
+
+/// Splits the references in two, using the better of the best object and spatial splits.
+/// Both returned parts are non-empty and smaller than the input.
+fn split_binary(
+    references: Vec<Reference>,
+    bounds: &WorldBox,
+    vertices: &[VertexData],
+    min_overlap: f32,
+) -> (Vec<Reference>, Vec<Reference>) {
+    debug_assert!(references.len() > 1);
+
+    let object = find_object_split(&references);
+    let overlap = object
+        .as_ref()
+        .map_or(FloatType::INFINITY, |split| split.overlap);
+    if overlap > min_overlap
+        && let Some(spatial) = find_spatial_split(&references, bounds, vertices)
+        && object
+            .as_ref()
+            .is_none_or(|object| spatial.cost < object.cost)
+    {
+        let (left, right) =
+            apply_spatial_split(&references, spatial.axis, spatial.position, vertices);
+        if !left.is_empty()
+            && !right.is_empty()
+            && left.len() < references.len()
+            && right.len() < references.len()
+        {
+            return (left, right);
+        }
+    }
+
+    match object {
+        Some(split) => {
+            let (left, right) = references.into_iter().partition(|reference| {
+                split.binning.bin(reference.centroid(split.axis)) < split.bin
+            });
+            (left, right)
+        }
+        None => split_in_half(references),
+    }
+}
+
+/// Fallback for when all the centroids coincide.
+fn split_in_half(mut references: Vec<Reference>) -> (Vec<Reference>, Vec<Reference>) {
+    let axis = references_bounds(&references).size().imax();
+    references.sort_by_key(|reference| OrderedFloat(reference.centroid(axis)));
+    let right = references.split_off(references.len() / 2);
+    (references, right)
+}
+
+/// Uniform bins along one axis.
+struct Binning {
+    min: FloatType,
+    /// Inverse of the bin size
+    scale: FloatType,
+}
+
+impl Binning {
+    /// Returns None if the range is empty.
+    fn new(min: FloatType, max: FloatType) -> Option<Self> {
+        let size = max - min;
+        (size > 0.0).then(|| Binning {
+            min,
+            scale: BIN_COUNT as FloatType / size,
+        })
+    }
+
+    fn bin(&self, x: FloatType) -> usize {
+        // Points on the maximum edge would land just outside of the range
+        (((x - self.min) * self.scale).max(0.0) as usize).min(BIN_COUNT - 1)
+    }
+
+    /// Position of the boundary below the given bin.
+    fn boundary(&self, bin: usize) -> FloatType {
+        self.min + bin as FloatType / self.scale
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Bin {
+    bounds: WorldBox,
+    /// Number of references starting in this bin (or having centroid in it, for object splits)
+    entries: usize,
+    /// Number of references ending in this bin
+    exits: usize,
+}
+
+struct ObjectSplit {
+    axis: usize,
+    binning: Binning,
+    /// References with centroid in bins below this go to the left
+    bin: usize,
+    cost: f32,
+    /// Surface area of the intersection of the two sides
+    overlap: f32,
+}
+
+struct SpatialSplit {
+    axis: usize,
+    position: FloatType,
+    cost: f32,
+}
+
+/// Returns None if all centroids coincide.
+fn find_object_split(references: &[Reference]) -> Option<ObjectSplit> {
+    let mut best: Option<ObjectSplit> = None;
+    for axis in 0..3 {
+        let (min, max) = references
+            .iter()
+            .map(|reference| reference.centroid(axis))
+            .minmax()
+            .into_option()?;
+        let Some(binning) = Binning::new(min, max) else {
+            continue;
+        };
+
+        let mut bins: [Bin; BIN_COUNT] = Default::default();
+        for reference in references {
+            let bin = &mut bins[binning.bin(reference.centroid(axis))];
+            bin.bounds = bin.bounds.union(&reference.bounds);
+            bin.entries += 1;
+        }
+
+        let (bin, cost, left, right) = sweep(&bins, |bin| bin.entries, |bin| bin.entries);
+        if best.as_ref().is_none_or(|best| cost < best.cost) {
+            let overlap = box_area(&left.intersection(&right));
+            best = Some(ObjectSplit {
+                axis,
+                binning,
+                bin,
+                cost,
+                overlap,
+            });
+        }
+    }
+    best
+}
+
+/// Returns None if the node is a single point.
+fn find_spatial_split(
+    references: &[Reference],
+    bounds: &WorldBox,
+    vertices: &[VertexData],
+) -> Option<SpatialSplit> {
+    let mut best: Option<SpatialSplit> = None;
+    for axis in 0..3 {
+        let Some(binning) = Binning::new(bounds.min[axis], bounds.max[axis]) else {
+            continue;
+        };
+
+        let mut bins: [Bin; BIN_COUNT] = Default::default();
+        for reference in references {
+            let first = binning.bin(reference.bounds.min[axis]);
+            let last = binning.bin(reference.bounds.max[axis]);
+            bins[first].entries += 1;
+            bins[last].exits += 1;
+
+            // Chop the triangle into the bins it spans
+            let mut polygon = reference.polygon(vertices);
+            for (i, bin) in bins.iter_mut().enumerate().take(last).skip(first) {
+                let (below, above) = polygon.split(axis, binning.boundary(i + 1));
+                if let Some(part_bounds) = below.bounds() {
+                    bin.bounds = bin.bounds.union(&part_bounds);
+                }
+                polygon = above;
+            }
+            if let Some(part_bounds) = polygon.bounds() {
+                bins[last].bounds = bins[last].bounds.union(&part_bounds);
+            }
+        }
+
+        let (bin, cost, _, _) = sweep(&bins, |bin| bin.entries, |bin| bin.exits);
+        if best.as_ref().is_none_or(|best| cost < best.cost) {
+            best = Some(SpatialSplit {
+                axis,
+                position: binning.boundary(bin),
+                cost,
+            });
+        }
+    }
+    best
+}
+
+/// Finds the bin boundary with the lowest surface area heuristic.
+/// Returns index of the first bin on the right, the cost and bounds of both sides.
+fn sweep(
+    bins: &[Bin; BIN_COUNT],
+    left_count: impl Fn(&Bin) -> usize,
+    right_count: impl Fn(&Bin) -> usize,
+) -> (usize, f32, WorldBox, WorldBox) {
+    // Bounds and counts of everything right of each boundary
+    let mut right_sides = vec![(WorldBox::default(), 0); BIN_COUNT];
+    let mut right = (WorldBox::default(), 0);
+    for (i, bin) in bins.iter().enumerate().rev() {
+        right = (right.0.union(&bin.bounds), right.1 + right_count(bin));
+        right_sides[i] = right.clone();
+    }
+
+    let mut best = (0, f32::INFINITY, WorldBox::default(), WorldBox::default());
+    let mut left = (WorldBox::default(), 0);
+    for (i, bin) in bins.iter().enumerate().take(BIN_COUNT - 1) {
+        left = (left.0.union(&bin.bounds), left.1 + left_count(bin));
+        let (right_bounds, right_count) = &right_sides[i + 1];
+        if left.1 == 0 || *right_count == 0 {
+            continue;
+        }
+        let cost = box_area(&left.0) * left.1 as f32 + box_area(right_bounds) * *right_count as f32;
+        if cost < best.1 {
+            best = (i + 1, cost, left.0.clone(), right_bounds.clone());
+        }
+    }
+    best
+}
+
+/// Sorts references to the sides of the plane, references crossing it get clipped to both.
+fn apply_spatial_split(
+    references: &[Reference],
+    axis: usize,
+    position: FloatType,
+    vertices: &[VertexData],
+) -> (Vec<Reference>, Vec<Reference>) {
+    let mut left = Vec::new();
+    let mut right = Vec::new();
+    for reference in references {
+        if reference.bounds.max[axis] <= position {
+            left.push(reference.clone());
+        } else if reference.bounds.min[axis] >= position {
+            right.push(reference.clone());
+        } else {
+            let (below, above) = reference.polygon(vertices).split(axis, position);
+            for (side, polygon) in [(&mut left, below), (&mut right, above)] {
+                if let Some(bounds) = polygon.bounds() {
+                    side.push(Reference {
+                        triangle: reference.triangle.clone(),
+                        bounds,
+                    });
+                }
+            }
+        }
+    }
+    (left, right)
+}
+
+fn references_bounds(references: &[Reference]) -> WorldBox {
+    references
+        .iter()
+        .map(|reference| reference.bounds.clone())
+        .reduce(|a, b| a.union(&b))
+        .unwrap_or_default()
+}
+
+/// Surface area that is zero for empty boxes.
+pub(super) fn box_area(b: &WorldBox) -> f32 {
+    if b.size().iter().any(|&x| x < 0.0) {
+        0.0
+    } else {
+        b.surface_area()
+    }
+}
+
+/// Vertex of a clipped triangle.
+#[derive(Clone, Copy, Debug)]
+struct ClipVertex {
+    position: WorldPoint,
+    /// Barycentric coordinates within the original triangle
+    uv: Vector2<FloatType>,
+}
+
+/// Convex polygon, part of a triangle cut out by axis aligned planes.
+#[derive(Clone, Debug, Default)]
+pub(super) struct Polygon(Vec<ClipVertex>);
+
+impl Polygon {
+    pub(super) fn from_triangle(triangle: &Triangle<WorldPoint>) -> Self {
+        let uv = whole_triangle_uv();
+        Polygon(
+            (0..3)
+                .map(|i| ClipVertex {
+                    position: triangle[i],
+                    uv: uv[i],
+                })
+                .collect(),
+        )
+    }
+
+    /// Splits the polygon into parts below and above an axis aligned plane.
+    fn split(&self, axis: usize, position: FloatType) -> (Polygon, Polygon) {
+        let mut below = Vec::new();
+        let mut above = Vec::new();
+        for (a, b) in self.0.iter().circular_tuple_windows() {
+            let da = a.position[axis] - position;
+            let db = b.position[axis] - position;
+            if da <= 0.0 {
+                below.push(*a);
+            }
+            if da >= 0.0 {
+                above.push(*a);
+            }
+            if (da < 0.0 && db > 0.0) || (da > 0.0 && db < 0.0) {
+                let t = da / (da - db);
+                let mut crossing = ClipVertex {
+                    position: a.position + (b.position - a.position) * t,
+                    uv: a.uv.lerp(&b.uv, t),
+                };
+                // Make sure the point is exactly on the plane
+                crossing.position[axis] = position;
+                below.push(crossing);
+                above.push(crossing);
+            }
+        }
+        (Polygon(below), Polygon(above))
+    }
+
+    pub(super) fn clip_to_box(self, b: &WorldBox) -> Polygon {
+        (0..3).fold(self, |polygon, axis| {
+            let polygon = polygon.split(axis, b.min[axis]).1;
+            polygon.split(axis, b.max[axis]).0
+        })
+    }
+
+    pub(super) fn area(&self) -> FloatType {
+        let Some((first, rest)) = self.0.split_first() else {
+            return 0.0;
+        };
+        let doubled: WorldVector = rest
+            .iter()
+            .tuple_windows()
+            .map(|(b, c)| (b.position - first.position).cross(&(c.position - first.position)))
+            .sum();
+        doubled.norm() / 2.0
+    }
+
+    /// Returns None for an empty polygon.
+    fn bounds(&self) -> Option<WorldBox> {
+        WorldBox::from_points(self.0.iter().map(|v| v.position))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    fn triangle() -> Triangle<WorldPoint> {
+        Triangle::new(
+            WorldPoint::new(0.0, 0.0, 0.0),
+            WorldPoint::new(4.0, 0.0, 0.0),
+            WorldPoint::new(0.0, 4.0, 0.0),
+        )
+    }
+
+    #[test]
+    fn clipped_vertices_keep_barycentric_coordinates() {
+        let triangle = triangle();
+        let polygon = Polygon::from_triangle(&triangle).clip_to_box(&WorldBox::new(
+            WorldPoint::new(1.0, -1.0, -1.0),
+            WorldPoint::new(2.0, 5.0, 1.0),
+        ));
+
+        // Cut by two vertical lines, gives a trapezoid
+        assert!(polygon.0.len() == 4);
+        assert!((polygon.area() - 2.5).abs() < 1e-5);
+        for vertex in &polygon.0 {
+            assert!(vertex.position.x >= 1.0);
+            assert!(vertex.position.x <= 2.0);
+            let expected = triangle[0].coords * (1.0 - vertex.uv.x - vertex.uv.y)
+                + triangle[1].coords * vertex.uv.x
+                + triangle[2].coords * vertex.uv.y;
+            assert!((expected - vertex.position.coords).norm() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn split_outside_keeps_polygon_on_one_side() {
+        let polygon = Polygon::from_triangle(&triangle());
+        let (below, above) = polygon.split(2, 1.0);
+        assert!(below.0.len() == 3);
+        assert!(above.bounds().is_none());
+    }
+}
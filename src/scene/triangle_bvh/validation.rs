@@ -0,0 +1,279 @@
+//! Consistency checks of a built tree, for tests and for debugging the builder.
+
+use index_vec::IndexVec;
+use simba::simd::SimdValue as _;
+use thiserror::Error;
+
+use crate::{
+    geometry::{WorldBox, WorldBoxSized8, WorldPoint},
+    util::simba::simd_element_iter,
+};
+
+use super::{
+    CompressedNodeLink, InnerNodeIdx, LEAF_NODE_PACKET_SIZE, NodeLink, TriangleBvh,
+    TrianglePackIdx, TrianglePackIdxRange,
+};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BvhValidationError {
+    #[error("Link to inner node {0} is out of range")]
+    InnerNodeOutOfRange(usize),
+
+    #[error("Link to triangle packets {0}..{1} is out of range")]
+    PacketsOutOfRange(usize, usize),
+
+    #[error("Inner node {0} is reachable more than once")]
+    InnerNodeSharedOrCyclic(usize),
+
+    #[error("Triangle packet {0} is reachable more than once")]
+    PacketShared(usize),
+
+    #[error("Inner node {0} is not reachable from the root")]
+    UnreachableInnerNode(usize),
+
+    #[error("Triangle packet {0} is not reachable from the root")]
+    UnreachablePacket(usize),
+
+    #[error("Box of child {1} of inner node {0} is inverted or outside of the node")]
+    BadChildBox(usize, usize),
+
+    #[error("Triangle {0} lies outside of its leaf box")]
+    TriangleOutsideLeaf(usize),
+
+    #[error("Padding triangle {0} is not at the end of its leaf")]
+    MisplacedPadding(usize),
+
+    #[error("Padding triangle {0} is not masked out by degenerate geometry")]
+    UnmaskedPadding(usize),
+
+    #[error("Triangle {0} refers to a vertex that doesn't exist")]
+    VertexOutOfRange(usize),
+}
+
+impl TriangleBvh {
+    /// Walks the tree from the root and checks that
+    /// - all node links point inside their arrays,
+    /// - every inner node and every triangle packet is reachable exactly once,
+    /// - child boxes are inside their parents' and triangles inside their leaves' boxes,
+    ///   all after decompression, the way the traversal sees them,
+    /// - padding only fills the end of the last packet of a leaf and can't be hit.
+    pub fn validate(&self) -> Result<(), BvhValidationError> {
+        let mut state = ValidationState {
+            inner_nodes: IndexVec::from_vec(vec![false; self.inner_nodes.len()]),
+            packets: IndexVec::from_vec(vec![false; self.triangle_geometry.len()]),
+        };
+
+        self.validate_recursive(self.root, &self.bounding_box, &mut state)?;
+
+        if let Some(index) = state.inner_nodes.iter().position(|visited| !visited) {
+            return Err(BvhValidationError::UnreachableInnerNode(index));
+        }
+        if let Some(index) = state.packets.iter().position(|visited| !visited) {
+            return Err(BvhValidationError::UnreachablePacket(index));
+        }
+
+        let vertex_count = self.vertex_data.len();
+        if let Some(index) = self
+            .triangle_shading_data
+            .iter()
+            .position(|triangle| triangle.vertex_indices.iter().any(|&i| i >= vertex_count))
+        {
+            return Err(BvhValidationError::VertexOutOfRange(index));
+        }
+
+        Ok(())
+    }
+
+    fn validate_recursive(
+        &self,
+        link: CompressedNodeLink,
+        node_box: &WorldBox,
+        state: &mut ValidationState,
+    ) -> Result<(), BvhValidationError> {
+        match link.decode() {
+            NodeLink::Null => Ok(()),
+            NodeLink::Inner { index } => {
+                let visited = state
+                    .inner_nodes
+                    .get_mut(index)
+                    .ok_or(BvhValidationError::InnerNodeOutOfRange(index.index()))?;
+                if std::mem::replace(visited, true) {
+                    return Err(BvhValidationError::InnerNodeSharedOrCyclic(index.index()));
+                }
+
+                let node = &self.inner_nodes[index];
+                let child_boxes = node
+                    .child_bounds
+                    .decompress(&WorldBoxSized8::splat(node_box.into()));
+                for (i, child_link) in node.child_links.iter().enumerate() {
+                    if child_link.is_null() {
+                        continue;
+                    }
+                    let child_box = child_boxes.extract(i);
+                    let inverted = (0..3).any(|axis| child_box.min[axis] > child_box.max[axis]);
+                    if inverted || !contains(node_box, [&child_box.min, &child_box.max]) {
+                        return Err(BvhValidationError::BadChildBox(index.index(), i));
+                    }
+                    self.validate_recursive(*child_link, &child_box, state)?;
+                }
+                Ok(())
+            }
+            NodeLink::Leaf { indices } => self.validate_leaf(indices, node_box, state),
+        }
+    }
+
+    fn validate_leaf(
+        &self,
+        indices: TrianglePackIdxRange,
+        leaf_box: &WorldBox,
+        state: &mut ValidationState,
+    ) -> Result<(), BvhValidationError> {
+        if indices.last > self.triangle_geometry.len_idx() {
+            return Err(BvhValidationError::PacketsOutOfRange(
+                indices.first.index(),
+                indices.last.index(),
+            ));
+        }
+
+        let sized_box = WorldBoxSized8::splat(leaf_box.into());
+        let mut padding_started = false;
+        for packet in indices.iter() {
+            if std::mem::replace(&mut state.packets[packet], true) {
+                return Err(BvhValidationError::PacketShared(packet.index()));
+            }
+
+            let compressed = &self.triangle_geometry[packet];
+            let triangles = compressed.decompress(&sized_box);
+            let is_zero = simd_element_iter(
+                compressed[0].is_zero() & compressed[1].is_zero() & compressed[2].is_zero(),
+            );
+            for (lane, is_zero) in is_zero.enumerate() {
+                let index = packet.to_triangle_idx(lane);
+                let is_padding = self.triangle_shading_data[index].is_padding();
+
+                if is_padding {
+                    // The last packet must have at least one real triangle
+                    if lane == 0 {
+                        return Err(BvhValidationError::MisplacedPadding(index.index()));
+                    }
+                    // Zero triangle is degenerate, so the intersection never reports it
+                    if !is_zero {
+                        return Err(BvhValidationError::UnmaskedPadding(index.index()));
+                    }
+                    padding_started = true;
+                } else {
+                    if padding_started {
+                        return Err(BvhValidationError::MisplacedPadding(index.index()));
+                    }
+                    if !contains(leaf_box, triangles.extract(lane).iter()) {
+                        return Err(BvhValidationError::TriangleOutsideLeaf(index.index()));
+                    }
+                }
+            }
+            if padding_started && packet + 1 != indices.last {
+                return Err(BvhValidationError::MisplacedPadding(
+                    packet.to_triangle_idx(LEAF_NODE_PACKET_SIZE - 1).index(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct ValidationState {
+    /// Which inner nodes were already reached
+    inner_nodes: IndexVec<InnerNodeIdx, bool>,
+    /// Which triangle packets were already reached
+    packets: IndexVec<TrianglePackIdx, bool>,
+}
+
+/// Checks that the points are inside of the box, allowing for rounding errors of
+/// the decompression.
+fn contains<'a>(b: &WorldBox, points: impl IntoIterator<Item = &'a WorldPoint>) -> bool {
+    let tolerance = (b.max.coords.abs() + b.min.coords.abs()) * 1e-6;
+    let min = b.min - tolerance;
+    let max = b.max + tolerance;
+    points
+        .into_iter()
+        .all(|p| (0..3).all(|axis| min[axis] <= p[axis] && p[axis] <= max[axis]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scene::triangle_bvh::{
+        INNER_NODE_CHILDREN, compressed_geometry::RelativePoint8, test::test_bvh,
+    };
+    use assert2::{assert, let_assert};
+
+    /// Index of a leaf with padding and an inner node that links to it.
+    fn padded_leaf(bvh: &TriangleBvh) -> (InnerNodeIdx, usize, TrianglePackIdxRange) {
+        bvh.inner_nodes
+            .iter_enumerated()
+            .flat_map(|(index, node)| {
+                node.child_links
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, link)| (index, i, link.decode()))
+            })
+            .find_map(|(index, i, link)| match link {
+                NodeLink::Leaf { indices }
+                    if bvh.triangle_shading_data
+                        [(indices.last - 1).to_triangle_idx(LEAF_NODE_PACKET_SIZE - 1)]
+                    .is_padding() =>
+                {
+                    Some((index, i, indices))
+                }
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn teapot_is_valid() {
+        assert!(test_bvh().validate() == Ok(()));
+    }
+
+    #[test]
+    fn link_out_of_range() {
+        let mut bvh = test_bvh().clone();
+        bvh.root = CompressedNodeLink::new_inner(bvh.inner_nodes.next_idx());
+        let_assert!(Err(BvhValidationError::InnerNodeOutOfRange(_)) = bvh.validate());
+    }
+
+    #[test]
+    fn shared_leaf() {
+        let mut bvh = test_bvh().clone();
+        let (index, i, _) = padded_leaf(&bvh);
+        let other = (i + 1) % INNER_NODE_CHILDREN;
+        let node = &mut bvh.inner_nodes[index];
+        node.child_links[other] = node.child_links[i];
+        for p in [&mut node.child_bounds.min, &mut node.child_bounds.max] {
+            let mut raw = p.to_raw();
+            for axis in &mut raw {
+                axis[other] = axis[i];
+            }
+            *p = RelativePoint8::from_raw(raw);
+        }
+        let_assert!(Err(BvhValidationError::PacketShared(_)) = bvh.validate());
+    }
+
+    #[test]
+    fn unreachable_node() {
+        let mut bvh = test_bvh().clone();
+        bvh.inner_nodes.push(Default::default());
+        let_assert!(Err(BvhValidationError::UnreachableInnerNode(_)) = bvh.validate());
+    }
+
+    #[test]
+    fn unmasked_padding() {
+        let mut bvh = test_bvh().clone();
+        let (_, _, indices) = padded_leaf(&bvh);
+        let last = indices.last - 1;
+        let mut raw = bvh.triangle_geometry[last][0].to_raw();
+        raw[0][LEAF_NODE_PACKET_SIZE - 1] = 1;
+        bvh.triangle_geometry[last][0] = RelativePoint8::from_raw(raw);
+        let_assert!(Err(BvhValidationError::UnmaskedPadding(_)) = bvh.validate());
+    }
+}